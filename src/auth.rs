@@ -1,9 +1,116 @@
-use anyhow::Result;
-use std::io::{self, Write};
+use crate::authenticator::Authenticator;
+use crate::error::{Error, Result};
+use crate::pin_provider::PinProvider;
+use ctap_hid_fido2::fidokey::get_info::InfoOption;
+use zeroize::Zeroizing;
+
+/// At or below this many remaining attempts every prompt carries a loud warning.
+pub const LOW_PIN_RETRIES: i32 = 3;
+
+const MIN_PIN_LENGTH: usize = 4;
+const MAX_PIN_BYTES: usize = 63;
+
+pub fn is_pin_set(device: &impl Authenticator) -> Result<bool> {
+    Ok(device.enable_info_option(&InfoOption::ClientPin)? == Some(true))
+}
+
+/// Returns the remaining PIN attempts, failing if the PIN is already blocked.
+pub fn check_pin_retries(device: &impl Authenticator) -> Result<i32> {
+    let retries = device.get_pin_retries()?;
+
+    if retries <= 0 {
+        return Err(Error::PinBlocked);
+    }
+
+    Ok(retries)
+}
+
+/// Asks the provider for the PIN, with the retry counter in the prompt.
+pub fn prompt_pin(
+    device: &impl Authenticator,
+    provider: &dyn PinProvider,
+    prompt: &str,
+) -> Result<Zeroizing<String>> {
+    if !is_pin_set(device)? {
+        return Err(Error::PinNotSet);
+    }
+
+    let retries = check_pin_retries(device)?;
+    let prompt = if retries <= LOW_PIN_RETRIES {
+        format!(
+            "{} (⚠️  only {} attempt(s) left before the PIN is blocked for good!)",
+            prompt, retries
+        )
+    } else {
+        format!("{} ({} attempts left)", prompt, retries)
+    };
+
+    provider.get_pin(&prompt)
+}
+
+/// Maps an authenticator error to a typed one, filling in the retries left
+/// after a wrong PIN.
+pub fn explain_pin_error(device: &impl Authenticator, error: anyhow::Error) -> Error {
+    match Error::from(error) {
+        Error::PinInvalid { .. } => Error::PinInvalid {
+            retries_left: device.get_pin_retries().ok(),
+        },
+        error => error,
+    }
+}
+
+fn read_new_pin(provider: &dyn PinProvider, min_length: usize) -> Result<Zeroizing<String>> {
+    let pin = provider.get_pin("Enter new PIN")?;
+    let confirmation = provider.get_pin("Confirm new PIN")?;
+
+    if *pin != *confirmation {
+        return Err(Error::InvalidInput("PINs do not match".to_string()));
+    }
+
+    if pin.chars().count() < min_length {
+        return Err(Error::InvalidInput(format!(
+            "PIN must have at least {} characters",
+            min_length
+        )));
+    }
+
+    if pin.len() > MAX_PIN_BYTES {
+        return Err(Error::InvalidInput(format!(
+            "PIN must be at most {} bytes",
+            MAX_PIN_BYTES
+        )));
+    }
 
-pub fn get_pin_from_user() -> Result<String> {
-    print!("Enter your PIN: ");
-    io::stdout().flush()?;
-    let pin = rpassword::read_password()?;
     Ok(pin)
 }
+
+fn min_pin_length(device: &impl Authenticator) -> usize {
+    device
+        .get_authenticator_info()
+        .map(|info| (info.min_pin_length as usize).max(MIN_PIN_LENGTH))
+        .unwrap_or(MIN_PIN_LENGTH)
+}
+
+/// Sets the initial PIN on an authenticator that has none.
+pub fn set_pin(device: &impl Authenticator, provider: &dyn PinProvider) -> Result<()> {
+    if is_pin_set(device)? {
+        return Err(Error::InvalidInput(
+            "a PIN is already set on this device; change it instead".to_string(),
+        ));
+    }
+
+    let pin = read_new_pin(provider, min_pin_length(device))?;
+
+    device
+        .set_new_pin(&pin)
+        .map_err(|e| explain_pin_error(device, e))
+}
+
+pub fn change_pin(device: &impl Authenticator, provider: &dyn PinProvider) -> Result<()> {
+    let current_pin = prompt_pin(device, provider, "Enter current PIN")?;
+    let new_pin = read_new_pin(provider, min_pin_length(device))?;
+
+    device
+        .change_pin(&current_pin, &new_pin)
+        .map_err(|e| explain_pin_error(device, e))
+}
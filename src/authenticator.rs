@@ -0,0 +1,259 @@
+use anyhow::Result;
+use ctap_hid_fido2::{
+    fidokey::{get_info::InfoOption, large_blobs::large_blobs_params::LargeBlobData},
+    public_key_credential_user_entity::PublicKeyCredentialUserEntity,
+};
+use std::fmt;
+use std::ops::BitOr;
+use zeroize::Zeroizing;
+
+/// Initial contents of an authenticator's largeBlob array: a serialized empty CBOR array.
+pub const EMPTY_LARGE_BLOB_ARRAY: [u8; 1] = [0x80];
+
+/// Trailing truncated SHA-256 that the authenticator appends to the serialized array.
+pub const LARGE_BLOB_HASH_LEN: usize = 16;
+
+/// Minimum maxSerializedLargeBlobArray an authenticator may report (CTAP 2.1 §6.4).
+pub const MIN_SERIALIZED_LARGE_BLOB_ARRAY: usize = 1024;
+
+/// maxMsgSize assumed when the authenticator reports none (CTAP 2.1 §6.4).
+pub const DEFAULT_MAX_MSG_SIZE: u32 = 1024;
+
+/// The authenticatorGetInfo fields the crate cares about.
+#[derive(Debug, Clone, Default)]
+pub struct AuthenticatorInfo {
+    pub versions: Vec<String>,
+    pub extensions: Vec<String>,
+    pub aaguid: Vec<u8>,
+    pub options: Vec<(String, bool)>,
+    pub max_msg_size: u32,
+    pub max_serialized_large_blob_array: u32,
+    pub remaining_discoverable_credentials: u32,
+    pub min_pin_length: u32,
+    pub firmware_version: u32,
+}
+
+impl AuthenticatorInfo {
+    pub fn option(&self, info_option: &InfoOption) -> Option<bool> {
+        self.options
+            .iter()
+            .find(|(name, _)| name == info_option.as_ref())
+            .map(|(_, value)| *value)
+    }
+
+    pub fn max_large_blob_array(&self) -> usize {
+        (self.max_serialized_large_blob_array as usize).max(MIN_SERIALIZED_LARGE_BLOB_ARRAY)
+    }
+}
+
+/// A discoverable credential as reported by authenticatorCredentialManagement.
+#[derive(Debug, Clone)]
+pub struct ResidentCredential {
+    pub credential_id: Vec<u8>,
+    pub user: PublicKeyCredentialUserEntity,
+}
+
+/// A status code other than success returned by the authenticator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CtapStatus(pub u8);
+
+impl CtapStatus {
+    pub const INVALID_PARAMETER: Self = Self(0x02);
+    pub const UNSUPPORTED_EXTENSION: Self = Self(0x16);
+    pub const LARGE_BLOB_STORAGE_FULL: Self = Self(0x18);
+    pub const OPERATION_DENIED: Self = Self(0x27);
+    pub const KEY_STORE_FULL: Self = Self(0x28);
+    pub const KEEPALIVE_CANCEL: Self = Self(0x2D);
+    pub const NO_CREDENTIALS: Self = Self(0x2E);
+    pub const USER_ACTION_TIMEOUT: Self = Self(0x2F);
+    pub const PIN_INVALID: Self = Self(0x31);
+    pub const PIN_BLOCKED: Self = Self(0x32);
+    pub const PIN_AUTH_INVALID: Self = Self(0x33);
+    pub const PIN_AUTH_BLOCKED: Self = Self(0x34);
+    pub const PIN_NOT_SET: Self = Self(0x35);
+    pub const PUAT_REQUIRED: Self = Self(0x36);
+    pub const PIN_POLICY_VIOLATION: Self = Self(0x37);
+    pub const INTEGRITY_FAILURE: Self = Self(0x3C);
+    pub const UNAUTHORIZED_PERMISSION: Self = Self(0x40);
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::INVALID_PARAMETER => "CTAP2_ERR_INVALID_PARAMETER",
+            Self::UNSUPPORTED_EXTENSION => "CTAP2_ERR_UNSUPPORTED_EXTENSION",
+            Self::LARGE_BLOB_STORAGE_FULL => "CTAP2_ERR_LARGE_BLOB_STORAGE_FULL",
+            Self::OPERATION_DENIED => "CTAP2_ERR_OPERATION_DENIED",
+            Self::KEY_STORE_FULL => "CTAP2_ERR_KEY_STORE_FULL",
+            Self::KEEPALIVE_CANCEL => "CTAP2_ERR_KEEPALIVE_CANCEL",
+            Self::NO_CREDENTIALS => "CTAP2_ERR_NO_CREDENTIALS",
+            Self::USER_ACTION_TIMEOUT => "CTAP2_ERR_USER_ACTION_TIMEOUT",
+            Self::PIN_INVALID => "CTAP2_ERR_PIN_INVALID",
+            Self::PIN_BLOCKED => "CTAP2_ERR_PIN_BLOCKED",
+            Self::PIN_AUTH_INVALID => "CTAP2_ERR_PIN_AUTH_INVALID",
+            Self::PIN_AUTH_BLOCKED => "CTAP2_ERR_PIN_AUTH_BLOCKED",
+            Self::PIN_NOT_SET => "CTAP2_ERR_PIN_NOT_SET",
+            Self::PUAT_REQUIRED => "CTAP2_ERR_PUAT_REQUIRED",
+            Self::PIN_POLICY_VIOLATION => "CTAP2_ERR_PIN_POLICY_VIOLATION",
+            Self::INTEGRITY_FAILURE => "CTAP2_ERR_INTEGRITY_FAILURE",
+            Self::UNAUTHORIZED_PERMISSION => "CTAP2_ERR_UNAUTHORIZED_PERMISSION",
+            _ => "unknown status",
+        }
+    }
+}
+
+impl fmt::Display for CtapStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:02X} {}", self.0, self.name())
+    }
+}
+
+impl std::error::Error for CtapStatus {}
+
+/// The status the authenticator failed `error` with, if it did.
+///
+/// Both authenticators return failures as a typed [`CtapStatus`], possibly
+/// under added context, so the status is never parsed out of a message.
+pub fn ctap_status(error: &anyhow::Error) -> Option<CtapStatus> {
+    error.downcast_ref::<CtapStatus>().copied()
+}
+
+/// What a pinUvAuthToken may be used for (CTAP 2.1 §6.5.5.7).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Permissions(u8);
+
+impl Permissions {
+    pub const MAKE_CREDENTIAL: Self = Self(0x01);
+    pub const GET_ASSERTION: Self = Self(0x02);
+    pub const CREDENTIAL_MANAGEMENT: Self = Self(0x04);
+    pub const LARGE_BLOB_WRITE: Self = Self(0x10);
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// A pinUvAuthToken the authenticator handed out for the PIN.
+///
+/// Commands are authorized with it instead of the PIN. It stops working when
+/// the authenticator is power cycled or hands out another one, and CTAP 2.1
+/// takes every permission but `LARGE_BLOB_WRITE` away from it once an
+/// assertion or registration has checked for user presence.
+pub struct PinUvAuthToken {
+    key: Zeroizing<Vec<u8>>,
+    permissions: Permissions,
+}
+
+impl PinUvAuthToken {
+    pub fn new(key: Vec<u8>, permissions: Permissions) -> Self {
+        Self {
+            key: Zeroizing::new(key),
+            permissions,
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn permissions(&self) -> Permissions {
+        self.permissions
+    }
+}
+
+impl fmt::Debug for PinUvAuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PinUvAuthToken")
+            .field("permissions", &self.permissions)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An authenticatorGetAssertion for one of the crate's credentials.
+#[derive(Debug, Clone, Copy)]
+pub struct AssertionRequest<'a> {
+    pub rp_id: &'a str,
+    /// Empty to let the authenticator pick a discoverable credential.
+    pub credential_ids: &'a [Vec<u8>],
+    /// No, one or two hmac-secret salts, answered in the same order.
+    pub hmac_secret_salts: &'a [[u8; 32]],
+    pub large_blob_key: bool,
+}
+
+/// The parts of an assertion the crate uses; the signature is never checked.
+#[derive(Debug, Default)]
+pub struct AssertionResponse {
+    pub credential_id: Vec<u8>,
+    /// One output per requested salt.
+    pub hmac_secret: Vec<Zeroizing<[u8; 32]>>,
+    /// `None` for credentials created without a largeBlobKey.
+    pub large_blob_key: Option<Zeroizing<[u8; 32]>>,
+}
+
+/// The subset of CTAP2 operations the crate relies on.
+///
+/// Implemented for a USB [`HidAuthenticator`](crate::hid::HidAuthenticator)
+/// and for the in-memory
+/// [`SoftAuthenticator`](crate::soft_authenticator::SoftAuthenticator).
+/// Failures the authenticator reports come back as a [`CtapStatus`].
+pub trait Authenticator {
+    /// Exchanges the PIN for a token carrying `permissions`. A wrong PIN
+    /// counts against the retries like on any other command.
+    fn get_pin_uv_auth_token(&self, pin: &str, permissions: Permissions) -> Result<PinUvAuthToken>;
+
+    fn get_assertion(
+        &self,
+        request: &AssertionRequest<'_>,
+        token: &PinUvAuthToken,
+    ) -> Result<AssertionResponse>;
+
+    /// Registers a discoverable credential with hmac-secret and a
+    /// largeBlobKey, returning its ID.
+    fn make_credential(
+        &self,
+        rp_id: &str,
+        user: &PublicKeyCredentialUserEntity,
+        token: &PinUvAuthToken,
+    ) -> Result<Vec<u8>>;
+
+    fn get_large_blob(&self) -> Result<LargeBlobData>;
+
+    /// Replaces the largeBlob array with `data`; the trailing hash is added here.
+    fn write_large_blob(&self, token: &PinUvAuthToken, data: Vec<u8>) -> Result<()>;
+
+    fn enable_info_option(&self, info_option: &InfoOption) -> Result<Option<bool>>;
+
+    fn get_authenticator_info(&self) -> Result<AuthenticatorInfo>;
+
+    fn get_pin_retries(&self) -> Result<i32>;
+
+    fn set_new_pin(&self, pin: &str) -> Result<()>;
+
+    fn change_pin(&self, current_pin: &str, new_pin: &str) -> Result<()>;
+
+    /// Discoverable credentials for `rpid`, in the order the authenticator
+    /// enumerates them. Empty when the RP has none.
+    fn enumerate_credentials(
+        &self,
+        token: &PinUvAuthToken,
+        rpid: &str,
+    ) -> Result<Vec<ResidentCredential>>;
+
+    fn delete_credential(&self, token: &PinUvAuthToken, credential_id: &[u8]) -> Result<()>;
+
+    /// Bytes available for largeBlob data, excluding the trailing hash.
+    fn large_blob_capacity(&self) -> Result<usize> {
+        let info = self.get_authenticator_info()?;
+        Ok(info.max_large_blob_array() - LARGE_BLOB_HASH_LEN)
+    }
+}
@@ -0,0 +1,228 @@
+use crate::blob_format::{Algorithm, EntryType};
+use crate::blob_operations::EntryStatus;
+use crate::encryption::{Cipher, decrypt_with_key, encrypt_with_key};
+use crate::error::{Error, Result};
+use crate::kdf::{KeyPurpose, PassphraseKdf};
+use crate::secret::Secret;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Prefix of every backup file.
+pub const BACKUP_MAGIC: &[u8; 4] = b"YNSB";
+
+pub const BACKUP_VERSION: u8 = 1;
+
+/// Readable part of a backup file: how the passphrase is turned into the key.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    /// HKDF label the key was derived with from the stretched passphrase.
+    kdf: String,
+    /// Name of the AEAD the entries are sealed with.
+    cipher: String,
+    passphrase: PassphraseKdf,
+    created_at: u64,
+}
+
+/// The encrypted part of a backup file.
+#[derive(Deserialize)]
+struct Payload {
+    entries: Vec<BackupEntry>,
+}
+
+#[derive(Serialize)]
+struct PayloadRef<'a> {
+    entries: &'a [BackupEntry],
+}
+
+/// An entry's passphrase as the backup keeps it: its Argon2id parameters and
+/// output, so a restored entry needs the same passphrase without asking for it.
+///
+/// The output is all the entry's key needs besides the vault, and the entry's
+/// secret sits next to it in the payload anyway: inside a backup, an entry
+/// is protected by the backup passphrase only, not by its own.
+#[derive(Serialize, Deserialize)]
+pub struct EntryPassphrase {
+    pub kdf: PassphraseKdf,
+    #[serde(with = "serde_bytes")]
+    pub key: Vec<u8>,
+}
+
+impl Drop for EntryPassphrase {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+/// One decrypted entry with its metadata.
+#[derive(Serialize, Deserialize)]
+pub struct BackupEntry {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub entry_type: EntryType,
+    /// The algorithm it was stored with, kept on restore if it is a vault one.
+    #[serde(rename = "alg")]
+    pub algorithm: Algorithm,
+    pub created_at: Option<u64>,
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub petname: Option<String>,
+    #[serde(default)]
+    pub default: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<EntryPassphrase>,
+    /// The plaintext, as stored in the largeBlob.
+    #[serde(with = "serde_bytes")]
+    pub secret: Vec<u8>,
+}
+
+impl BackupEntry {
+    /// The entry's secret, checked against its type and recorded public key.
+    pub fn secret(&self) -> Result<Secret> {
+        let secret = Secret::from_plaintext(self.entry_type, Zeroizing::new(self.secret.clone()))?;
+        if self.public_key.is_some()
+            && secret.public_key().map(|key| key.to_bytes().to_vec()) != self.public_key
+        {
+            return Err(Error::PublicKeyMismatch);
+        }
+        Ok(secret)
+    }
+}
+
+impl Drop for BackupEntry {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+/// Every entry of a profile, decrypted, for writing to or reading from a
+/// passphrase-encrypted file that any authenticator can be restored from.
+pub struct Backup {
+    /// Unix seconds.
+    pub created_at: u64,
+    pub entries: Vec<BackupEntry>,
+}
+
+impl Backup {
+    pub fn new(entries: Vec<BackupEntry>) -> Self {
+        Self {
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            entries,
+        }
+    }
+
+    /// The backup file: `magic || version || len(header) (u32 BE) || header
+    /// || ciphertext`, the header in CBOR and the entries sealed with
+    /// `cipher` under a key derived from the Argon2id output of `passphrase`.
+    /// Everything before the ciphertext is associated data.
+    pub fn seal(&self, passphrase: &str, cipher: Cipher) -> Result<Vec<u8>> {
+        if passphrase.is_empty() {
+            return Err(Error::InvalidInput(
+                "passphrase cannot be empty".to_string(),
+            ));
+        }
+
+        let header = Header {
+            kdf: KeyPurpose::Backup.label().to_string(),
+            cipher: cipher.name().to_string(),
+            passphrase: PassphraseKdf::new(),
+            created_at: self.created_at,
+        };
+        let key = header.passphrase.stretch(passphrase)?;
+
+        let mut data = BACKUP_MAGIC.to_vec();
+        data.push(BACKUP_VERSION);
+        let mut encoded_header = Vec::new();
+        ciborium::into_writer(&header, &mut encoded_header)
+            .map_err(|e| Error::InvalidInput(format!("cannot encode backup: {}", e)))?;
+        data.extend_from_slice(&(encoded_header.len() as u32).to_be_bytes());
+        data.extend_from_slice(&encoded_header);
+
+        let mut plaintext = Zeroizing::new(Vec::new());
+        let payload = PayloadRef {
+            entries: &self.entries,
+        };
+        ciborium::into_writer(&payload, &mut *plaintext)
+            .map_err(|e| Error::InvalidInput(format!("cannot encode backup: {}", e)))?;
+
+        let ciphertext =
+            encrypt_with_key(key.as_ref(), cipher, KeyPurpose::Backup, &plaintext, &data)?;
+        data.extend(ciphertext);
+        Ok(data)
+    }
+
+    /// Reads a file written by `seal`. A wrong passphrase, or a file changed
+    /// after it was written, fails with `DecryptFailed`.
+    pub fn open(data: &[u8], passphrase: &str) -> Result<Self> {
+        let (version, rest) = data
+            .strip_prefix(BACKUP_MAGIC.as_slice())
+            .and_then(|rest| rest.split_first())
+            .ok_or(Error::BackupCorrupted)?;
+        if *version != BACKUP_VERSION {
+            return Err(Error::UnsupportedBackupFormat(*version as u32));
+        }
+
+        let (header_len, rest) = rest
+            .split_first_chunk::<4>()
+            .ok_or(Error::BackupCorrupted)?;
+        let header_len = u32::from_be_bytes(*header_len) as usize;
+        if rest.len() < header_len {
+            return Err(Error::BackupCorrupted);
+        }
+        let (encoded_header, ciphertext) = rest.split_at(header_len);
+        let header: Header =
+            ciborium::from_reader(encoded_header).map_err(|_| Error::BackupCorrupted)?;
+        if KeyPurpose::from_label(&header.kdf) != Some(KeyPurpose::Backup) {
+            return Err(Error::UnsupportedKeyLabel(header.kdf));
+        }
+        let cipher: Cipher = header.cipher.parse()?;
+        // The header isn't authenticated until the passphrase is stretched.
+        if !header.passphrase.is_within_limits() {
+            return Err(Error::BackupCorrupted);
+        }
+
+        let key = header.passphrase.stretch(passphrase)?;
+        let associated_data = &data[..data.len() - ciphertext.len()];
+        let plaintext = decrypt_with_key(
+            key.as_ref(),
+            cipher,
+            KeyPurpose::Backup,
+            ciphertext,
+            associated_data,
+        )?;
+        let payload: Payload =
+            ciborium::from_reader(plaintext.as_slice()).map_err(|_| Error::BackupCorrupted)?;
+
+        Ok(Self {
+            created_at: header.created_at,
+            entries: payload.entries,
+        })
+    }
+
+    /// What restoring each entry would find wrong with it, without touching
+    /// any authenticator.
+    pub fn verify(&self) -> Vec<(&BackupEntry, EntryStatus)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let status = if self.entries[..index].iter().any(|e| e.id == entry.id) {
+                    EntryStatus::DuplicateId
+                } else {
+                    match entry.secret() {
+                        Ok(_) => EntryStatus::Ok,
+                        Err(Error::PublicKeyMismatch) => EntryStatus::Malformed(
+                            "does not match its recorded public key".to_string(),
+                        ),
+                        Err(_) => EntryStatus::InvalidPayload(entry.entry_type),
+                    }
+                };
+                (entry, status)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,409 @@
+use crate::authenticator::EMPTY_LARGE_BLOB_ARRAY;
+use crate::encryption::Cipher;
+use crate::error::{Error, Result};
+use crate::kdf::{KeyPurpose, PassphraseKdf};
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Prefix of every container, so it can't be mistaken for the legacy text format.
+pub const BLOB_MAGIC: &[u8; 4] = b"YNS\x00";
+
+pub const FORMAT_VERSION: u32 = 2;
+
+// Version 1 had no entries bound to their identity; it is read and rewritten.
+const MIN_FORMAT_VERSION: u32 = 1;
+
+const LEGACY_ENTRY_SEPARATOR: char = '|';
+const LEGACY_ID_SEPARATOR: char = ':';
+
+/// What an entry's plaintext holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryType {
+    /// 32 raw bytes (hex text in entries stored by older versions).
+    NostrSecretKey,
+    /// NIP-49 password-encrypted secret key, as its `ncryptsec1...` string.
+    Ncryptsec,
+    /// NIP-06 BIP-39 mnemonic, as lowercase words separated by single spaces.
+    Mnemonic,
+    /// NIP-47 `nostr+walletconnect://` URI.
+    NostrWalletConnect,
+    /// Any other secret, stored as given.
+    Opaque,
+}
+
+impl EntryType {
+    /// The name the type is stored under.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EntryType::NostrSecretKey => "nostr-secret-key",
+            EntryType::Ncryptsec => "ncryptsec",
+            EntryType::Mnemonic => "mnemonic",
+            EntryType::NostrWalletConnect => "nostr-wallet-connect",
+            EntryType::Opaque => "opaque",
+        }
+    }
+
+    pub const ALL: [EntryType; 5] = [
+        EntryType::NostrSecretKey,
+        EntryType::Ncryptsec,
+        EntryType::Mnemonic,
+        EntryType::NostrWalletConnect,
+        EntryType::Opaque,
+    ];
+}
+
+impl fmt::Display for EntryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EntryType::NostrSecretKey => "Nostr secret key",
+            EntryType::Ncryptsec => "NIP-49 ncryptsec",
+            EntryType::Mnemonic => "NIP-06 mnemonic",
+            EntryType::NostrWalletConnect => "Nostr Wallet Connect URI",
+            EntryType::Opaque => "opaque secret",
+        })
+    }
+}
+
+/// How an entry's ciphertext was produced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Algorithm {
+    /// `salt (32) || nonce (12) || AES-256-GCM ciphertext`, keyed by hmac-secret(salt).
+    HmacSecretAes256Gcm,
+    /// As `HmacSecretAes256Gcm`, with the entry's identity as associated data
+    /// (see `associated_data`), so a ciphertext moved to another entry,
+    /// type, format or credential no longer decrypts.
+    HmacSecretAes256GcmAad,
+    /// `salt (32) || nonce (12) || AES-256-GCM ciphertext`, keyed by
+    /// HKDF(vault master key, salt) and bound like `HmacSecretAes256GcmAad`.
+    /// Decrypted on the host once the vault is unlocked.
+    VaultAes256Gcm,
+    /// As `VaultAes256Gcm`, with XChaCha20-Poly1305 and a 24-byte nonce.
+    VaultXchacha20Poly1305,
+    /// As `VaultAes256Gcm`, with AES-256-GCM-SIV.
+    VaultAes256GcmSiv,
+}
+
+impl Algorithm {
+    /// The vault algorithm sealing with `cipher`.
+    pub fn vault(cipher: Cipher) -> Self {
+        match cipher {
+            Cipher::Aes256Gcm => Algorithm::VaultAes256Gcm,
+            Cipher::XChaCha20Poly1305 => Algorithm::VaultXchacha20Poly1305,
+            Cipher::Aes256GcmSiv => Algorithm::VaultAes256GcmSiv,
+        }
+    }
+
+    /// Whether entries are keyed by the vault master key rather than
+    /// directly by an hmac-secret output.
+    pub fn is_vault(&self) -> bool {
+        !matches!(
+            self,
+            Algorithm::HmacSecretAes256Gcm | Algorithm::HmacSecretAes256GcmAad
+        )
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        match self {
+            Algorithm::HmacSecretAes256Gcm
+            | Algorithm::HmacSecretAes256GcmAad
+            | Algorithm::VaultAes256Gcm => Cipher::Aes256Gcm,
+            Algorithm::VaultXchacha20Poly1305 => Cipher::XChaCha20Poly1305,
+            Algorithm::VaultAes256GcmSiv => Cipher::Aes256GcmSiv,
+        }
+    }
+}
+
+/// The vault master key, wrapped under an hmac-secret output of the
+/// credential. Unwrapping it is the only assertion entries of the
+/// `VaultAes256Gcm` algorithm need.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrappedVaultKey {
+    /// HKDF label the wrapping key was derived with from the hmac-secret output.
+    pub kdf: String,
+    /// The master key as `encrypt_data` output.
+    #[serde(with = "serde_bytes")]
+    pub wrapped: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryRecord {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub entry_type: EntryType,
+    #[serde(rename = "alg")]
+    pub algorithm: Algorithm,
+    /// HKDF label the entry's key was derived with from the hmac-secret
+    /// output; `None` for entries keyed by the output directly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<String>,
+    /// Set on entries that also need a passphrase: how it is stretched
+    /// before joining the vault master key in the entry's key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<PassphraseKdf>,
+    /// Unix seconds; `None` for entries migrated from the legacy format.
+    pub created_at: Option<u64>,
+    /// X-only public key of a Nostr secret key or mnemonic, kept in the clear so entries
+    /// can be listed without decrypting them. Checked after every decrypt.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub petname: Option<String>,
+    /// The entry the bunker uses unless told otherwise.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub default: bool,
+    #[serde(with = "serde_bytes")]
+    pub ciphertext: Vec<u8>,
+    /// Format version the entry was encrypted under, which its associated
+    /// data binds. Stored only when it differs from the container's; filled
+    /// in from the container when read.
+    #[serde(default, rename = "version", skip_serializing_if = "Option::is_none")]
+    pub format_version: Option<u32>,
+    /// Carried over from the legacy data every profile used to list, so it
+    /// may belong to another profile; vault entries among them are under
+    /// the container's `shared_vault`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub shared: bool,
+}
+
+impl EntryRecord {
+    pub fn new(id: &str, entry_type: EntryType, algorithm: Algorithm, ciphertext: Vec<u8>) -> Self {
+        Self {
+            id: id.to_string(),
+            entry_type,
+            algorithm,
+            kdf: Some(KeyPurpose::Entry.label().to_string()),
+            passphrase: None,
+            created_at: Some(now()),
+            public_key: None,
+            petname: None,
+            default: false,
+            ciphertext,
+            format_version: Some(FORMAT_VERSION),
+            shared: false,
+        }
+    }
+
+    /// Whether the entry was encrypted the way older versions did, directly
+    /// under an hmac-secret output instead of the vault master key, and
+    /// should be encrypted again.
+    pub fn is_outdated(&self) -> bool {
+        !self.algorithm.is_vault() || self.kdf.as_deref() != Some(KeyPurpose::Entry.label())
+    }
+
+    /// Whether `BlobStore::upgrade_encryption` would rewrite the entry: it is
+    /// outdated, shared legacy data, or a key whose public key isn't
+    /// recorded yet.
+    pub fn needs_upgrade(&self) -> bool {
+        self.is_outdated()
+            || self.shared
+            || (self.public_key.is_none()
+                && matches!(
+                    self.entry_type,
+                    EntryType::NostrSecretKey | EntryType::Mnemonic
+                ))
+    }
+}
+
+/// Associated data an entry is encrypted with under `HmacSecretAes256GcmAad`
+/// and the vault algorithms:
+/// a label, the format version the entry was encrypted under, then the entry
+/// ID, entry type and credential ID, each prefixed with its length (u32,
+/// big-endian).
+pub fn associated_data(
+    id: &str,
+    entry_type: EntryType,
+    format_version: u32,
+    credential_id: &[u8],
+) -> Vec<u8> {
+    let mut aad = b"yubikey-nostr-signer entry".to_vec();
+    aad.extend_from_slice(&format_version.to_be_bytes());
+    for field in [id.as_bytes(), entry_type.name().as_bytes(), credential_id] {
+        aad.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        aad.extend_from_slice(field);
+    }
+    aad
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+#[derive(Serialize, Deserialize)]
+struct Container {
+    version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vault: Option<WrappedVaultKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shared_vault: Option<WrappedVaultKey>,
+    entries: Vec<EntryRecord>,
+}
+
+/// Entries read from the largeBlob, and whether they still need rewriting.
+#[derive(Debug, Default)]
+pub struct ParsedBlob {
+    pub entries: Vec<EntryRecord>,
+    pub legacy: bool,
+    /// The vault of this credential's entries.
+    pub vault: Option<WrappedVaultKey>,
+    /// The vault stored with the shared legacy entries, if any.
+    pub shared_vault: Option<WrappedVaultKey>,
+}
+
+impl ParsedBlob {
+    /// The vault `entry` was encrypted under, if it was.
+    pub fn vault_for(&self, entry: &EntryRecord) -> Option<&WrappedVaultKey> {
+        if entry.shared {
+            self.shared_vault.as_ref()
+        } else {
+            self.vault.as_ref()
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn encode(entries: &[EntryRecord], vault: Option<&WrappedVaultKey>) -> Result<Vec<u8>> {
+    encode_with_shared_vault(entries, vault, None)
+}
+
+/// As `encode`, keeping the vault of the shared legacy entries among `entries`.
+pub fn encode_with_shared_vault(
+    entries: &[EntryRecord],
+    vault: Option<&WrappedVaultKey>,
+    shared_vault: Option<&WrappedVaultKey>,
+) -> Result<Vec<u8>> {
+    let entries = entries
+        .iter()
+        .map(|entry| EntryRecord {
+            // The container records the current version for everything else.
+            format_version: entry.format_version.filter(|version| {
+                *version != FORMAT_VERSION && entry.algorithm != Algorithm::HmacSecretAes256Gcm
+            }),
+            ..entry.clone()
+        })
+        .collect();
+    let container = Container {
+        version: FORMAT_VERSION,
+        vault: vault.cloned(),
+        shared_vault: shared_vault.cloned(),
+        entries,
+    };
+
+    let mut data = BLOB_MAGIC.to_vec();
+    ciborium::into_writer(&container, &mut data)
+        .map_err(|e| Error::InvalidInput(format!("cannot encode largeBlob: {}", e)))?;
+    Ok(data)
+}
+
+/// Parses a container, or any of the legacy encodings.
+pub fn decode(data: &[u8]) -> Result<ParsedBlob> {
+    let Some(body) = data.strip_prefix(BLOB_MAGIC.as_slice()) else {
+        return decode_legacy(data);
+    };
+
+    let mut container: Container =
+        ciborium::from_reader(body).map_err(|_| Error::EntryCorrupted)?;
+    if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&container.version) {
+        return Err(Error::UnsupportedFormat(container.version));
+    }
+    for entry in &mut container.entries {
+        entry.format_version.get_or_insert(container.version);
+    }
+
+    Ok(ParsedBlob {
+        entries: container.entries,
+        legacy: container.version != FORMAT_VERSION,
+        vault: container.vault,
+        shared_vault: container.shared_vault,
+    })
+}
+
+/// `id`, or `id-2`, `id-3`... if an entry already has it.
+pub fn unique_id(entries: &[EntryRecord], id: &str) -> String {
+    let taken = |candidate: &str| entries.iter().any(|entry| entry.id == candidate);
+    if !taken(id) {
+        return id.to_string();
+    }
+
+    (2..)
+        .map(|n| format!("{}-{}", id, n))
+        .find(|candidate| !taken(candidate))
+        .unwrap_or_default()
+}
+
+/// Reads `id:base64|id:base64`, including ID-less base64 or hex entries and
+/// the `base64("EMPTY")` / `hex("EMPTY")` placeholders. IDs the old format
+/// allowed to repeat are made unique.
+fn decode_legacy(data: &[u8]) -> Result<ParsedBlob> {
+    if data.is_empty() || data == EMPTY_LARGE_BLOB_ARRAY {
+        return Ok(ParsedBlob::default());
+    }
+
+    let content = std::str::from_utf8(data).map_err(|_| Error::EntryCorrupted)?;
+    if content == general_purpose::STANDARD.encode("EMPTY") || content == hex::encode("EMPTY") {
+        return Ok(ParsedBlob {
+            legacy: true,
+            ..ParsedBlob::default()
+        });
+    }
+
+    let mut entries = Vec::new();
+    for (index, entry) in content
+        .split(LEGACY_ENTRY_SEPARATOR)
+        .filter(|e| !e.is_empty())
+        .enumerate()
+    {
+        let (id, ciphertext) = match entry.split_once(LEGACY_ID_SEPARATOR) {
+            Some((id, encoded)) => (id.to_string(), decode_legacy_ciphertext(encoded)),
+            None => (
+                format!("entry-{}", index + 1),
+                decode_legacy_ciphertext(entry),
+            ),
+        };
+
+        entries.push(EntryRecord {
+            id: unique_id(&entries, &id),
+            entry_type: EntryType::NostrSecretKey,
+            algorithm: Algorithm::HmacSecretAes256Gcm,
+            kdf: None,
+            passphrase: None,
+            created_at: None,
+            public_key: None,
+            petname: None,
+            default: false,
+            ciphertext,
+            format_version: None,
+            shared: false,
+        });
+    }
+
+    Ok(ParsedBlob {
+        entries,
+        legacy: true,
+        ..ParsedBlob::default()
+    })
+}
+
+// ID-less entries were either base64 or hex. Random ciphertext encoded as
+// base64 is never all hex digits, so those are read as hex. Anything that
+// decodes as neither is kept as is and will fail to decrypt.
+fn decode_legacy_ciphertext(encoded: &str) -> Vec<u8> {
+    let looks_hex =
+        encoded.len().is_multiple_of(2) && encoded.bytes().all(|b| b.is_ascii_hexdigit());
+    if looks_hex && let Ok(bytes) = hex::decode(encoded) {
+        return bytes;
+    }
+
+    general_purpose::STANDARD
+        .decode(encoded)
+        .unwrap_or_else(|_| encoded.as_bytes().to_vec())
+}
@@ -1,416 +1,1356 @@
-use crate::auth::get_pin_from_user;
-use crate::encryption::{decrypt_data, encrypt_data};
-use anyhow::{Context, Result, anyhow};
-use base64::{Engine as _, engine::general_purpose};
-use ctap_hid_fido2::fidokey::FidoKeyHid;
-use std::io::{self, Write};
-use zeroize::Zeroize;
-
-fn get_entry_id() -> Result<String> {
-    print!("Enter an ID for this entry: ");
-    io::stdout().flush()?;
-    let mut id_input = String::new();
-    io::stdin().read_line(&mut id_input)?;
-    let entry_id = id_input.trim().to_string();
-
-    if entry_id.is_empty() {
-        return Err(anyhow!("ID cannot be empty"));
-    }
-
-    Ok(entry_id)
+use crate::authenticator::{Authenticator, CtapStatus, EMPTY_LARGE_BLOB_ARRAY, ctap_status};
+use crate::backup::{Backup, BackupEntry, EntryPassphrase};
+use crate::blob_format::{
+    self, Algorithm, EntryRecord, EntryType, FORMAT_VERSION, ParsedBlob, WrappedVaultKey,
+};
+use crate::encryption::{Cipher, decrypt_data, decrypt_with_key, encrypt_with_key};
+use crate::error::{Error, Result};
+use crate::kdf::{KeyPurpose, PassphraseKdf};
+use crate::large_blob::LargeBlobArray;
+use crate::profile::Profile;
+use crate::secret::Secret;
+use crate::session::Session;
+use crate::snapshot::{load_snapshot, recorded_vault, save_snapshot, snapshot_path};
+use crate::vault;
+use nostr::{PublicKey, ToBech32};
+use std::fmt;
+use std::path::PathBuf;
+use zeroize::Zeroizing;
+
+/// Decrypts `entry` with the associated data its algorithm calls for.
+/// Vault entries are decrypted on the host once `vault` is unlocked; those
+/// flagged for a passphrase also need its stretched form.
+fn decrypt_entry(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+    vault: Option<&WrappedVaultKey>,
+    passphrase_key: Option<&[u8; 32]>,
+    entry: &EntryRecord,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let purpose = match entry.kdf.as_deref() {
+        None => None,
+        Some(label) => match KeyPurpose::from_label(label) {
+            Some(KeyPurpose::Entry) => Some(KeyPurpose::Entry),
+            // Keys of other purposes never open entries.
+            _ => return Err(Error::UnsupportedKeyLabel(label.to_string())),
+        },
+    };
+    let aad = match entry.algorithm {
+        Algorithm::HmacSecretAes256Gcm => Vec::new(),
+        _ => blob_format::associated_data(
+            &entry.id,
+            entry.entry_type,
+            entry.format_version.unwrap_or(FORMAT_VERSION),
+            credential_id,
+        ),
+    };
+    if entry.algorithm.is_vault() {
+        let passphrase_key = match (&entry.passphrase, passphrase_key) {
+            (Some(_), None) => return Err(Error::PassphraseRequired),
+            (Some(_), key) => key,
+            (None, _) => None,
+        };
+        let vault = vault.ok_or(Error::VaultKeyMissing)?;
+        let master_key = vault::unlock(device, session, profile, credential_id, vault)?;
+        return decrypt_with_key(
+            &key_material(&master_key, passphrase_key),
+            entry.algorithm.cipher(),
+            purpose.unwrap_or(KeyPurpose::Entry),
+            &entry.ciphertext,
+            &aad,
+        );
+    }
+    decrypt_data(
+        device,
+        session,
+        profile,
+        credential_id,
+        purpose,
+        &entry.ciphertext,
+        &aad,
+    )
 }
 
-fn get_user_choice(prompt: &str) -> Result<usize> {
-    print!("{}", prompt);
-    io::stdout().flush()?;
-    let mut input = String::new();
-    io::stdin().read_line(&mut input)?;
-    Ok(input.trim().parse().unwrap_or(0))
+/// What an entry's key is derived from: the vault master key, followed by
+/// the stretched passphrase for entries that have one.
+fn key_material(master_key: &[u8; 32], passphrase_key: Option<&[u8; 32]>) -> Zeroizing<Vec<u8>> {
+    let mut material = Zeroizing::new(master_key.to_vec());
+    if let Some(passphrase_key) = passphrase_key {
+        material.extend_from_slice(passphrase_key);
+    }
+    material
 }
 
-pub fn parse_blob_entries(blob_content: &str) -> Vec<String> {
-    if blob_content == general_purpose::STANDARD.encode("EMPTY") {
-        return Vec::new();
+fn decode_shared(data: &[u8]) -> Result<ParsedBlob> {
+    let mut parsed = blob_format::decode(data)?;
+    for entry in &mut parsed.entries {
+        entry.shared = true;
     }
+    parsed.shared_vault = parsed.vault.take();
+    Ok(parsed)
+}
 
-    blob_content
-        .split('|')
-        .filter(|e| !e.is_empty())
-        .map(|e| e.to_string())
-        .collect()
+/// Reads the entries `large_blob_key` opens in the largeBlob array, plus any
+/// left from before the array followed CTAP 2.1.
+pub fn read_entries(
+    device: &mut impl Authenticator,
+    large_blob_key: Option<&[u8; 32]>,
+) -> Result<ParsedBlob> {
+    decode_entries(&device.get_large_blob()?.large_blob_array, large_blob_key)
 }
 
-fn is_blob_empty(blob_data: &[u8]) -> bool {
-    if blob_data.is_empty() {
-        return true;
-    }
+fn decode_entries(data: &[u8], large_blob_key: Option<&[u8; 32]>) -> Result<ParsedBlob> {
+    let Some(array) = LargeBlobArray::parse(data) else {
+        // Written raw by an older version. With a largeBlobKey it can now be
+        // moved into the array; without one it stays raw and isn't shared.
+        return match large_blob_key {
+            Some(_) => decode_shared(data).map(|parsed| ParsedBlob {
+                legacy: true,
+                ..parsed
+            }),
+            None => blob_format::decode(data),
+        };
+    };
 
-    if let Ok(content) = String::from_utf8(blob_data.to_vec()) {
-        content == general_purpose::STANDARD.encode("EMPTY") || content == hex::encode("EMPTY")
-    } else {
-        false
+    let mut parsed = match large_blob_key.and_then(|key| array.get(key)) {
+        Some(container) => blob_format::decode(&container)?,
+        None => ParsedBlob::default(),
+    };
+    // Left in the array by earlier versions; the next write moves it into
+    // this credential's element.
+    if let Some(legacy) = array.legacy_data() {
+        let shared = decode_shared(legacy)?;
+        parsed.entries.extend(shared.entries);
+        parsed.legacy = true;
+        if parsed.shared_vault.is_none() {
+            parsed.shared_vault = shared.shared_vault;
+        }
     }
+
+    Ok(parsed)
 }
 
-pub fn get_blob_content(device: &mut FidoKeyHid) -> Result<Option<String>> {
-    let result = device
-        .get_large_blob()
-        .context("Failed to read from largeBlob")?;
+fn clean_petname(petname: &str) -> Option<String> {
+    let petname = petname.trim();
+    (!petname.is_empty()).then(|| petname.to_string())
+}
 
-    if is_blob_empty(&result.large_blob_array) {
-        return Ok(None);
+fn validate_entry_id(id: &str) -> Result<()> {
+    if id.trim().is_empty() {
+        return Err(Error::InvalidInput("ID cannot be empty".to_string()));
     }
+    Ok(())
+}
 
-    String::from_utf8(result.large_blob_array)
-        .context("Invalid data in largeBlob")
-        .map(Some)
+/// An entry as listed from the largeBlob, without decrypting it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryInfo {
+    pub index: usize,
+    pub id: String,
+    pub entry_type: EntryType,
+    pub algorithm: Algorithm,
+    /// Encrypted the way older versions did; see `BlobStore::upgrade_encryption`.
+    pub outdated: bool,
+    /// `None` until the entry is first decrypted, for entries stored by older versions.
+    pub public_key: Option<PublicKey>,
+    pub petname: Option<String>,
+    /// Unix seconds; `None` for entries migrated from the legacy format.
+    pub created_at: Option<u64>,
+    pub default: bool,
+    /// Needs its passphrase, besides the authenticator, to be decrypted.
+    pub passphrase: bool,
+    /// Carried over from the legacy data every profile used to list, so it
+    /// may belong to another profile.
+    pub shared: bool,
+    /// `upgrade_encryption` would rewrite it.
+    pub needs_upgrade: bool,
+    /// Bytes of ciphertext.
+    pub size: usize,
 }
 
-fn display_entries(entries: &[String], title: &str) {
-    println!("\n{}:", title);
-    for (i, entry) in entries.iter().enumerate() {
-        if let Some(colon_pos) = entry.find(':') {
-            let entry_id = &entry[..colon_pos];
-            println!("{}: {}", i + 1, entry_id);
-        } else {
-            println!("{}: (entry without ID)", i + 1);
+impl EntryInfo {
+    fn new(index: usize, entry: &EntryRecord) -> Self {
+        Self {
+            index,
+            id: entry.id.clone(),
+            entry_type: entry.entry_type,
+            algorithm: entry.algorithm,
+            outdated: entry.is_outdated(),
+            public_key: entry
+                .public_key
+                .as_deref()
+                .and_then(|key| PublicKey::from_slice(key).ok()),
+            petname: entry.petname.clone(),
+            created_at: entry.created_at,
+            default: entry.default,
+            passphrase: entry.passphrase.is_some(),
+            shared: entry.shared,
+            needs_upgrade: entry.needs_upgrade(),
+            size: entry.ciphertext.len(),
         }
     }
 }
 
-fn handle_space_management(existing_entries: &[String], new_entry: &str) -> Result<Vec<String>> {
-    const MAX_SIZE: usize = 1024;
-    let current_size = existing_entries.join("|").len();
-    let needed_space = current_size + new_entry.len() + 1; // +1 for separator
-
-    if needed_space <= MAX_SIZE {
-        return Ok(existing_entries.to_vec());
+impl fmt::Display for EntryInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        if let Some(petname) = &self.petname {
+            write!(f, " ({})", petname)?;
+        }
+        if self.entry_type != EntryType::NostrSecretKey {
+            write!(f, " <{}>", self.entry_type)?;
+        }
+        if let Some(public_key) = &self.public_key {
+            let Ok(npub) = public_key.to_bech32();
+            write!(f, " | {}", npub)?;
+        }
+        if self.passphrase {
+            write!(f, " [passphrase]")?;
+        }
+        if self.default {
+            write!(f, " [default]")?;
+        }
+        Ok(())
     }
+}
 
-    println!("Insufficient space ({}/{} bytes).", needed_space, MAX_SIZE);
-    display_entries(existing_entries, "Existing entries");
+/// How `BlobStore::put_with` stores an entry.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PutOptions<'o> {
+    /// Needed besides the authenticator every time the entry is decrypted.
+    /// An overwritten entry keeps its own unless a new one is given.
+    pub passphrase: Option<&'o str>,
+    /// An overwritten entry keeps its own unless a new one is given.
+    pub petname: Option<&'o str>,
+    /// Replace an existing entry with the same ID instead of failing.
+    pub overwrite: bool,
+}
 
-    let choice = get_user_choice("Enter the entry number to remove (or 0 to cancel): ")?;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobCapacity {
+    pub used: usize,
+    pub total: usize,
+}
 
-    if choice == 0 {
-        return Err(anyhow!("Operation cancelled"));
+impl BlobCapacity {
+    pub fn available(&self) -> usize {
+        self.total.saturating_sub(self.used)
     }
+}
 
-    if choice > 0 && choice <= existing_entries.len() {
-        let mut updated_entries = existing_entries.to_vec();
-        updated_entries.remove(choice - 1);
-        println!("Entry {} removed.", choice);
-        Ok(updated_entries)
-    } else {
-        Err(anyhow!("Invalid choice"))
-    }
+/// What `BlobStore::check` found out about an entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryStatus {
+    Ok,
+    /// Fails to decrypt under this credential: encrypted under another
+    /// (possibly deleted) credential, or damaged.
+    Undecryptable,
+    /// Can't be what its record says it is.
+    Malformed(String),
+    /// An earlier entry has the same ID, so lookups by ID never reach it.
+    DuplicateId,
+    /// Decrypts, but not to what its entry type holds.
+    InvalidPayload(EntryType),
+    /// Not decrypted, since that would ask for its passphrase.
+    PassphraseProtected,
 }
 
-fn write_to_device(device: &mut FidoKeyHid, data: Vec<u8>) -> Result<()> {
-    let mut pin = get_pin_from_user()?;
+impl EntryStatus {
+    /// Whether there is nothing to repair.
+    pub fn is_ok(&self) -> bool {
+        matches!(self, EntryStatus::Ok | EntryStatus::PassphraseProtected)
+    }
+}
 
-    let result = device.write_large_blob(Some(pin.as_str()), data);
+impl fmt::Display for EntryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryStatus::Ok => write!(f, "ok"),
+            EntryStatus::Undecryptable => write!(f, "undecryptable"),
+            EntryStatus::Malformed(reason) => write!(f, "malformed: {}", reason),
+            EntryStatus::DuplicateId => write!(f, "duplicate ID"),
+            EntryStatus::InvalidPayload(entry_type) => write!(f, "not a valid {}", entry_type),
+            EntryStatus::PassphraseProtected => write!(f, "needs its passphrase, not checked"),
+        }
+    }
+}
 
-    pin.zeroize();
+#[derive(Debug, Clone)]
+pub struct EntryCheck {
+    pub entry: EntryInfo,
+    pub status: EntryStatus,
+}
 
-    result.map(|_| ()).context("Error writing to largeBlob")
+/// Encrypted entries in an authenticator's largeBlob, for one profile's credential.
+///
+/// Nothing here prompts: conditions such as a full largeBlob or an existing
+/// ID are returned as errors for the caller to handle.
+///
+/// Every write is checked against the array as it was read, preceded by a
+/// local snapshot of the previous array and followed by a read-back.
+pub struct BlobStore<'a, A: Authenticator> {
+    device: &'a mut A,
+    session: &'a mut Session,
+    profile: &'a Profile,
+    credential_id: &'a [u8],
+    // The array as last read, which a write must still find on the device.
+    observed: Option<Vec<u8>>,
+    // The vaults as last read, written back with the entries.
+    vault: Option<WrappedVaultKey>,
+    shared_vault: Option<WrappedVaultKey>,
+    // What new entries are sealed with.
+    cipher: Cipher,
+    // Stretched passphrases by Argon2id salt, asked for at most once per store.
+    passphrase_keys: Vec<(Vec<u8>, Zeroizing<[u8; 32]>)>,
 }
 
-fn build_final_data(existing_entries: Vec<String>, new_entry: String) -> Vec<u8> {
-    if existing_entries.is_empty() {
-        new_entry.into_bytes()
-    } else {
-        let mut all_entries = existing_entries;
-        all_entries.push(new_entry);
-        all_entries.join("|").into_bytes()
+impl<'a, A: Authenticator> BlobStore<'a, A> {
+    pub fn new(
+        device: &'a mut A,
+        session: &'a mut Session,
+        profile: &'a Profile,
+        credential_id: &'a [u8],
+    ) -> Self {
+        Self {
+            device,
+            session,
+            profile,
+            credential_id,
+            observed: None,
+            vault: None,
+            shared_vault: None,
+            cipher: Cipher::default(),
+            passphrase_keys: Vec::new(),
+        }
     }
-}
 
-fn decrypt_and_display_entry(
-    device: &mut FidoKeyHid,
-    credential_id: &[u8],
-    entry: &str,
-    entry_number: usize,
-) -> Result<()> {
-    if let Some(colon_pos) = entry.find(':') {
-        let entry_id = &entry[..colon_pos];
-        let encrypted_base64 = &entry[colon_pos + 1..];
-
-        match general_purpose::STANDARD.decode(encrypted_base64) {
-            Ok(encrypted_bytes) => match decrypt_data(device, credential_id, &encrypted_bytes) {
-                Ok(decrypted_str) => {
-                    println!("Decrypted data: {}", decrypted_str);
-                }
-                Err(_) => {
-                    println!("Decryption error for '{}'", entry_id);
-                }
-            },
-            Err(_) => {
-                println!("Corrupted data in '{}'", entry_id);
-            }
+    /// Seals entries stored or upgraded from now on with `cipher`. Entries
+    /// already in the vault keep theirs until `change_cipher`.
+    pub fn with_cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// The largeBlobKey, fetched together with the vault's hmac-secret: the
+    /// vault as last read, or else the one recorded with the snapshot.
+    fn large_blob_key(&mut self) -> Result<Option<Zeroizing<[u8; 32]>>> {
+        if let Some(key) = self.session.large_blob_key(self.credential_id) {
+            return Ok(Some(key));
         }
-    } else {
-        // Handle old format without ID - try both base64 and hex for backward compatibility
-        if let Ok(encrypted_bytes) = general_purpose::STANDARD.decode(entry) {
-            match decrypt_data(device, credential_id, &encrypted_bytes) {
-                Ok(decrypted_str) => {
-                    println!("Entry {}: \"{}\"", entry_number, decrypted_str);
-                }
-                Err(_) => {
-                    println!("Decryption error for entry {}", entry_number);
-                }
-            }
-        } else if let Ok(encrypted_bytes) = hex::decode(entry) {
-            // Fallback to hex for backward compatibility
-            match decrypt_data(device, credential_id, &encrypted_bytes) {
-                Ok(decrypted_str) => {
-                    println!("Entry {}: \"{}\"", entry_number, decrypted_str);
-                }
-                Err(_) => {
-                    println!("Decryption error for entry {}", entry_number);
-                }
+
+        let hint = self
+            .vault
+            .clone()
+            .or_else(|| recorded_vault(self.profile, self.credential_id));
+        vault::large_blob_key(
+            self.device,
+            self.session,
+            self.profile,
+            self.credential_id,
+            hint.as_ref(),
+        )
+    }
+
+    fn fetch(&mut self) -> Result<Vec<u8>> {
+        let data = self.device.get_large_blob()?.large_blob_array;
+        self.observed = Some(data.clone());
+        Ok(data)
+    }
+
+    fn read(&mut self) -> Result<ParsedBlob> {
+        let key = self.large_blob_key()?;
+        let data = self.fetch()?;
+        let parsed = decode_entries(&data, key.as_deref())?;
+        self.vault = parsed.vault.clone();
+        self.shared_vault = parsed.shared_vault.clone();
+        Ok(parsed)
+    }
+
+    fn entries(&mut self) -> Result<Vec<EntryRecord>> {
+        Ok(self.read()?.entries)
+    }
+
+    /// The whole largeBlob array with `entries` as this credential's contents.
+    fn serialize(&mut self, entries: &[EntryRecord]) -> Result<Vec<u8>> {
+        let key = self.large_blob_key()?;
+        let current = match &self.observed {
+            Some(data) => LargeBlobArray::parse(data),
+            None => LargeBlobArray::parse(&self.fetch()?),
+        };
+
+        let Some(key) = key else {
+            // Without a largeBlobKey only the raw layout is possible, which
+            // would overwrite whatever other credentials keep in the array.
+            if current.is_some_and(|array| !array.is_empty()) {
+                return Err(Error::LargeBlobKeyUnavailable);
             }
+            return if entries.is_empty() {
+                Ok(EMPTY_LARGE_BLOB_ARRAY.to_vec())
+            } else {
+                blob_format::encode(entries, self.vault.as_ref())
+            };
+        };
+
+        // Legacy entries move into this credential's element with the vault
+        // they were stored with, so every element is a CTAP 2.1 map.
+        let mut array = current.unwrap_or_default();
+        array.remove_legacy_data();
+
+        if entries.is_empty() {
+            array.remove(&key);
         } else {
-            println!("Corrupted data in entry {}", entry_number);
+            let shared_vault = entries
+                .iter()
+                .any(|entry| entry.shared)
+                .then_some(self.shared_vault.as_ref())
+                .flatten();
+            array.set(
+                &key,
+                &Zeroizing::new(blob_format::encode_with_shared_vault(
+                    entries,
+                    self.vault.as_ref(),
+                    shared_vault,
+                )?),
+            )?;
         }
+
+        array.serialize()
     }
-    Ok(())
-}
 
-pub fn write_blob(device: &mut FidoKeyHid, credential_id: &[u8], data: &str) -> Result<()> {
-    let entry_id = get_entry_id().context("Failed to get entry ID")?;
+    fn write_entries(&mut self, entries: &[EntryRecord]) -> Result<()> {
+        let data = self.serialize(entries)?;
+        // Keyed by the vault, so a store that already unlocked it takes no
+        // extra assertion. Without a vault yet, the one created here is
+        // written with the next write of the entries.
+        let master_key = self.master_key()?;
+        let snapshot_key = self.vault.clone().map(|vault| (vault, master_key));
+        self.write_array(data, snapshot_key)
+    }
 
-    let encrypted_data =
-        encrypt_data(device, credential_id, data).context("Failed to encrypt data")?;
+    /// Replaces the whole largeBlob array with `data`, first saving the
+    /// current one under the vault in `snapshot_key` if there is one.
+    fn write_array(
+        &mut self,
+        data: Vec<u8>,
+        snapshot_key: Option<(WrappedVaultKey, Zeroizing<[u8; 32]>)>,
+    ) -> Result<()> {
+        let capacity = self.device.large_blob_capacity()?;
+        if data.len() > capacity {
+            return Err(Error::BlobFull {
+                needed: data.len(),
+                capacity,
+            });
+        }
 
-    let entry_with_id = format!(
-        "{}:{}",
-        entry_id,
-        general_purpose::STANDARD.encode(&encrypted_data)
-    );
+        let current = self.device.get_large_blob()?.large_blob_array;
+        if self
+            .observed
+            .as_ref()
+            .is_some_and(|observed| *observed != current)
+        {
+            return Err(Error::BlobConflict);
+        }
 
-    // Get existing blob content
-    let existing_entries = match get_blob_content(device)? {
-        Some(content) => parse_blob_entries(&content),
-        None => Vec::new(),
-    };
+        if let Some((vault, master_key)) = &snapshot_key {
+            save_snapshot(
+                self.profile,
+                self.credential_id,
+                vault,
+                master_key,
+                &current,
+            )?;
+        }
 
-    // Handle space management if needed
-    let final_entries = handle_space_management(&existing_entries, &entry_with_id)?;
+        let needed = data.len();
+        self.session.with_token(&*self.device, |device, token| {
+            device
+                .write_large_blob(token, data.clone())
+                .map_err(|e| match ctap_status(&e) {
+                    // The authenticator may have less room than it reported.
+                    Some(CtapStatus::LARGE_BLOB_STORAGE_FULL) => {
+                        Error::BlobFull { needed, capacity }.into()
+                    }
+                    _ => e,
+                })
+        })?;
+
+        let written = self.fetch()?;
+        if written != data {
+            return Err(Error::WriteVerificationFailed);
+        }
 
-    let final_data = build_final_data(final_entries, entry_with_id);
-    write_to_device(device, final_data)?;
+        Ok(())
+    }
 
-    println!("✓ Data stored successfully!");
-    Ok(())
-}
+    /// Writes back this credential's element as it was before the last
+    /// write; the elements of other credentials and tools stay as they are
+    /// now. The array being replaced becomes the new snapshot, so a restore
+    /// can itself be undone.
+    pub fn restore_snapshot(&mut self) -> Result<()> {
+        // Fetched first, so the assertion for the largeBlobKey also unwraps
+        // the vault the snapshot header records.
+        let key = self.large_blob_key()?;
+        let snapshot = load_snapshot(self.device, self.session, self.profile, self.credential_id)?;
+        let restored = decode_entries(&snapshot.blob, key.as_deref())?;
+        self.read()?;
+
+        // The vault that opened the snapshot keys the new one, so no vault
+        // has to be created or unwrapped for it.
+        let snapshot_key = match snapshot
+            .vault
+            .or_else(|| restored.vault.clone())
+            .or_else(|| self.vault.clone())
+        {
+            Some(vault) => {
+                let master_key = vault::unlock(
+                    self.device,
+                    self.session,
+                    self.profile,
+                    self.credential_id,
+                    &vault,
+                )?;
+                Some((vault, master_key))
+            }
+            None => None,
+        };
 
-pub fn select_and_read_entry(
-    device: &mut FidoKeyHid,
-    credential_id: &[u8],
-) -> Result<(usize, Vec<u8>)> {
-    let blob_content = match get_blob_content(device)? {
-        Some(content) => content,
-        None => {
-            return Err(anyhow!("LargeBlob is empty"));
-        }
-    };
+        self.vault = restored.vault;
+        self.shared_vault = restored.shared_vault;
+        let data = self.serialize(&restored.entries)?;
+        self.write_array(data, snapshot_key)
+    }
 
-    let entries = parse_blob_entries(&blob_content);
+    /// Where this credential's snapshot is kept.
+    pub fn snapshot_path(&self) -> PathBuf {
+        snapshot_path(self.profile, self.credential_id)
+    }
 
-    if entries.is_empty() {
-        return Err(anyhow!("No entries found"));
+    fn position(entries: &[EntryRecord], id: &str) -> Result<usize> {
+        entries
+            .iter()
+            .position(|entry| entry.id == id)
+            .ok_or(Error::EntryNotFound)
     }
 
-    println!("\n📋 Entries:");
-    for (i, entry) in entries.iter().enumerate() {
-        if let Some(colon_pos) = entry.find(':') {
-            let entry_id = &entry[..colon_pos];
-            println!("   {}. {}", i + 1, entry_id);
-        } else {
-            println!("   {}. (entry without ID)", i + 1);
+    /// Decrypts `entry` and checks it against its recorded public key.
+    /// Nothing is written: entries without a recorded key, or shared legacy
+    /// ones, are taken over by `upgrade_encryption`.
+    fn open(&mut self, entry: &EntryRecord) -> Result<Secret> {
+        let secret = self.decrypt_secret(entry)?;
+        match (&entry.public_key, secret.public_key()) {
+            (Some(recorded), Some(actual)) if recorded.as_slice() == actual.to_bytes() => {}
+            (Some(_), _) => return Err(Error::PublicKeyMismatch),
+            (None, _) => {}
         }
+        Ok(secret)
     }
 
-    print!("\n🔑 Choose entry (1-{}): ", entries.len());
-    io::stdout().flush()?;
+    /// `old` as this credential's own entry, with its public key recorded. A
+    /// shared legacy entry in the vault is encrypted again under this
+    /// credential's vault.
+    fn claim(&mut self, old: &EntryRecord) -> Result<EntryRecord> {
+        let secret = self.open(old)?;
+        if old.shared && old.algorithm.is_vault() {
+            return self.rebuild(old, &old.id, &secret, old.algorithm);
+        }
+        Ok(EntryRecord {
+            public_key: secret
+                .public_key()
+                .map(|public_key| public_key.to_bytes().to_vec()),
+            shared: false,
+            ..old.clone()
+        })
+    }
 
-    let mut input = String::new();
-    io::stdin().read_line(&mut input)?;
-    let choice: usize = input.trim().parse().context("Invalid input")?;
+    /// Rewrites a largeBlob still in a legacy layout: the old text format, a
+    /// raw container, or the byte-string element earlier versions kept in
+    /// the array. Legacy entries all move into this credential's element,
+    /// including any of other profiles, which then stay listed here as
+    /// shared entries that don't decrypt. Returns whether anything was
+    /// rewritten.
+    pub fn migrate(&mut self) -> Result<bool> {
+        let blob = self.read()?;
+        if !blob.legacy {
+            return Ok(false);
+        }
 
-    if choice == 0 || choice > entries.len() {
-        return Err(anyhow!("Invalid choice"));
+        self.write_entries(&blob.entries)?;
+        Ok(true)
     }
 
-    let selected_entry_index = choice - 1;
-    let selected_entry = &entries[selected_entry_index];
+    pub fn list(&mut self) -> Result<Vec<EntryInfo>> {
+        Ok(self
+            .entries()?
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| EntryInfo::new(index, entry))
+            .collect())
+    }
 
-    let decrypted = decrypt_entry_raw(device, credential_id, selected_entry)?;
+    pub fn get(&mut self, id: &str) -> Result<Secret> {
+        let entries = self.entries()?;
+        let position = Self::position(&entries, id)?;
+        self.open(&entries[position])
+    }
 
-    Ok((selected_entry_index, decrypted))
-}
+    pub fn get_at(&mut self, index: usize) -> Result<Secret> {
+        let entries = self.entries()?;
+        self.open(entries.get(index).ok_or(Error::EntryNotFound)?)
+    }
 
-pub fn read_blob_entry_by_index(
-    device: &mut FidoKeyHid,
-    credential_id: &[u8],
-    index: usize,
-) -> Result<Vec<u8>> {
-    let blob_content = match get_blob_content(device)? {
-        Some(content) => content,
-        None => {
-            return Err(anyhow!("LargeBlob is empty"));
+    /// The vault `entry` was encrypted under, if it was.
+    fn vault_for(&self, entry: &EntryRecord) -> Option<WrappedVaultKey> {
+        if entry.shared {
+            self.shared_vault.clone()
+        } else {
+            self.vault.clone()
         }
-    };
+    }
 
-    let entries = parse_blob_entries(&blob_content);
+    /// The master key of this credential's vault, creating the vault if
+    /// there is none yet. It is written with the next write of the entries.
+    fn master_key(&mut self) -> Result<Zeroizing<[u8; 32]>> {
+        if let Some(vault) = &self.vault {
+            return vault::unlock(
+                self.device,
+                self.session,
+                self.profile,
+                self.credential_id,
+                vault,
+            );
+        }
 
-    if index >= entries.len() {
-        return Err(anyhow!("Invalid entry index"));
+        let (vault, master_key) =
+            vault::create(self.device, self.session, self.profile, self.credential_id)?;
+        self.vault = Some(vault);
+        Ok(master_key)
     }
 
-    let entry = &entries[index];
-    decrypt_entry_raw(device, credential_id, entry)
-}
+    /// The stretched passphrase of the entry `id` protected by `kdf`, asked
+    /// for through the session's PIN source the first time it is needed.
+    fn passphrase_key(&mut self, id: &str, kdf: &PassphraseKdf) -> Result<Zeroizing<[u8; 32]>> {
+        if !kdf.is_within_limits() {
+            return Err(Error::EntryCorrupted);
+        }
+        if let Some((_, key)) = self
+            .passphrase_keys
+            .iter()
+            .find(|(salt, _)| *salt == kdf.salt)
+        {
+            return Ok(key.clone());
+        }
 
-fn decrypt_entry_raw(
-    device: &mut FidoKeyHid,
-    credential_id: &[u8],
-    entry: &str,
-) -> Result<Vec<u8>> {
-    if let Some(colon_pos) = entry.find(':') {
-        let encrypted_base64 = &entry[colon_pos + 1..];
-        let encrypted_bytes = general_purpose::STANDARD
-            .decode(encrypted_base64)
-            .context("Failed to decode base64")?;
-        let decrypted_str = decrypt_data(device, credential_id, &encrypted_bytes)?;
-        Ok(decrypted_str.into_bytes())
-    } else {
-        // Old format without ID - try base64
-        if let Ok(encrypted_bytes) = general_purpose::STANDARD.decode(entry) {
-            let decrypted_str = decrypt_data(device, credential_id, &encrypted_bytes)?;
-            Ok(decrypted_str.into_bytes())
-        } else if let Ok(encrypted_bytes) = hex::decode(entry) {
-            // Fallback to hex
-            let decrypted_str = decrypt_data(device, credential_id, &encrypted_bytes)?;
-            Ok(decrypted_str.into_bytes())
-        } else {
-            Err(anyhow!("Invalid entry format"))
+        let passphrase = self
+            .session
+            .passphrase(&format!("Passphrase for '{}'", id))?;
+        let key = kdf.stretch(&passphrase)?;
+        self.passphrase_keys.push((kdf.salt.clone(), key.clone()));
+        Ok(key)
+    }
+
+    /// Argon2id parameters for a new passphrase, whose stretched form is kept
+    /// for the entry about to be encrypted.
+    fn protect(&mut self, passphrase: &str) -> Result<PassphraseKdf> {
+        if passphrase.is_empty() {
+            return Err(Error::InvalidInput(
+                "passphrase cannot be empty".to_string(),
+            ));
         }
+
+        let kdf = PassphraseKdf::new();
+        let key = kdf.stretch(passphrase)?;
+        self.passphrase_keys.push((kdf.salt.clone(), key));
+        Ok(kdf)
     }
-}
 
-#[allow(dead_code)]
-pub fn read_blob_entry(
-    device: &mut FidoKeyHid,
-    credential_id: &[u8],
-    entry_id: &str,
-) -> Result<Vec<u8>> {
-    let blob_content = match get_blob_content(device)? {
-        Some(content) => content,
-        None => {
-            return Err(anyhow!("The largeBlob is empty"));
+    fn decrypt_secret(&mut self, entry: &EntryRecord) -> Result<Secret> {
+        let vault = self.vault_for(entry);
+        let passphrase_key = match &entry.passphrase {
+            Some(kdf) if entry.algorithm.is_vault() => Some(self.passphrase_key(&entry.id, kdf)?),
+            _ => None,
+        };
+        let result = decrypt_entry(
+            self.device,
+            self.session,
+            self.profile,
+            self.credential_id,
+            vault.as_ref(),
+            passphrase_key.as_deref(),
+            entry,
+        );
+        if let (Err(Error::DecryptFailed), Some(kdf)) = (&result, &entry.passphrase) {
+            // Most likely a wrong passphrase; ask again next time.
+            self.passphrase_keys.retain(|(salt, _)| *salt != kdf.salt);
         }
-    };
+        let plaintext = result?;
+        Secret::from_plaintext(entry.entry_type, plaintext).map_err(|_| Error::EntryCorrupted)
+    }
+
+    fn encrypt_entry(
+        &mut self,
+        id: &str,
+        secret: &Secret,
+        algorithm: Algorithm,
+        passphrase: Option<PassphraseKdf>,
+    ) -> Result<EntryRecord> {
+        let aad = blob_format::associated_data(
+            id,
+            secret.entry_type(),
+            FORMAT_VERSION,
+            self.credential_id,
+        );
+        let passphrase_key = match &passphrase {
+            Some(kdf) => Some(self.passphrase_key(id, kdf)?),
+            None => None,
+        };
+        let master_key = self.master_key()?;
+        let ciphertext = encrypt_with_key(
+            &key_material(&master_key, passphrase_key.as_deref()),
+            algorithm.cipher(),
+            KeyPurpose::Entry,
+            secret.as_bytes(),
+            &aad,
+        )?;
+        let mut entry = EntryRecord::new(id, secret.entry_type(), algorithm, ciphertext);
+        entry.passphrase = passphrase;
+        entry.public_key = secret
+            .public_key()
+            .map(|public_key| public_key.to_bytes().to_vec());
+        Ok(entry)
+    }
+
+    /// The algorithm `old` is encrypted again with: its own if it is in the
+    /// vault, otherwise the vault algorithm for this store's cipher.
+    fn kept_algorithm(&self, old: &EntryRecord) -> Algorithm {
+        if old.algorithm.is_vault() {
+            old.algorithm
+        } else {
+            Algorithm::vault(self.cipher)
+        }
+    }
 
-    let entries = parse_blob_entries(&blob_content);
+    /// `old` decrypted and encrypted again as `id`, under a fresh salt and
+    /// `kept_algorithm`, keeping its creation time, petname, default flag and
+    /// passphrase.
+    fn reencrypt(&mut self, old: &EntryRecord, id: &str) -> Result<EntryRecord> {
+        let secret = self.decrypt_secret(old)?;
+        let algorithm = self.kept_algorithm(old);
+        self.rebuild(old, id, &secret, algorithm)
+    }
 
-    if entries.is_empty() {
-        return Err(anyhow!("No entries found"));
+    /// `secret`, the plaintext of `old`, encrypted as `id` like `reencrypt` does.
+    fn rebuild(
+        &mut self,
+        old: &EntryRecord,
+        id: &str,
+        secret: &Secret,
+        algorithm: Algorithm,
+    ) -> Result<EntryRecord> {
+        let mut entry = self.encrypt_entry(id, secret, algorithm, old.passphrase.clone())?;
+        entry.created_at = old.created_at.or(entry.created_at);
+        entry.petname = old.petname.clone();
+        entry.default = old.default;
+        Ok(entry)
     }
 
-    for entry in entries {
-        if let Some(colon_pos) = entry.find(':') {
-            let current_id = &entry[..colon_pos];
-            if current_id == entry_id {
-                let encrypted_base64 = &entry[colon_pos + 1..];
-                let encrypted_bytes = general_purpose::STANDARD
-                    .decode(encrypted_base64)
-                    .context("Failed to decode base64")?;
-                let decrypted_str = decrypt_data(device, credential_id, &encrypted_bytes)?;
-                return Ok(decrypted_str.into_bytes());
+    /// Encrypts `secret` under `id`. Replacing an existing entry requires `overwrite`.
+    pub fn put(&mut self, id: &str, secret: &Secret, overwrite: bool) -> Result<()> {
+        self.put_with(
+            id,
+            secret,
+            &PutOptions {
+                overwrite,
+                ..PutOptions::default()
+            },
+        )
+    }
+
+    /// Like `put`, with `passphrase` needed besides the authenticator every
+    /// time the entry is decrypted. An overwritten entry takes the new one.
+    pub fn put_with_passphrase(
+        &mut self,
+        id: &str,
+        secret: &Secret,
+        passphrase: &str,
+        overwrite: bool,
+    ) -> Result<()> {
+        self.put_with(
+            id,
+            secret,
+            &PutOptions {
+                passphrase: Some(passphrase),
+                overwrite,
+                ..PutOptions::default()
+            },
+        )
+    }
+
+    /// Like `put`, with everything about the new entry written at once.
+    pub fn put_with(&mut self, id: &str, secret: &Secret, options: &PutOptions<'_>) -> Result<()> {
+        validate_entry_id(id)?;
+
+        let entries = self.entries()?;
+        let position = entries.iter().position(|entry| entry.id == id);
+        if position.is_some() && !options.overwrite {
+            return Err(Error::EntryExists);
+        }
+
+        let passphrase = options.passphrase.map(|p| self.protect(p)).transpose()?;
+        let petname = options.petname.and_then(clean_petname);
+        match position {
+            Some(position) if passphrase.is_some() => {
+                self.replace_at(entries, position, secret, passphrase, petname)
+            }
+            Some(position) => {
+                let passphrase = entries[position].passphrase.clone();
+                if passphrase.is_some() {
+                    self.decrypt_secret(&entries[position])?;
+                }
+                self.replace_at(entries, position, secret, passphrase, petname)
+            }
+            None => {
+                let mut entry =
+                    self.encrypt_entry(id, secret, Algorithm::vault(self.cipher), passphrase)?;
+                entry.petname = petname;
+                let mut entries = entries;
+                entries.push(entry);
+                self.write_entries(&entries)
             }
         }
     }
 
-    Err(anyhow!("Entry '{}' not found", entry_id))
-}
+    /// Replaces the secret of an existing entry, encrypted under a fresh salt.
+    /// Its position, petname, default flag, vault algorithm and passphrase are
+    /// kept; an entry with a passphrase is decrypted first, so a mistyped
+    /// passphrase can't lock the new secret away.
+    pub fn replace(&mut self, id: &str, secret: &Secret) -> Result<()> {
+        let entries = self.entries()?;
+        let position = Self::position(&entries, id)?;
+
+        let passphrase = entries[position].passphrase.clone();
+        if passphrase.is_some() {
+            self.decrypt_secret(&entries[position])?;
+        }
+        self.replace_at(entries, position, secret, passphrase, None)
+    }
 
-pub fn read_blob(device: &mut FidoKeyHid, credential_id: &[u8]) -> Result<()> {
-    let blob_content = match get_blob_content(device)? {
-        Some(content) => content,
-        None => {
-            println!("LargeBlob is empty.");
+    /// Replaces the entry at `position`, keeping its petname unless `petname` is given.
+    fn replace_at(
+        &mut self,
+        mut entries: Vec<EntryRecord>,
+        position: usize,
+        secret: &Secret,
+        passphrase: Option<PassphraseKdf>,
+        petname: Option<String>,
+    ) -> Result<()> {
+        let id = entries[position].id.clone();
+        let algorithm = self.kept_algorithm(&entries[position]);
+        let mut entry = self.encrypt_entry(&id, secret, algorithm, passphrase)?;
+        entry.petname = petname.or_else(|| entries[position].petname.take());
+        entry.default = entries[position].default;
+        entries[position] = entry;
+
+        self.write_entries(&entries)
+    }
+
+    /// Sets, changes or (with `None`) removes the passphrase the entry needs
+    /// besides the authenticator. The entry is decrypted, asking for its
+    /// current passphrase if it has one, and encrypted again.
+    pub fn set_passphrase(&mut self, id: &str, passphrase: Option<&str>) -> Result<()> {
+        let mut entries = self.entries()?;
+        let position = Self::position(&entries, id)?;
+
+        let old = entries[position].clone();
+        let secret = self.decrypt_secret(&old)?;
+        if old.public_key.is_some()
+            && secret.public_key().map(|key| key.to_bytes().to_vec()) != old.public_key
+        {
+            return Err(Error::PublicKeyMismatch);
+        }
+
+        let kdf = passphrase.map(|p| self.protect(p)).transpose()?;
+        let algorithm = self.kept_algorithm(&old);
+        let mut entry = self.encrypt_entry(id, &secret, algorithm, kdf)?;
+        entry.created_at = old.created_at.or(entry.created_at);
+        entry.petname = old.petname;
+        entry.default = old.default;
+        entries[position] = entry;
+        self.write_entries(&entries)
+    }
+
+    /// Renames the entry. The ID is bound to the ciphertext, so the entry is
+    /// decrypted and encrypted again under its new ID.
+    pub fn rename(&mut self, id: &str, new_id: &str) -> Result<()> {
+        let new_id = new_id.trim();
+        validate_entry_id(new_id)?;
+
+        let mut entries = self.entries()?;
+        let position = Self::position(&entries, id)?;
+        if new_id == id {
             return Ok(());
         }
-    };
+        if entries.iter().any(|entry| entry.id == new_id) {
+            return Err(Error::EntryExists);
+        }
 
-    let entries = parse_blob_entries(&blob_content);
+        let entry = self.reencrypt(&entries[position], new_id)?;
+        if entries[position].public_key.is_some()
+            && entry.public_key != entries[position].public_key
+        {
+            return Err(Error::PublicKeyMismatch);
+        }
+        entries[position] = entry;
+        self.write_entries(&entries)
+    }
+
+    /// Decrypts the entry and encrypts it again with `cipher`, moving it into
+    /// the vault if it isn't yet.
+    pub fn change_cipher(&mut self, id: &str, cipher: Cipher) -> Result<()> {
+        let mut entries = self.entries()?;
+        let position = Self::position(&entries, id)?;
+
+        let old = entries[position].clone();
+        let secret = self.decrypt_secret(&old)?;
+        if old.public_key.is_some()
+            && secret.public_key().map(|key| key.to_bytes().to_vec()) != old.public_key
+        {
+            return Err(Error::PublicKeyMismatch);
+        }
+        entries[position] = self.rebuild(&old, id, &secret, Algorithm::vault(cipher))?;
+        entries[position].shared = false;
+        self.write_entries(&entries)
+    }
+
+    /// Moves the entry to `index`, or to the end if `index` is past it.
+    pub fn move_entry(&mut self, id: &str, index: usize) -> Result<()> {
+        let mut entries = self.entries()?;
+        let position = Self::position(&entries, id)?;
 
-    if entries.is_empty() {
-        println!("No entries.");
-        return Ok(());
+        let entry = entries.remove(position);
+        entries.insert(index.min(entries.len()), entry);
+        self.write_entries(&entries)
     }
 
-    display_entries(&entries, "Existing entries");
+    /// Makes `id` the entry the bunker uses unless told otherwise, or clears
+    /// the default with `None`.
+    ///
+    /// The flag lives in this credential's own element, so a shared legacy
+    /// entry is decrypted first to claim it.
+    pub fn set_default(&mut self, id: Option<&str>) -> Result<()> {
+        let mut entries = self.entries()?;
+        if let Some(id) = id {
+            let position = Self::position(&entries, id)?;
+            if entries[position].shared {
+                // Only decrypts if the entry is this credential's.
+                entries[position] = self.claim(&entries[position].clone())?;
+            }
+        }
+
+        for entry in entries.iter_mut().filter(|entry| !entry.shared) {
+            entry.default = id == Some(entry.id.as_str());
+        }
+        self.write_entries(&entries)
+    }
 
-    let choice = get_user_choice("\nEnter the number of the entry to decrypt (or 0 to cancel): ")?;
+    /// Sets or clears the petname shown next to the entry's ID.
+    pub fn set_petname(&mut self, id: &str, petname: Option<&str>) -> Result<()> {
+        let mut entries = self.entries()?;
+        let position = Self::position(&entries, id)?;
+        entries[position].petname = petname.and_then(clean_petname);
+        self.write_entries(&entries)
+    }
 
-    if choice == 0 || choice > entries.len() {
-        return Ok(());
+    pub fn delete(&mut self, id: &str) -> Result<()> {
+        let entries = self.entries()?;
+        let position = Self::position(&entries, id)?;
+        self.delete_at(position)
     }
 
-    let selected_entry = &entries[choice - 1];
-    decrypt_and_display_entry(device, credential_id, selected_entry, choice)?;
+    pub fn delete_at(&mut self, index: usize) -> Result<()> {
+        let mut entries = self.entries()?;
+        if index >= entries.len() {
+            return Err(Error::EntryNotFound);
+        }
 
-    Ok(())
-}
+        entries.remove(index);
+        self.write_entries(&entries)
+    }
 
-pub fn delete_single_entry(device: &mut FidoKeyHid) -> Result<()> {
-    let blob_content = match get_blob_content(device)? {
-        Some(content) => content,
-        None => {
-            println!("LargeBlob is empty.");
-            return Ok(());
+    /// Decrypts every entry, without writing anything, and reports what is
+    /// wrong with each. Unlocking the vault costs one touch, and so does
+    /// every entry not yet encrypted under it.
+    pub fn check(&mut self) -> Result<Vec<EntryCheck>> {
+        let entries = self.entries()?;
+        let mut checks = Vec::with_capacity(entries.len());
+
+        for (index, entry) in entries.iter().enumerate() {
+            let status = if entries[..index].iter().any(|e| e.id == entry.id) {
+                EntryStatus::DuplicateId
+            } else {
+                self.check_entry(entry)?
+            };
+            checks.push(EntryCheck {
+                entry: EntryInfo::new(index, entry),
+                status,
+            });
         }
-    };
 
-    let entries = parse_blob_entries(&blob_content);
+        Ok(checks)
+    }
+
+    // Only errors unrelated to the entry itself, such as a missing touch, abort the check.
+    fn check_entry(&mut self, entry: &EntryRecord) -> Result<EntryStatus> {
+        let overhead = entry.algorithm.cipher().overhead();
+        if entry.ciphertext.len() < overhead {
+            return Ok(EntryStatus::Malformed(format!(
+                "{} bytes of ciphertext, at least {} expected",
+                entry.ciphertext.len(),
+                overhead
+            )));
+        }
+
+        let vault = self.vault_for(entry);
+        let plaintext = match decrypt_entry(
+            self.device,
+            self.session,
+            self.profile,
+            self.credential_id,
+            vault.as_ref(),
+            None,
+            entry,
+        ) {
+            Ok(plaintext) => plaintext,
+            Err(Error::PassphraseRequired) => return Ok(EntryStatus::PassphraseProtected),
+            Err(Error::DecryptFailed) => return Ok(EntryStatus::Undecryptable),
+            Err(Error::VaultKeyMissing) => {
+                return Ok(EntryStatus::Malformed(
+                    "encrypted under a vault key that is missing".to_string(),
+                ));
+            }
+            Err(Error::EntryCorrupted) => {
+                return Ok(EntryStatus::Malformed(
+                    "its vault key is corrupted".to_string(),
+                ));
+            }
+            Err(Error::UnsupportedKeyLabel(label)) => {
+                return Ok(EntryStatus::Malformed(format!(
+                    "unknown key derivation label '{}'",
+                    label
+                )));
+            }
+            Err(e) => return Err(e),
+        };
+
+        let Ok(secret) = Secret::from_plaintext(entry.entry_type, plaintext) else {
+            return Ok(EntryStatus::InvalidPayload(entry.entry_type));
+        };
 
-    if entries.is_empty() {
-        println!("No entries.");
-        return Ok(());
+        match (&entry.public_key, secret.public_key()) {
+            (Some(recorded), Some(actual)) if recorded.as_slice() == actual.to_bytes() => {
+                Ok(EntryStatus::Ok)
+            }
+            (Some(_), _) => Ok(EntryStatus::Malformed(
+                "does not match its recorded public key".to_string(),
+            )),
+            (None, _) => Ok(EntryStatus::Ok),
+        }
     }
 
-    display_entries(&entries, "Existing entries");
+    /// Rewrites the entry at `index` as a freshly stored one: the secret is
+    /// decrypted, converted to its type's current stored form and encrypted
+    /// under a new salt, its public key recorded again, and a duplicate ID
+    /// made unique. Returns its ID.
+    pub fn reencode_at(&mut self, index: usize) -> Result<String> {
+        let mut entries = self.entries()?;
+        let old = entries.get(index).ok_or(Error::EntryNotFound)?.clone();
+
+        let others: Vec<_> = entries
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != index)
+            .map(|(_, entry)| entry.clone())
+            .collect();
+        let id = blob_format::unique_id(&others, &old.id);
+
+        entries[index] = self.reencrypt(&old, &id)?;
+        self.write_entries(&entries)?;
+        Ok(id)
+    }
 
-    let choice = get_user_choice("Enter the number of the entry to delete (or 0 to cancel): ")?;
+    /// Re-encrypts every entry stored the way older versions did, directly
+    /// under an hmac-secret output, under the vault master key, takes over
+    /// the shared legacy entries this credential decrypts, records the public
+    /// key of keys stored before it was, and writes them back at once.
+    /// Entries that don't decrypt under this credential, such as another
+    /// profile's legacy entries, or that no longer match their public key
+    /// are left alone. Returns how many were upgraded.
+    pub fn upgrade_encryption(&mut self) -> Result<usize> {
+        let entries = self.entries()?;
+        let mut upgraded_entries = Vec::with_capacity(entries.len());
+        let mut upgraded = 0;
+
+        for old in entries {
+            if old.needs_upgrade() {
+                let result = if old.is_outdated() {
+                    self.reencrypt(&old, &old.id)
+                } else {
+                    self.claim(&old)
+                };
+                match result {
+                    Ok(entry) if old.public_key.is_none() || entry.public_key == old.public_key => {
+                        upgraded_entries.push(entry);
+                        upgraded += 1;
+                        continue;
+                    }
+                    Ok(_)
+                    | Err(
+                        Error::DecryptFailed
+                        | Error::EntryCorrupted
+                        | Error::UnsupportedKeyLabel(_)
+                        | Error::VaultKeyMissing
+                        | Error::PublicKeyMismatch,
+                    ) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            upgraded_entries.push(old);
+        }
 
-    if choice == 0 {
-        println!("Cancelled.");
-        return Ok(());
+        if upgraded > 0 {
+            self.write_entries(&upgraded_entries)?;
+        }
+        Ok(upgraded)
     }
 
-    if choice > 0 && choice <= entries.len() {
-        let mut updated_entries = entries;
-        updated_entries.remove(choice - 1);
+    /// Moves this credential's entries under a new vault master key wrapped
+    /// under a new hmac-secret salt, so a copy of the old blob is no help to
+    /// anyone who learns the outputs for its salts. The old key is unwrapped
+    /// and the new one wrapped in one assertion (one touch), every entry
+    /// decrypted and encrypted again on the host (entries not yet in the
+    /// vault cost a touch each too), and everything written at once. An entry
+    /// that doesn't decrypt aborts the rotation before anything is written;
+    /// shared legacy entries are left as they are. Returns how many entries were rotated.
+    pub fn rotate(&mut self) -> Result<usize> {
+        let mut entries = self.entries()?;
+        let old_vault = self.vault.clone();
+        let new_vault = match &old_vault {
+            Some(vault) => Some(
+                vault::rotate(
+                    self.device,
+                    self.session,
+                    self.profile,
+                    self.credential_id,
+                    vault,
+                )?
+                .0,
+            ),
+            None => None,
+        };
 
-        let final_data = if updated_entries.is_empty() {
-            general_purpose::STANDARD.encode("EMPTY").into_bytes()
-        } else {
-            updated_entries.join("|").into_bytes()
+        let mut secrets = Vec::new();
+        for (position, entry) in entries.iter().enumerate() {
+            if entry.shared {
+                continue;
+            }
+            let secret = self.decrypt_secret(entry)?;
+            if entry.public_key.is_some()
+                && secret.public_key().map(|key| key.to_bytes().to_vec()) != entry.public_key
+            {
+                return Err(Error::PublicKeyMismatch);
+            }
+            secrets.push((position, secret));
+        }
+        if secrets.is_empty() {
+            return Ok(0);
+        }
+
+        // Without a vault yet, the first entry encrypted creates one.
+        self.vault = new_vault;
+        for (position, secret) in &secrets {
+            let old = entries[*position].clone();
+            let algorithm = self.kept_algorithm(&old);
+            entries[*position] = self.rebuild(&old, &old.id, secret, algorithm)?;
+        }
+        self.write_entries(&entries)?;
+
+        if let Some(vault) = old_vault {
+            self.session.forget_vault_key(&vault.wrapped);
+        }
+        Ok(secrets.len())
+    }
+
+    /// Every entry this credential can decrypt, with its metadata, for a
+    /// backup file. Unlocking the vault costs one touch, and so does every
+    /// entry not yet in it; passphrases are asked for as usual. An entry of
+    /// this credential that doesn't decrypt aborts the export, while shared
+    /// legacy entries of other profiles are left out.
+    pub fn export_backup(&mut self) -> Result<Backup> {
+        let entries = self.entries()?;
+        let mut exported = Vec::with_capacity(entries.len());
+
+        for entry in &entries {
+            let secret = match self.decrypt_secret(entry) {
+                Ok(secret) => secret,
+                Err(Error::DecryptFailed) if entry.shared => continue,
+                Err(e) => return Err(e),
+            };
+            let passphrase = match &entry.passphrase {
+                Some(kdf) => Some(EntryPassphrase {
+                    kdf: kdf.clone(),
+                    key: self.passphrase_key(&entry.id, kdf)?.to_vec(),
+                }),
+                None => None,
+            };
+            let exported_entry = BackupEntry {
+                id: entry.id.clone(),
+                entry_type: entry.entry_type,
+                algorithm: entry.algorithm,
+                created_at: entry.created_at,
+                public_key: entry.public_key.clone(),
+                petname: entry.petname.clone(),
+                default: entry.default,
+                passphrase,
+                secret: secret.as_bytes().to_vec(),
+            };
+            exported_entry.secret()?;
+            exported.push(exported_entry);
+        }
+
+        Ok(Backup::new(exported))
+    }
+
+    /// Encrypts the entries of `backup` under this credential and writes them
+    /// all at once, keeping their metadata and passphrases. Entries whose ID
+    /// is taken replace the existing ones with `overwrite`, and are skipped
+    /// otherwise. Every entry is checked before anything is encrypted.
+    /// Returns how many were restored.
+    pub fn import_backup(&mut self, backup: &Backup, overwrite: bool) -> Result<usize> {
+        let secrets = backup
+            .entries
+            .iter()
+            .map(|item| {
+                validate_entry_id(&item.id)?;
+                item.secret()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut entries = self.entries()?;
+        let mut restored = 0;
+
+        for (item, secret) in backup.entries.iter().zip(&secrets) {
+            let position = entries.iter().position(|entry| entry.id == item.id);
+            if position.is_some() && !overwrite {
+                continue;
+            }
+
+            let passphrase = match &item.passphrase {
+                Some(protection) => {
+                    if !protection.kdf.is_within_limits() {
+                        return Err(Error::BackupCorrupted);
+                    }
+                    let key: [u8; 32] = protection
+                        .key
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| Error::BackupCorrupted)?;
+                    self.passphrase_keys
+                        .push((protection.kdf.salt.clone(), Zeroizing::new(key)));
+                    Some(protection.kdf.clone())
+                }
+                None => None,
+            };
+            let algorithm = if item.algorithm.is_vault() {
+                item.algorithm
+            } else {
+                Algorithm::vault(self.cipher)
+            };
+
+            let mut entry = self.encrypt_entry(&item.id, secret, algorithm, passphrase)?;
+            entry.created_at = item.created_at.or(entry.created_at);
+            entry.petname = item.petname.clone();
+            if item.default {
+                for other in entries.iter_mut() {
+                    other.default = false;
+                }
+                entry.default = true;
+            }
+            match position {
+                Some(position) => entries[position] = entry,
+                None => entries.push(entry),
+            }
+            restored += 1;
+        }
+
+        if restored > 0 {
+            self.write_entries(&entries)?;
+        }
+        Ok(restored)
+    }
+
+    /// The entry at `index` as stored, in a container of its own: metadata
+    /// and ciphertext, nothing decrypted. Readable with `blob_format::decode`.
+    pub fn export_raw_at(&mut self, index: usize) -> Result<Vec<u8>> {
+        let entries = self.entries()?;
+        let entry = entries.get(index).ok_or(Error::EntryNotFound)?;
+        let vault = self.vault_for(entry);
+        let entry = EntryRecord {
+            shared: false,
+            ..entry.clone()
         };
+        blob_format::encode(std::slice::from_ref(&entry), vault.as_ref())
+    }
 
-        write_to_device(device, final_data)?;
+    /// Drops this credential's element from the largeBlob array, so its space
+    /// isn't lost once the credential is deleted. Legacy entries migrated
+    /// into it go with it.
+    pub fn remove_credential_data(&mut self) -> Result<()> {
+        if self.large_blob_key()?.is_none() {
+            return Ok(());
+        }
 
-        if updated_entries.is_empty() {
-            println!("✓ LargeBlob cleared!");
-        } else {
-            println!("✓ Entry deleted!");
+        let entries = self.entries()?;
+        if entries.is_empty() {
+            return Ok(());
         }
-    } else {
-        println!("Invalid choice.");
+
+        // No snapshot: nothing could decrypt it once the credential is gone.
+        let data = self.serialize(&[])?;
+        self.write_array(data, None)
     }
 
-    Ok(())
+    pub fn capacity(&mut self) -> Result<BlobCapacity> {
+        let entries = self.entries()?;
+        Ok(BlobCapacity {
+            used: self.serialize(&entries)?.len(),
+            total: self.device.large_blob_capacity()?,
+        })
+    }
+}
+
+/// Counts the entries that decrypt under `credential_id`, plus those that
+/// need a passphrase, which are counted without asking for it.
+pub fn count_entries_for_credential(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+) -> Result<usize> {
+    let hint = recorded_vault(profile, credential_id);
+    let key = vault::large_blob_key(device, session, profile, credential_id, hint.as_ref())?;
+    let parsed = read_entries(device, key.as_deref())?;
+
+    Ok(parsed
+        .entries
+        .iter()
+        .filter(|entry| {
+            let vault = parsed.vault_for(entry);
+            // Entries with a passphrase aren't decrypted; being listed is enough.
+            matches!(
+                decrypt_entry(device, session, profile, credential_id, vault, None, entry),
+                Ok(_) | Err(Error::PassphraseRequired)
+            )
+        })
+        .count())
 }
@@ -1,87 +1,181 @@
-use crate::auth::get_pin_from_user;
-use anyhow::{Context, Result};
+use crate::authenticator::{AssertionRequest, AssertionResponse, Authenticator};
+use crate::error::{Error, Result};
+use crate::profile::Profile;
+use crate::session::Session;
 use ctap_hid_fido2::{
-    fidokey::FidoKeyHid,
-    fidokey::get_assertion::get_assertion_params::{
-        Extension as GetExtension, Extension as AssertionExtension,
-    },
-    fidokey::make_credential::make_credential_params::{
-        CredentialSupportedKeyType, Extension as MakeExtension, MakeCredentialArgs,
-    },
-    public_key_credential_user_entity::PublicKeyCredentialUserEntity,
+    fidokey::get_info::InfoOption, public_key_credential_user_entity::PublicKeyCredentialUserEntity,
 };
-use zeroize::Zeroize;
-
-const RP_ID: &str = "nostr.bunker.yubikey";
-const CHALLENGE: &[u8] = b"yubikey-nostr-bunker-challenge";
-
+use zeroize::Zeroizing;
+
+pub fn get_credential_id(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+) -> Result<Vec<u8>> {
+    // Only create a credential when the device really has none for our RP:
+    // every creation takes one of its limited discoverable slots.
+    if let Some(credential_id) = find_credential(device, session, profile)? {
+        return Ok(credential_id);
+    }
 
-pub fn get_credential_id(device: &mut FidoKeyHid) -> Result<Vec<u8>> {
-    let mut pin = get_pin_from_user()?;
+    let user = PublicKeyCredentialUserEntity {
+        id: profile.user_id.clone(),
+        name: profile.user_name.clone(),
+        display_name: profile.user_display_name.clone(),
+    };
 
-    if let Ok(assertion) = device.get_assertion(RP_ID, CHALLENGE, &[], Some(pin.as_str())) {
-        pin.zeroize();
+    session.with_token(device, |device, token| {
+        device.make_credential(&profile.rp_id, &user, token)
+    })
+}
 
-        return Ok(assertion.credential_id);
+// Credential management finds the credential without an assertion, which
+// would take a touch and use up the session token's other permissions.
+fn find_credential(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+) -> Result<Option<Vec<u8>>> {
+    if device.enable_info_option(&InfoOption::CredMgmt)?.is_none() {
+        let request = AssertionRequest {
+            rp_id: &profile.rp_id,
+            credential_ids: &[],
+            hmac_secret_salts: &[],
+            large_blob_key: false,
+        };
+        return match session.with_token(device, |device, token| {
+            device.get_assertion(&request, token)
+        }) {
+            Ok(assertion) => Ok(Some(assertion.credential_id)),
+            Err(Error::NoCredentials) => Ok(None),
+            Err(e) => Err(e),
+        };
     }
 
-    let user = PublicKeyCredentialUserEntity {
-        id: b"user-id-for-large-blob".to_vec(),
-        name: "test.user".to_string(),
-        display_name: "Test User".to_string(),
-    };
+    let credentials = session.with_token(device, |device, token| {
+        device.enumerate_credentials(token, &profile.rp_id)
+    })?;
+
+    // The profile's own user first; any other credential of the RP is what
+    // discovery by assertion picked before.
+    let credential = credentials
+        .iter()
+        .find(|credential| credential.user.id == profile.user_id)
+        .or(credentials.first());
+    Ok(credential.map(|credential| credential.credential_id.clone()))
+}
 
-    let hmac_extension = MakeExtension::HmacSecret(Some(true));
-    let extensions = vec![hmac_extension];
-
-    let args = MakeCredentialArgs {
-        rpid: RP_ID.to_string(),
-        challenge: CHALLENGE.to_vec(),
-        pin: Some(pin.as_str()),
-        key_types: vec![CredentialSupportedKeyType::Ecdsa256],
-        uv: None,
-        exclude_list: vec![],
-        user_entity: Some(user),
-        rk: Some(true),
-        extensions: Some(extensions),
+/// One assertion for the credential, asking for hmac-secret outputs for
+/// `salts` (at most two) and, with `large_blob_key`, its largeBlobKey.
+pub fn assert_credential(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+    salts: &[[u8; 32]],
+    large_blob_key: bool,
+) -> Result<AssertionResponse> {
+    let credential_ids = [credential_id.to_vec()];
+    let request = AssertionRequest {
+        rp_id: &profile.rp_id,
+        credential_ids: &credential_ids,
+        hmac_secret_salts: salts,
+        large_blob_key,
     };
 
-    let attestation = device
-        .make_credential_with_args(&args)
-        .context("Failed to create credential.")?;
+    let assertion = session.with_token(device, |device, token| {
+        device.get_assertion(&request, token)
+    })?;
 
-    pin.zeroize();
-
-    Ok(attestation.credential_descriptor.id)
+    if assertion.hmac_secret.len() != salts.len() {
+        return Err(Error::Ctap(
+            "the authenticator returned no hmac-secret output".to_string(),
+        ));
+    }
+    Ok(assertion)
 }
 
 pub fn get_hmac_secret(
-    device: &mut FidoKeyHid,
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
     credential_id: &[u8],
     salt: &[u8; 32],
 ) -> Result<[u8; 32]> {
-    let mut pin = get_pin_from_user()?;
-
-    let hmac_extension = GetExtension::HmacSecret(Some(*salt));
-    let extensions = vec![hmac_extension];
-
-    let assertion = device
-        .get_assertion_with_extensios(
-            RP_ID,
-            CHALLENGE,
-            &[credential_id.to_vec()],
-            Some(pin.as_str()),
-            Some(&extensions),
-        )
-        .context("Failed to get encryption key")?;
-
-    pin.zeroize();
-
-    for extension in &assertion.extensions {
-        if let AssertionExtension::HmacSecret(Some(hmac_secret)) = extension {
-            return Ok(*hmac_secret);
-        }
+    let assertion = assert_credential(
+        device,
+        session,
+        profile,
+        credential_id,
+        std::slice::from_ref(salt),
+        false,
+    )?;
+    Ok(*assertion.hmac_secret[0])
+}
+
+/// The hmac-secret outputs for two salts, from one assertion (one touch):
+/// hmac-secret encrypts both salts together and answers with both outputs.
+pub fn get_hmac_secret_pair(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+    salt1: &[u8; 32],
+    salt2: &[u8; 32],
+) -> Result<([u8; 32], [u8; 32])> {
+    let assertion = assert_credential(
+        device,
+        session,
+        profile,
+        credential_id,
+        &[*salt1, *salt2],
+        false,
+    )?;
+    Ok((*assertion.hmac_secret[0], *assertion.hmac_secret[1]))
+}
+
+/// The credential's largeBlobKey, or `None` for credentials created without
+/// one (by versions of this crate before the spec-compliant largeBlob array).
+pub fn get_large_blob_key(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+) -> Result<Option<Zeroizing<[u8; 32]>>> {
+    if let Some(key) = session.large_blob_key(credential_id) {
+        return Ok(Some(key));
+    }
+
+    let assertion = assert_credential(device, session, profile, credential_id, &[], true)?;
+
+    if let Some(key) = &assertion.large_blob_key {
+        session.cache_large_blob_key(credential_id, key.clone());
     }
+    Ok(assertion.large_blob_key)
+}
 
-    Err(anyhow::anyhow!("Encryption key not found"))
+/// The credential's largeBlobKey and the hmac-secret output for `salt`, from
+/// one assertion: one touch where `get_large_blob_key` followed by
+/// `get_hmac_secret` would take two. The largeBlobKey is cached like there;
+/// the assertion holds it and the one hmac-secret output.
+pub fn get_large_blob_key_and_hmac_secret(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+    salt: &[u8; 32],
+) -> Result<AssertionResponse> {
+    let assertion = assert_credential(
+        device,
+        session,
+        profile,
+        credential_id,
+        std::slice::from_ref(salt),
+        true,
+    )?;
+
+    if let Some(key) = &assertion.large_blob_key {
+        session.cache_large_blob_key(credential_id, key.clone());
+    }
+    Ok(assertion)
 }
@@ -0,0 +1,153 @@
+use crate::authenticator::Authenticator;
+use crate::blob_operations::{BlobStore, count_entries_for_credential};
+use crate::error::{Error, Result};
+use crate::profile::Profile;
+use crate::session::Session;
+use crate::snapshot::remove_snapshot;
+use ctap_hid_fido2::fidokey::get_info::InfoOption;
+use dialoguer::Confirm;
+use std::fmt;
+use std::io::{self, Write};
+
+/// A discoverable credential registered under a profile's RP.
+#[derive(Debug, Clone)]
+pub struct ManagedCredential {
+    /// 1-based position in the authenticator's enumeration. CTAP guarantees no
+    /// particular order, so it only identifies the credential within one listing.
+    pub order: usize,
+    pub credential_id: Vec<u8>,
+    pub user_name: String,
+    pub user_display_name: String,
+}
+
+impl fmt::Display for ManagedCredential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}. {} ({}) | id {}",
+            self.order,
+            self.user_name,
+            self.user_display_name,
+            hex::encode(&self.credential_id)
+        )
+    }
+}
+
+fn ensure_supported(device: &impl Authenticator) -> Result<()> {
+    match device.enable_info_option(&InfoOption::CredMgmt)? {
+        Some(_) => Ok(()),
+        None => Err(Error::CredentialManagementUnsupported),
+    }
+}
+
+pub fn list_credentials(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+) -> Result<Vec<ManagedCredential>> {
+    ensure_supported(device)?;
+
+    let credentials = session.with_token(device, |device, token| {
+        device.enumerate_credentials(token, &profile.rp_id)
+    })?;
+
+    Ok(credentials
+        .into_iter()
+        .enumerate()
+        .map(|(i, credential)| ManagedCredential {
+            order: i + 1,
+            credential_id: credential.credential_id,
+            user_name: credential.user.name,
+            user_display_name: credential.user.display_name,
+        })
+        .collect())
+}
+
+/// Deletes the credential along with its element in the largeBlob array.
+pub fn delete_credential<A: Authenticator>(
+    device: &mut A,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+) -> Result<()> {
+    ensure_supported(device)?;
+
+    BlobStore::new(device, session, profile, credential_id).remove_credential_data()?;
+
+    session.with_token(device, |device, token| {
+        device.delete_credential(token, credential_id)
+    })?;
+    remove_snapshot(profile, credential_id)
+}
+
+/// Lists the profile's credentials and lets the user delete one.
+///
+/// Returns `true` if the deleted credential was `active_credential_id`.
+pub fn manage_credentials(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    active_credential_id: &[u8],
+) -> Result<bool> {
+    let credentials = list_credentials(device, session, profile)?;
+
+    if credentials.is_empty() {
+        println!("No credentials registered for {}.", profile.rp_id);
+        return Ok(false);
+    }
+
+    println!("\n🪪 Credentials for {}:", profile.rp_id);
+    for credential in &credentials {
+        let marker = if credential.credential_id == active_credential_id {
+            " (in use)"
+        } else {
+            ""
+        };
+        println!("   {}{}", credential, marker);
+    }
+
+    print!("\nEnter the number of the credential to delete (or 0 to cancel): ");
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let choice: usize = input.trim().parse().unwrap_or(0);
+
+    if choice == 0 {
+        println!("Cancelled.");
+        return Ok(false);
+    }
+
+    let credential = credentials
+        .iter()
+        .find(|c| c.order == choice)
+        .ok_or_else(|| Error::InvalidInput(format!("no credential {}", choice)))?;
+
+    println!("🔍 Checking largeBlob entries encrypted under this credential...");
+    let entries =
+        count_entries_for_credential(device, session, profile, &credential.credential_id)?;
+
+    if entries > 0 {
+        println!(
+            "⚠️  WARNING: {} stored entr{} can only be decrypted with this credential.",
+            entries,
+            if entries == 1 { "y" } else { "ies" }
+        );
+        println!("   Deleting it makes those keys unrecoverable.");
+    }
+
+    let confirmed = Confirm::new()
+        .with_prompt(format!("Delete credential {}?", credential.order))
+        .default(false)
+        .interact()
+        .unwrap_or(false);
+
+    if !confirmed {
+        println!("Cancelled.");
+        return Ok(false);
+    }
+
+    delete_credential(device, session, profile, &credential.credential_id)?;
+    println!("✓ Credential deleted!");
+
+    Ok(credential.credential_id == active_credential_id)
+}
@@ -1,19 +1,302 @@
-use anyhow::{Result, anyhow};
-use ctap_hid_fido2::{
-    FidoKeyHidFactory, LibCfg, fidokey::FidoKeyHid, fidokey::get_info::InfoOption,
-    get_fidokey_devices,
-};
-
-pub fn find_fido_device() -> Result<FidoKeyHid> {
-    let devices = get_fidokey_devices();
+use crate::authenticator::{Authenticator, AuthenticatorInfo};
+use crate::error::{Error, Result};
+use crate::hid::HidAuthenticator;
+use ctap_hid_fido2::{HidParam, fidokey::get_info::InfoOption, get_fidokey_devices};
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+#[derive(Clone)]
+pub struct FidoDeviceInfo {
+    pub index: usize,
+    pub product_name: String,
+    pub path: String,
+    pub aaguid: Option<Vec<u8>>,
+    pub firmware_version: Option<u32>,
+    param: HidParam,
+}
+
+impl FidoDeviceInfo {
+    pub fn aaguid_hex(&self) -> String {
+        self.aaguid
+            .as_ref()
+            .map(hex::encode)
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    pub fn firmware_string(&self) -> String {
+        self.firmware_version
+            .map(format_firmware_version)
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+}
+
+fn format_firmware_version(version: u32) -> String {
+    // YubiKeys report the firmware as 0x00MMmmpp
+    if version > 0 && version <= 0xFF_FFFF {
+        format!(
+            "{}.{}.{}",
+            (version >> 16) & 0xFF,
+            (version >> 8) & 0xFF,
+            version & 0xFF
+        )
+    } else {
+        version.to_string()
+    }
+}
+
+impl fmt::Display for FidoDeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = if self.product_name.is_empty() {
+            "(unnamed device)"
+        } else {
+            &self.product_name
+        };
+        write!(
+            f,
+            "{}. {} | firmware {} | aaguid {} | path {}",
+            self.index,
+            name,
+            self.firmware_string(),
+            self.aaguid_hex(),
+            self.path
+        )
+    }
+}
+
+/// Selects one device among several connected ones.
+///
+/// Parsed from `--device` or `FIDO_DEVICE`: a 1-based index, `path:<hid path>`,
+/// `aaguid:<hex>`, or any other text matched against the product name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    Index(usize),
+    Path(String),
+    Aaguid(Vec<u8>),
+    Product(String),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(Error::InvalidInput(
+                "device selector cannot be empty".to_string(),
+            ));
+        }
+
+        if let Some(path) = s.strip_prefix("path:") {
+            return Ok(Self::Path(path.to_string()));
+        }
+
+        if let Some(aaguid) = s.strip_prefix("aaguid:") {
+            let bytes = hex::decode(aaguid.replace('-', ""))
+                .map_err(|_| Error::InvalidInput(format!("invalid AAGUID '{}'", aaguid)))?;
+            return Ok(Self::Aaguid(bytes));
+        }
+
+        if let Ok(index) = s.parse::<usize>() {
+            return Ok(Self::Index(index));
+        }
+
+        Ok(Self::Product(s.to_string()))
+    }
+}
+
+impl DeviceSelector {
+    fn matches(&self, device: &FidoDeviceInfo) -> bool {
+        match self {
+            Self::Index(index) => device.index == *index,
+            Self::Path(path) => device.path == *path,
+            Self::Aaguid(aaguid) => device.aaguid.as_deref() == Some(aaguid.as_slice()),
+            Self::Product(name) => device
+                .product_name
+                .to_lowercase()
+                .contains(&name.to_lowercase()),
+        }
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "{}", index),
+            Self::Path(path) => write!(f, "path:{}", path),
+            Self::Aaguid(aaguid) => write!(f, "aaguid:{}", hex::encode(aaguid)),
+            Self::Product(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+pub fn list_fido_devices() -> Vec<FidoDeviceInfo> {
+    get_fidokey_devices()
+        .into_iter()
+        .enumerate()
+        .map(|(i, hid)| {
+            let path = match &hid.param {
+                HidParam::Path(path) => path.clone(),
+                HidParam::VidPid { vid, pid } => format!("{:04x}:{:04x}", vid, pid),
+            };
+
+            let info = HidAuthenticator::open(&hid.param)
+                .and_then(|device| device.get_authenticator_info())
+                .ok();
+
+            FidoDeviceInfo {
+                index: i + 1,
+                product_name: hid.product_string,
+                path,
+                aaguid: info.as_ref().map(|info| info.aaguid.clone()),
+                firmware_version: info.as_ref().map(|info| info.firmware_version),
+                param: hid.param,
+            }
+        })
+        .collect()
+}
+
+pub fn open_fido_device(device: &FidoDeviceInfo) -> Result<HidAuthenticator> {
+    HidAuthenticator::open(&device.param).map_err(|_| Error::DeviceNotFound)
+}
+
+pub fn choose_fido_device(devices: &[FidoDeviceInfo]) -> Result<&FidoDeviceInfo> {
+    println!("\n🔌 FIDO2 devices:");
+    for device in devices {
+        println!("   {}", device);
+    }
+
+    print!("\n🔑 Choose device (1-{}): ", devices.len());
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let choice: usize = input
+        .trim()
+        .parse()
+        .map_err(|_| Error::InvalidInput(input.trim().to_string()))?;
+
+    devices
+        .iter()
+        .find(|d| d.index == choice)
+        .ok_or_else(|| Error::InvalidInput(format!("no device {}", choice)))
+}
+
+pub fn find_fido_device(selector: Option<&DeviceSelector>) -> Result<HidAuthenticator> {
+    let devices = list_fido_devices();
     if devices.is_empty() {
-        return Err(anyhow!("No FIDO2 HID device connected."));
+        return Err(Error::DeviceNotFound);
     }
-    let cfg = LibCfg::init();
-    FidoKeyHidFactory::create(&cfg)
+
+    let device = match selector {
+        Some(selector) => devices
+            .iter()
+            .find(|d| selector.matches(d))
+            .ok_or(Error::DeviceNotFound)?,
+        None if devices.len() == 1 => &devices[0],
+        None => choose_fido_device(&devices)?,
+    };
+
+    println!("🔌 Using {}", device);
+
+    open_fido_device(device)
+}
+
+/// Capabilities and state reported by an authenticator.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub versions: Vec<String>,
+    pub extensions: Vec<String>,
+    pub aaguid: Vec<u8>,
+    pub firmware_version: u32,
+    pub large_blobs: Option<bool>,
+    pub always_uv: Option<bool>,
+    pub cred_mgmt: Option<bool>,
+    pub client_pin: Option<bool>,
+    pub max_large_blob_array: usize,
+    pub large_blob_capacity: usize,
+    pub remaining_discoverable_credentials: u32,
+    pub pin_retries: Option<i32>,
+}
+
+impl DeviceInfo {
+    fn from_info(
+        info: AuthenticatorInfo,
+        large_blob_capacity: usize,
+        pin_retries: Option<i32>,
+    ) -> Self {
+        Self {
+            large_blobs: info.option(&InfoOption::LargeBlobs),
+            always_uv: info.option(&InfoOption::AlwaysUv),
+            cred_mgmt: info.option(&InfoOption::CredMgmt),
+            client_pin: info.option(&InfoOption::ClientPin),
+            max_large_blob_array: info.max_large_blob_array(),
+            large_blob_capacity,
+            remaining_discoverable_credentials: info.remaining_discoverable_credentials,
+            pin_retries,
+            versions: info.versions,
+            extensions: info.extensions,
+            aaguid: info.aaguid,
+            firmware_version: info.firmware_version,
+        }
+    }
+}
+
+fn format_option(value: Option<bool>) -> &'static str {
+    match value {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "not supported",
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "   Versions: {}", self.versions.join(", "))?;
+        writeln!(f, "   Extensions: {}", self.extensions.join(", "))?;
+        writeln!(f, "   AAGUID: {}", hex::encode(&self.aaguid))?;
+        writeln!(
+            f,
+            "   Firmware: {}",
+            format_firmware_version(self.firmware_version)
+        )?;
+        writeln!(f, "   largeBlobs: {}", format_option(self.large_blobs))?;
+        writeln!(f, "   alwaysUv: {}", format_option(self.always_uv))?;
+        writeln!(f, "   credMgmt: {}", format_option(self.cred_mgmt))?;
+        writeln!(f, "   PIN set: {}", format_option(self.client_pin))?;
+        writeln!(
+            f,
+            "   Max largeBlob size: {} bytes ({} usable)",
+            self.max_large_blob_array, self.large_blob_capacity
+        )?;
+        writeln!(
+            f,
+            "   Remaining discoverable credentials: {}",
+            self.remaining_discoverable_credentials
+        )?;
+        match self.pin_retries {
+            Some(retries) => write!(f, "   PIN retries: {}", retries),
+            None => write!(f, "   PIN retries: unavailable"),
+        }
+    }
+}
+
+pub fn get_device_info(device: &impl Authenticator) -> Result<DeviceInfo> {
+    let info = device.get_authenticator_info()?;
+    let large_blob_capacity = device.large_blob_capacity()?;
+    let pin_retries = match info.option(&InfoOption::ClientPin) {
+        Some(true) => device.get_pin_retries().ok(),
+        _ => None,
+    };
+
+    Ok(DeviceInfo::from_info(
+        info,
+        large_blob_capacity,
+        pin_retries,
+    ))
 }
 
-pub fn is_supported(device: &FidoKeyHid) -> Result<bool> {
+pub fn is_supported(device: &impl Authenticator) -> Result<bool> {
     Ok(device
         .enable_info_option(&InfoOption::LargeBlobs)?
         .is_some())
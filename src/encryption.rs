@@ -1,74 +1,293 @@
+use crate::authenticator::Authenticator;
 use crate::credential::get_hmac_secret;
-use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead};
-use anyhow::{Context, Result, anyhow};
-use ctap_hid_fido2::fidokey::FidoKeyHid;
+use crate::error::{Error, Result};
+use crate::kdf::{KeyPurpose, derive_key, derive_salted_key};
+use crate::profile::Profile;
+use crate::session::Session;
+use aes_gcm::{
+    Aes256Gcm, KeyInit,
+    aead::{Aead, AeadCore, Nonce, Payload},
+};
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::XChaCha20Poly1305;
 use rand::Rng;
-use zeroize::Zeroize;
+use std::fmt;
+use std::str::FromStr;
+use zeroize::{Zeroize, Zeroizing};
 
-pub fn encrypt_data(
-    device: &mut FidoKeyHid,
-    credential_id: &[u8],
-    plaintext: &str,
-) -> Result<Vec<u8>> {
+const SALT_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+
+/// The AEAD a payload is sealed with. Everything keyed directly by an
+/// hmac-secret output uses AES-256-GCM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cipher {
+    /// AES-256-GCM with a random 96-bit nonce.
+    #[default]
+    Aes256Gcm,
+    /// XChaCha20-Poly1305 with a random 192-bit nonce.
+    XChaCha20Poly1305,
+    /// AES-256-GCM-SIV, which stays secure if a nonce ever repeats.
+    Aes256GcmSiv,
+}
+
+impl Cipher {
+    pub const ALL: [Cipher; 3] = [
+        Cipher::Aes256Gcm,
+        Cipher::XChaCha20Poly1305,
+        Cipher::Aes256GcmSiv,
+    ];
+
+    /// The name used in configuration.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Cipher::Aes256Gcm => "aes-256-gcm",
+            Cipher::XChaCha20Poly1305 => "xchacha20-poly1305",
+            Cipher::Aes256GcmSiv => "aes-256-gcm-siv",
+        }
+    }
+
+    fn nonce_len(&self) -> usize {
+        match self {
+            Cipher::XChaCha20Poly1305 => 24,
+            Cipher::Aes256Gcm | Cipher::Aes256GcmSiv => 12,
+        }
+    }
+
+    /// Bytes an encryption adds to the plaintext: salt, nonce and tag.
+    pub fn overhead(&self) -> usize {
+        SALT_LEN + self.nonce_len() + TAG_LEN
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Cipher::Aes256Gcm => "AES-256-GCM",
+            Cipher::XChaCha20Poly1305 => "XChaCha20-Poly1305",
+            Cipher::Aes256GcmSiv => "AES-256-GCM-SIV",
+        })
+    }
+}
+
+impl FromStr for Cipher {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        Self::ALL
+            .into_iter()
+            .find(|cipher| cipher.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                Error::InvalidInput(format!(
+                    "unknown cipher '{}' (expected one of: {})",
+                    s,
+                    Self::ALL.map(|cipher| cipher.name()).join(", ")
+                ))
+            })
+    }
+}
+
+/// The AES key for `purpose`, derived from the hmac-secret output. `None`
+/// uses the output as is, as everything written before key derivation did.
+fn content_key(hmac_secret: &[u8; 32], purpose: Option<KeyPurpose>) -> Zeroizing<[u8; 32]> {
+    match purpose {
+        Some(purpose) => derive_key(hmac_secret, purpose),
+        None => Zeroizing::new(*hmac_secret),
+    }
+}
+
+fn random_salt() -> [u8; 32] {
     let mut salt = [0u8; 32];
     rand::rng().fill(&mut salt);
+    salt
+}
 
-    let mut hmac_secret =
-        get_hmac_secret(device, credential_id, &salt).context("Failed to get encryption key")?;
+fn aead_encrypt<C: Aead + AeadCore + KeyInit>(
+    key: &[u8; 32],
+    nonce: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    C::new_from_slice(key)
+        .expect("every cipher takes a 256-bit key")
+        .encrypt(
+            Nonce::<C>::from_slice(nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| Error::InvalidInput("plaintext too large to encrypt".to_string()))
+}
 
-    let mut nonce_bytes = [0u8; 12];
-    rand::rng().fill(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
+fn aead_decrypt<C: Aead + AeadCore + KeyInit>(
+    key: &[u8; 32],
+    nonce: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    C::new_from_slice(key)
+        .expect("every cipher takes a 256-bit key")
+        .decrypt(
+            Nonce::<C>::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| Error::DecryptFailed)
+}
 
-    let cipher = Aes256Gcm::new_from_slice(&hmac_secret)
-        .map_err(|e| anyhow!("Failed to create cipher: {}", e))?;
+/// `salt || nonce || ciphertext` under `key`, with a random nonce.
+fn seal(
+    cipher: Cipher,
+    key: &[u8; 32],
+    salt: &[u8; 32],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let mut nonce = vec![0u8; cipher.nonce_len()];
+    rand::rng().fill(nonce.as_mut_slice());
 
-    let ciphertext = cipher
-        .encrypt(nonce, plaintext.as_bytes())
-        .map_err(|e| anyhow!("Encryption failed: {}", e))?;
+    let ciphertext = match cipher {
+        Cipher::Aes256Gcm => aead_encrypt::<Aes256Gcm>(key, &nonce, plaintext, aad)?,
+        Cipher::XChaCha20Poly1305 => {
+            aead_encrypt::<XChaCha20Poly1305>(key, &nonce, plaintext, aad)?
+        }
+        Cipher::Aes256GcmSiv => aead_encrypt::<Aes256GcmSiv>(key, &nonce, plaintext, aad)?,
+    };
 
     let mut result = Vec::new();
-    result.extend_from_slice(&salt);
-    result.extend_from_slice(&nonce_bytes);
+    result.extend_from_slice(salt);
+    result.extend_from_slice(&nonce);
     result.extend_from_slice(&ciphertext);
 
-    hmac_secret.zeroize();
-
     Ok(result)
 }
 
-pub fn decrypt_data(
-    device: &mut FidoKeyHid,
-    credential_id: &[u8],
-    encrypted_data: &[u8],
-) -> Result<String> {
-    if encrypted_data.len() < 44 {
-        return Err(anyhow!("Invalid encrypted data"));
+fn salt_of(cipher: Cipher, encrypted_data: &[u8]) -> Result<[u8; 32]> {
+    if encrypted_data.len() < cipher.overhead() {
+        return Err(Error::EntryCorrupted);
     }
 
-    let salt: [u8; 32] = encrypted_data[0..32]
+    encrypted_data[..SALT_LEN]
         .try_into()
-        .map_err(|_| anyhow!("Error extracting decryption data"))?;
-    let nonce_bytes: [u8; 12] = encrypted_data[32..44]
-        .try_into()
-        .map_err(|_| anyhow!("Error extracting decryption data"))?;
-    let ciphertext = &encrypted_data[44..];
+        .map_err(|_| Error::EntryCorrupted)
+}
+
+/// Decrypts what `seal` produced under `key`.
+fn open(
+    cipher: Cipher,
+    key: &[u8; 32],
+    encrypted_data: &[u8],
+    aad: &[u8],
+) -> Result<Zeroizing<Vec<u8>>> {
+    if encrypted_data.len() < cipher.overhead() {
+        return Err(Error::EntryCorrupted);
+    }
+
+    let (nonce, ciphertext) = encrypted_data[SALT_LEN..].split_at(cipher.nonce_len());
+    let plaintext = match cipher {
+        Cipher::Aes256Gcm => aead_decrypt::<Aes256Gcm>(key, nonce, ciphertext, aad)?,
+        Cipher::XChaCha20Poly1305 => {
+            aead_decrypt::<XChaCha20Poly1305>(key, nonce, ciphertext, aad)?
+        }
+        Cipher::Aes256GcmSiv => aead_decrypt::<Aes256GcmSiv>(key, nonce, ciphertext, aad)?,
+    };
 
-    let mut hmac_secret =
-        get_hmac_secret(device, credential_id, &salt).context("Error extracting decryption Key")?;
+    Ok(Zeroizing::new(plaintext))
+}
 
-    let cipher = Aes256Gcm::new_from_slice(&hmac_secret)
-        .map_err(|e| anyhow!("Failed to create cipher: {}", e))?;
+pub fn encrypt_data(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+    purpose: Option<KeyPurpose>,
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let salt = random_salt();
 
-    let nonce = Nonce::from_slice(&nonce_bytes);
+    let mut hmac_secret = get_hmac_secret(device, session, profile, credential_id, &salt)?;
+    let result = encrypt_with_hmac_secret(&hmac_secret, &salt, purpose, plaintext, aad);
+    hmac_secret.zeroize();
 
-    let plaintext_bytes = cipher
-        .decrypt(nonce, ciphertext)
-        .map_err(|e| anyhow!("Decryption failed: {}", e))?;
+    result
+}
 
-    let result = String::from_utf8(plaintext_bytes).context("Invalid decrypted data")?;
+pub fn decrypt_data(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+    purpose: Option<KeyPurpose>,
+    encrypted_data: &[u8],
+    aad: &[u8],
+) -> Result<Zeroizing<Vec<u8>>> {
+    let salt = hmac_secret_salt(encrypted_data)?;
 
+    let mut hmac_secret = get_hmac_secret(device, session, profile, credential_id, &salt)?;
+    let result = decrypt_with_hmac_secret(&hmac_secret, purpose, encrypted_data, aad);
     hmac_secret.zeroize();
 
-    Ok(result)
+    result
+}
+
+/// The salt `encrypt_data` asked the authenticator's hmac-secret for.
+pub fn hmac_secret_salt(encrypted_data: &[u8]) -> Result<[u8; 32]> {
+    salt_of(Cipher::Aes256Gcm, encrypted_data)
+}
+
+/// `encrypt_data` with the hmac-secret output for `salt` already at hand.
+pub fn encrypt_with_hmac_secret(
+    hmac_secret: &[u8; 32],
+    salt: &[u8; 32],
+    purpose: Option<KeyPurpose>,
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let key = content_key(hmac_secret, purpose);
+    seal(Cipher::Aes256Gcm, &key, salt, plaintext, aad)
+}
+
+/// `decrypt_data` with the hmac-secret output for the data's salt already at hand.
+pub fn decrypt_with_hmac_secret(
+    hmac_secret: &[u8; 32],
+    purpose: Option<KeyPurpose>,
+    encrypted_data: &[u8],
+    aad: &[u8],
+) -> Result<Zeroizing<Vec<u8>>> {
+    let key = content_key(hmac_secret, purpose);
+    open(Cipher::Aes256Gcm, &key, encrypted_data, aad)
+}
+
+/// Like `encrypt_data`, with the key derived on the host from `key_material`
+/// (a vault master key, possibly followed by a stretched passphrase) and a
+/// random salt instead of from an hmac-secret assertion, and sealed with
+/// `cipher`.
+pub fn encrypt_with_key(
+    key_material: &[u8],
+    cipher: Cipher,
+    purpose: KeyPurpose,
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    let salt = random_salt();
+    let key = derive_salted_key(key_material, Some(&salt), purpose);
+    seal(cipher, &key, &salt, plaintext, aad)
+}
+
+/// Decrypts what `encrypt_with_key` produced.
+pub fn decrypt_with_key(
+    key_material: &[u8],
+    cipher: Cipher,
+    purpose: KeyPurpose,
+    encrypted_data: &[u8],
+    aad: &[u8],
+) -> Result<Zeroizing<Vec<u8>>> {
+    let salt = salt_of(cipher, encrypted_data)?;
+    let key = derive_salted_key(key_material, Some(&salt), purpose);
+    open(cipher, &key, encrypted_data, aad)
 }
@@ -0,0 +1,199 @@
+use crate::auth::LOW_PIN_RETRIES;
+use crate::authenticator::{CtapStatus, ctap_status};
+use std::fmt;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors returned by the library.
+///
+/// Authenticator status codes are mapped to their own variants, so callers
+/// can tell a wrong PIN from a missing device or a key nobody touched.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("no FIDO2 device found")]
+    DeviceNotFound,
+
+    #[error("signer unavailable")]
+    SignerUnavailable,
+
+    #[error("this device does not support largeBlob")]
+    LargeBlobUnsupported,
+
+    #[error("this device does not support credential management (CTAP 2.1)")]
+    CredentialManagementUnsupported,
+
+    #[error("no PIN is set on this device")]
+    PinNotSet,
+
+    #[error("a PIN is required for this operation")]
+    PinRequired,
+
+    #[error("wrong PIN{}", RetriesLeft(*.retries_left))]
+    PinInvalid { retries_left: Option<i32> },
+
+    #[error(
+        "too many wrong PINs in a row; unplug and reinsert the authenticator before trying again"
+    )]
+    PinAuthBlocked,
+
+    #[error(
+        "the PIN is blocked; the authenticator must be reset, which erases all credentials and stored keys"
+    )]
+    PinBlocked,
+
+    /// The session's pinUvAuthToken expired, was invalidated by a power
+    /// cycle, or lost the permission the command needs.
+    #[error("the authenticator rejected the PIN session's token")]
+    PinTokenRejected,
+
+    #[error("the configured PIN source returned a wrong PIN; it will not be asked again")]
+    PinSourceRejected,
+
+    #[error("PIN source failed: {0}")]
+    PinSource(String),
+
+    #[error("the new PIN does not meet the authenticator's PIN policy")]
+    PinPolicyViolation,
+
+    #[error("timed out waiting for the authenticator to be touched")]
+    UserPresenceTimeout,
+
+    #[error("the operation was denied or cancelled on the authenticator")]
+    OperationDenied,
+
+    #[error("no credential for this relying party on the device")]
+    NoCredentials,
+
+    #[error("the authenticator has no free discoverable credential slots")]
+    KeyStoreFull,
+
+    #[error("largeBlob is full ({needed}/{capacity} bytes)")]
+    BlobFull { needed: usize, capacity: usize },
+
+    #[error("entry not found")]
+    EntryNotFound,
+
+    #[error("an entry with this ID already exists")]
+    EntryExists,
+
+    #[error(
+        "this credential has no largeBlobKey and the largeBlob array holds other credentials' data"
+    )]
+    LargeBlobKeyUnavailable,
+
+    #[error("the largeBlob changed since it was read; nothing was written")]
+    BlobConflict,
+
+    #[error(
+        "the largeBlob read back differs from what was written; restore the snapshot of the previous contents"
+    )]
+    WriteVerificationFailed,
+
+    #[error("no largeBlob snapshot saved for this profile")]
+    SnapshotNotFound,
+
+    #[error("not a backup file, or a damaged one")]
+    BackupCorrupted,
+
+    #[error("backup uses format version {0}, which this version cannot read")]
+    UnsupportedBackupFormat(u32),
+
+    #[error("largeBlob uses format version {0}, which this version cannot read")]
+    UnsupportedFormat(u32),
+
+    #[error("unknown key derivation label '{0}'")]
+    UnsupportedKeyLabel(String),
+
+    #[error("entry is encrypted under a vault key that is missing")]
+    VaultKeyMissing,
+
+    #[error("this entry needs its passphrase, which can only be entered interactively")]
+    PassphraseRequired,
+
+    #[error("entry is corrupted")]
+    EntryCorrupted,
+
+    #[error("decryption failed")]
+    DecryptFailed,
+
+    #[error("the decrypted key does not match the entry's recorded public key")]
+    PublicKeyMismatch,
+
+    #[error("operation cancelled")]
+    Cancelled,
+
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+
+    /// A status code without a dedicated variant.
+    #[error("authenticator error: {0}")]
+    Ctap(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+struct RetriesLeft(Option<i32>);
+
+impl fmt::Display for RetriesLeft {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(retries) if retries <= LOW_PIN_RETRIES => write!(
+                f,
+                " (only {} attempt(s) left before the PIN is blocked for good!)",
+                retries
+            ),
+            Some(retries) => write!(f, " ({} attempts left)", retries),
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    /// Recovers a typed error from an authenticator (or wrapped library) error.
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<Error>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+
+        let Some(status) = ctap_status(&error) else {
+            return Error::Other(error);
+        };
+
+        match status {
+            CtapStatus::PIN_INVALID => Error::PinInvalid { retries_left: None },
+            CtapStatus::PIN_AUTH_INVALID => Error::PinTokenRejected,
+            CtapStatus::PIN_AUTH_BLOCKED => Error::PinAuthBlocked,
+            CtapStatus::PIN_BLOCKED => Error::PinBlocked,
+            CtapStatus::PIN_NOT_SET => Error::PinNotSet,
+            CtapStatus::PUAT_REQUIRED => Error::PinRequired,
+            CtapStatus::PIN_POLICY_VIOLATION => Error::PinPolicyViolation,
+            CtapStatus::USER_ACTION_TIMEOUT => Error::UserPresenceTimeout,
+            CtapStatus::OPERATION_DENIED | CtapStatus::KEEPALIVE_CANCEL => Error::OperationDenied,
+            CtapStatus::NO_CREDENTIALS => Error::NoCredentials,
+            CtapStatus::KEY_STORE_FULL => Error::KeyStoreFull,
+            status => Error::Ctap(status.to_string()),
+        }
+    }
+}
+
+impl Error {
+    pub fn is_pin_error(&self) -> bool {
+        matches!(
+            self,
+            Error::PinNotSet
+                | Error::PinRequired
+                | Error::PinInvalid { .. }
+                | Error::PinSourceRejected
+                | Error::PinTokenRejected
+                | Error::PinAuthBlocked
+                | Error::PinBlocked
+                | Error::PinPolicyViolation
+        )
+    }
+}
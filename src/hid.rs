@@ -0,0 +1,824 @@
+//! CTAP2 over USB HID, for the commands in [`Authenticator`].
+//!
+//! ctap-hid-fido2 takes the PIN on every call and keeps the pinUvAuthToken it
+//! exchanges it for to itself, and it sends a single hmac-secret salt. This
+//! speaks CTAPHID directly, so a session can hold a token instead of the PIN
+//! and one assertion can return two hmac-secret outputs and the largeBlobKey.
+//! Only PIN/UV auth protocol 1 is used, which every CTAP2 authenticator
+//! supports.
+
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit, block_padding::NoPadding};
+use anyhow::{Result, anyhow};
+use ciborium::{Value, value::Integer};
+use ctap_hid_fido2::{
+    HidParam,
+    fidokey::{get_info::InfoOption, large_blobs::large_blobs_params::LargeBlobData},
+    public_key_credential_user_entity::PublicKeyCredentialUserEntity,
+};
+use hidapi::{HidApi, HidDevice};
+use hmac::{Hmac, Mac};
+use ring::agreement;
+use sha2::{Digest, Sha256};
+use std::ffi::CString;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+use crate::authenticator::{
+    AssertionRequest, AssertionResponse, Authenticator, AuthenticatorInfo, CtapStatus,
+    DEFAULT_MAX_MSG_SIZE, EMPTY_LARGE_BLOB_ARRAY, LARGE_BLOB_HASH_LEN, Permissions, PinUvAuthToken,
+    ResidentCredential,
+};
+
+const PACKET_SIZE: usize = 64;
+const INIT_PACKET_DATA: usize = PACKET_SIZE - 7;
+const CONT_PACKET_DATA: usize = PACKET_SIZE - 5;
+const BROADCAST_CID: [u8; 4] = [0xff; 4];
+
+const CTAPHID_INIT: u8 = 0x86;
+const CTAPHID_CBOR: u8 = 0x90;
+const CTAPHID_KEEPALIVE: u8 = 0xBB;
+const CTAPHID_ERROR: u8 = 0xBF;
+const KEEPALIVE_UP_NEEDED: u8 = 2;
+
+// Keepalives come every 100 ms while the authenticator works or waits for a
+// touch, so one that stays silent this long is gone.
+const READ_TIMEOUT_MS: i32 = 2000;
+
+const MAKE_CREDENTIAL: u8 = 0x01;
+const GET_ASSERTION: u8 = 0x02;
+const GET_INFO: u8 = 0x04;
+const CLIENT_PIN: u8 = 0x06;
+const CREDENTIAL_MANAGEMENT: u8 = 0x0A;
+const LARGE_BLOBS: u8 = 0x0C;
+
+const PIN_PROTOCOL: u8 = 1;
+
+// authenticatorClientPIN subcommands.
+const GET_PIN_RETRIES: u8 = 0x01;
+const GET_KEY_AGREEMENT: u8 = 0x02;
+const SET_PIN: u8 = 0x03;
+const CHANGE_PIN: u8 = 0x04;
+const GET_PIN_TOKEN: u8 = 0x05;
+const GET_PIN_UV_AUTH_TOKEN_USING_PIN_WITH_PERMISSIONS: u8 = 0x09;
+
+// authenticatorCredentialManagement subcommands.
+const ENUMERATE_CREDENTIALS_BEGIN: u8 = 0x04;
+const ENUMERATE_CREDENTIALS_GET_NEXT: u8 = 0x05;
+const DELETE_CREDENTIAL: u8 = 0x06;
+
+// A largeBlobs fragment is at most maxMsgSize minus this (CTAP 2.1 §6.10.2).
+const LARGE_BLOB_FRAGMENT_OVERHEAD: u32 = 64;
+
+const PADDED_PIN_LEN: usize = 64;
+
+// authData flags.
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+const FLAG_EXTENSION_DATA: u8 = 0x80;
+const FLAGS_OFFSET: usize = 32;
+const AUTH_DATA_HEADER_LEN: usize = FLAGS_OFFSET + 1 + 4;
+const AAGUID_LEN: usize = 16;
+
+/// A FIDO2 authenticator on a USB HID channel of its own.
+pub struct HidAuthenticator {
+    device: Mutex<HidDevice>,
+    cid: [u8; 4],
+    max_msg_size: u32,
+    // CTAP 2.1 authenticators hand out tokens restricted to permissions;
+    // 2.0 ones only have getPinToken.
+    token_permissions: bool,
+}
+
+impl HidAuthenticator {
+    pub fn open(param: &HidParam) -> Result<Self> {
+        let api = HidApi::new()?;
+        let device = match param {
+            HidParam::Path(path) => api.open_path(&CString::new(path.as_str())?)?,
+            HidParam::VidPid { vid, pid } => api.open(*vid, *pid)?,
+        };
+        let cid = init_channel(&device)?;
+
+        let mut authenticator = Self {
+            device: Mutex::new(device),
+            cid,
+            max_msg_size: DEFAULT_MAX_MSG_SIZE,
+            token_permissions: false,
+        };
+        let info = authenticator.get_authenticator_info()?;
+        authenticator.max_msg_size = info.max_msg_size;
+        authenticator.token_permissions = info.option(&InfoOption::PinUvAuthToken) == Some(true);
+
+        Ok(authenticator)
+    }
+
+    fn transact(&self, command: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let device = self
+            .device
+            .lock()
+            .map_err(|_| anyhow!("HID device lock poisoned"))?;
+        send(&device, self.cid, command, data)?;
+        receive(&device, self.cid, command)
+    }
+
+    /// Sends a CTAP2 command and returns its response map, failing with the
+    /// [`CtapStatus`] the authenticator reported.
+    fn command(&self, command: u8, parameters: Option<Value>) -> Result<Value> {
+        let mut request = vec![command];
+        if let Some(parameters) = parameters {
+            ciborium::into_writer(&parameters, &mut request)?;
+        }
+
+        let response = self.transact(CTAPHID_CBOR, &request)?;
+        let (&status, body) = response
+            .split_first()
+            .ok_or_else(|| anyhow!("empty CTAP2 response"))?;
+        if status != 0 {
+            return Err(CtapStatus(status).into());
+        }
+        if body.is_empty() {
+            return Ok(Value::Map(Vec::new()));
+        }
+        Ok(ciborium::from_reader(body)?)
+    }
+
+    fn client_pin(&self, subcommand: u8, mut parameters: Vec<(Value, Value)>) -> Result<Value> {
+        parameters.splice(
+            0..0,
+            [(int(1), int(PIN_PROTOCOL)), (int(2), int(subcommand))],
+        );
+        self.command(CLIENT_PIN, Some(Value::Map(parameters)))
+    }
+
+    fn key_agreement(&self) -> Result<SharedSecret> {
+        let response = self.client_pin(GET_KEY_AGREEMENT, Vec::new())?;
+        let peer_key = field(&response, 1).ok_or_else(|| malformed("keyAgreement"))?;
+        SharedSecret::new(peer_key)
+    }
+
+    fn credential_management(
+        &self,
+        token: &PinUvAuthToken,
+        subcommand: u8,
+        parameters: Value,
+    ) -> Result<Value> {
+        let mut message = vec![subcommand];
+        ciborium::into_writer(&parameters, &mut message)?;
+
+        self.command(
+            CREDENTIAL_MANAGEMENT,
+            Some(Value::Map(vec![
+                (int(1), int(subcommand)),
+                (int(2), parameters),
+                (int(3), int(PIN_PROTOCOL)),
+                (int(4), Value::Bytes(authenticate(token.key(), &message))),
+            ])),
+        )
+    }
+
+    fn large_blob_fragment(&self) -> usize {
+        self.max_msg_size
+            .saturating_sub(LARGE_BLOB_FRAGMENT_OVERHEAD) as usize
+    }
+}
+
+impl Authenticator for HidAuthenticator {
+    fn get_pin_uv_auth_token(&self, pin: &str, permissions: Permissions) -> Result<PinUvAuthToken> {
+        let shared = self.key_agreement()?;
+        let pin_hash = Sha256::digest(pin.as_bytes());
+
+        let mut parameters = vec![
+            (int(3), shared.platform_key.clone()),
+            (int(6), Value::Bytes(shared.encrypt(&pin_hash[..16]))),
+        ];
+        let subcommand = if self.token_permissions {
+            parameters.push((int(9), int(permissions.bits())));
+            GET_PIN_UV_AUTH_TOKEN_USING_PIN_WITH_PERMISSIONS
+        } else {
+            GET_PIN_TOKEN
+        };
+
+        let response = self.client_pin(subcommand, parameters)?;
+        let encrypted = bytes_field(&response, 2).ok_or_else(|| malformed("pinUvAuthToken"))?;
+        let key = shared.decrypt(encrypted)?;
+
+        Ok(PinUvAuthToken::new(key.to_vec(), permissions))
+    }
+
+    fn get_assertion(
+        &self,
+        request: &AssertionRequest<'_>,
+        token: &PinUvAuthToken,
+    ) -> Result<AssertionResponse> {
+        if request.hmac_secret_salts.len() > 2 {
+            return Err(anyhow!("hmac-secret takes at most two salts"));
+        }
+
+        let client_data_hash: [u8; 32] = rand::random();
+        let shared = match request.hmac_secret_salts {
+            [] => None,
+            _ => Some(self.key_agreement()?),
+        };
+
+        let mut extensions = Vec::new();
+        if let Some(shared) = &shared {
+            let salt_enc = shared.encrypt(&request.hmac_secret_salts.concat());
+            let salt_auth = shared.authenticate(&salt_enc);
+            extensions.push((
+                text("hmac-secret"),
+                Value::Map(vec![
+                    (int(1), shared.platform_key.clone()),
+                    (int(2), Value::Bytes(salt_enc)),
+                    (int(3), Value::Bytes(salt_auth)),
+                ]),
+            ));
+        }
+        if request.large_blob_key {
+            extensions.push((text("largeBlobKey"), Value::Bool(true)));
+        }
+
+        let mut parameters = vec![
+            (int(1), text(request.rp_id)),
+            (int(2), Value::Bytes(client_data_hash.to_vec())),
+        ];
+        if !request.credential_ids.is_empty() {
+            let allow_list = request
+                .credential_ids
+                .iter()
+                .map(|id| credential_descriptor(id))
+                .collect();
+            parameters.push((int(3), Value::Array(allow_list)));
+        }
+        if !extensions.is_empty() {
+            parameters.push((int(4), Value::Map(extensions)));
+        }
+        parameters.push((
+            int(6),
+            Value::Bytes(authenticate(token.key(), &client_data_hash)),
+        ));
+        parameters.push((int(7), int(PIN_PROTOCOL)));
+
+        let response = self.command(GET_ASSERTION, Some(Value::Map(parameters)))?;
+
+        // The credential may be left out when the allow list named only one.
+        let credential_id = match field(&response, 1) {
+            Some(credential) => text_field(credential, "id")
+                .and_then(Value::as_bytes)
+                .cloned()
+                .ok_or_else(|| malformed("credential"))?,
+            None => request
+                .credential_ids
+                .first()
+                .cloned()
+                .ok_or_else(|| malformed("credential"))?,
+        };
+
+        let hmac_secret = match &shared {
+            Some(shared) => {
+                let auth_data = bytes_field(&response, 2).ok_or_else(|| malformed("authData"))?;
+                let extensions = auth_data_extensions(auth_data)?;
+                let encrypted = extensions
+                    .as_ref()
+                    .and_then(|extensions| text_field(extensions, "hmac-secret"))
+                    .and_then(Value::as_bytes)
+                    .ok_or_else(|| anyhow!("the authenticator returned no hmac-secret output"))?;
+                let outputs = shared.decrypt(encrypted)?;
+                if outputs.len() != 32 * request.hmac_secret_salts.len() {
+                    return Err(malformed("hmac-secret output"));
+                }
+                outputs
+                    .chunks_exact(32)
+                    .map(|output| Zeroizing::new(output.try_into().expect("32-byte chunk")))
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+
+        let large_blob_key = match bytes_field(&response, 7) {
+            Some(key) => Some(Zeroizing::new(
+                <[u8; 32]>::try_from(key.as_slice()).map_err(|_| malformed("largeBlobKey"))?,
+            )),
+            None => None,
+        };
+
+        Ok(AssertionResponse {
+            credential_id,
+            hmac_secret,
+            large_blob_key,
+        })
+    }
+
+    fn make_credential(
+        &self,
+        rp_id: &str,
+        user: &PublicKeyCredentialUserEntity,
+        token: &PinUvAuthToken,
+    ) -> Result<Vec<u8>> {
+        let client_data_hash: [u8; 32] = rand::random();
+
+        let parameters = vec![
+            (int(1), Value::Bytes(client_data_hash.to_vec())),
+            (
+                int(2),
+                Value::Map(vec![(text("id"), text(rp_id)), (text("name"), text(rp_id))]),
+            ),
+            (
+                int(3),
+                Value::Map(vec![
+                    (text("id"), Value::Bytes(user.id.clone())),
+                    (text("name"), text(&user.name)),
+                    (text("displayName"), text(&user.display_name)),
+                ]),
+            ),
+            (
+                int(4),
+                Value::Array(vec![Value::Map(vec![
+                    (text("alg"), int(-7)),
+                    (text("type"), text("public-key")),
+                ])]),
+            ),
+            (
+                int(6),
+                Value::Map(vec![
+                    (text("hmac-secret"), Value::Bool(true)),
+                    (text("largeBlobKey"), Value::Bool(true)),
+                ]),
+            ),
+            (int(7), Value::Map(vec![(text("rk"), Value::Bool(true))])),
+            (
+                int(8),
+                Value::Bytes(authenticate(token.key(), &client_data_hash)),
+            ),
+            (int(9), int(PIN_PROTOCOL)),
+        ];
+
+        let response = self.command(MAKE_CREDENTIAL, Some(Value::Map(parameters)))?;
+        let auth_data = bytes_field(&response, 2).ok_or_else(|| malformed("authData"))?;
+
+        // rpIdHash, flags, counter, then aaguid, a length and the credential ID.
+        let start = AUTH_DATA_HEADER_LEN + AAGUID_LEN;
+        let length = auth_data
+            .get(start..start + 2)
+            .map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+            .ok_or_else(|| malformed("authData"))?;
+        auth_data
+            .get(start + 2..start + 2 + length)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| malformed("authData"))
+    }
+
+    fn get_large_blob(&self) -> Result<LargeBlobData> {
+        let fragment = self.large_blob_fragment();
+        let mut serialized = Vec::new();
+        loop {
+            let response = self.command(
+                LARGE_BLOBS,
+                Some(Value::Map(vec![
+                    (int(1), int(fragment)),
+                    (int(3), int(serialized.len())),
+                ])),
+            )?;
+            let data = bytes_field(&response, 1).ok_or_else(|| malformed("largeBlob"))?;
+            serialized.extend_from_slice(data);
+            if data.len() < fragment {
+                break;
+            }
+        }
+
+        // An array whose hash doesn't match reads as the empty array (CTAP 2.1 §6.10.3).
+        let valid = serialized.len() > LARGE_BLOB_HASH_LEN && {
+            let (array, hash) = serialized.split_at(serialized.len() - LARGE_BLOB_HASH_LEN);
+            Sha256::digest(array)[..LARGE_BLOB_HASH_LEN] == *hash
+        };
+        if !valid {
+            serialized = EMPTY_LARGE_BLOB_ARRAY.to_vec();
+            serialized.extend_from_slice(&Sha256::digest(&serialized)[..LARGE_BLOB_HASH_LEN]);
+        }
+
+        let hash = serialized.split_off(serialized.len() - LARGE_BLOB_HASH_LEN);
+        Ok(LargeBlobData {
+            large_blob_array: serialized,
+            hash,
+        })
+    }
+
+    fn write_large_blob(&self, token: &PinUvAuthToken, mut data: Vec<u8>) -> Result<()> {
+        let hash = Sha256::digest(&data);
+        data.extend_from_slice(&hash[..LARGE_BLOB_HASH_LEN]);
+
+        let fragment = self.large_blob_fragment();
+        for (index, chunk) in data.chunks(fragment).enumerate() {
+            let offset = (index * fragment) as u32;
+
+            let mut message = vec![0xff; 32];
+            message.extend_from_slice(&[LARGE_BLOBS, 0x00]);
+            message.extend_from_slice(&offset.to_le_bytes());
+            message.extend_from_slice(&Sha256::digest(chunk));
+
+            let mut parameters = vec![
+                (int(2), Value::Bytes(chunk.to_vec())),
+                (int(3), int(offset)),
+            ];
+            if offset == 0 {
+                parameters.push((int(4), int(data.len())));
+            }
+            parameters.push((int(5), Value::Bytes(authenticate(token.key(), &message))));
+            parameters.push((int(6), int(PIN_PROTOCOL)));
+
+            self.command(LARGE_BLOBS, Some(Value::Map(parameters)))?;
+        }
+
+        Ok(())
+    }
+
+    fn enable_info_option(&self, info_option: &InfoOption) -> Result<Option<bool>> {
+        Ok(self.get_authenticator_info()?.option(info_option))
+    }
+
+    fn get_authenticator_info(&self) -> Result<AuthenticatorInfo> {
+        let response = self.command(GET_INFO, None)?;
+
+        let texts = |key| {
+            field(&response, key)
+                .and_then(Value::as_array)
+                .map(|values| {
+                    values
+                        .iter()
+                        .filter_map(|value| value.as_text().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let number = |key| uint_field(&response, key).unwrap_or(0);
+
+        Ok(AuthenticatorInfo {
+            versions: texts(0x01),
+            extensions: texts(0x02),
+            aaguid: bytes_field(&response, 0x03).cloned().unwrap_or_default(),
+            options: field(&response, 0x04)
+                .and_then(Value::as_map)
+                .map(|options| {
+                    options
+                        .iter()
+                        .filter_map(|(name, value)| {
+                            Some((name.as_text()?.to_string(), value.as_bool()?))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            max_msg_size: uint_field(&response, 0x05).unwrap_or(DEFAULT_MAX_MSG_SIZE),
+            max_serialized_large_blob_array: number(0x0B),
+            min_pin_length: number(0x0D),
+            firmware_version: number(0x0E),
+            remaining_discoverable_credentials: number(0x14),
+        })
+    }
+
+    fn get_pin_retries(&self) -> Result<i32> {
+        let response = self.client_pin(GET_PIN_RETRIES, Vec::new())?;
+        uint_field(&response, 3)
+            .map(|retries| retries as i32)
+            .ok_or_else(|| malformed("pinRetries"))
+    }
+
+    fn set_new_pin(&self, pin: &str) -> Result<()> {
+        let shared = self.key_agreement()?;
+        let new_pin_enc = shared.encrypt(&padded_pin(pin)?);
+
+        self.client_pin(
+            SET_PIN,
+            vec![
+                (int(3), shared.platform_key.clone()),
+                (int(4), Value::Bytes(shared.authenticate(&new_pin_enc))),
+                (int(5), Value::Bytes(new_pin_enc)),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn change_pin(&self, current_pin: &str, new_pin: &str) -> Result<()> {
+        let shared = self.key_agreement()?;
+        let new_pin_enc = shared.encrypt(&padded_pin(new_pin)?);
+        let pin_hash_enc = shared.encrypt(&Sha256::digest(current_pin.as_bytes())[..16]);
+
+        let mut message = new_pin_enc.clone();
+        message.extend_from_slice(&pin_hash_enc);
+
+        self.client_pin(
+            CHANGE_PIN,
+            vec![
+                (int(3), shared.platform_key.clone()),
+                (int(4), Value::Bytes(shared.authenticate(&message))),
+                (int(5), Value::Bytes(new_pin_enc)),
+                (int(6), Value::Bytes(pin_hash_enc)),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn enumerate_credentials(
+        &self,
+        token: &PinUvAuthToken,
+        rpid: &str,
+    ) -> Result<Vec<ResidentCredential>> {
+        let rpid_hash = Sha256::digest(rpid.as_bytes()).to_vec();
+        let first = match self.credential_management(
+            token,
+            ENUMERATE_CREDENTIALS_BEGIN,
+            Value::Map(vec![(int(1), Value::Bytes(rpid_hash))]),
+        ) {
+            Ok(response) => response,
+            Err(e) if e.downcast_ref() == Some(&CtapStatus::NO_CREDENTIALS) => {
+                return Ok(Vec::new());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let total = uint_field(&first, 9).unwrap_or(1);
+        let mut credentials = vec![resident_credential(&first)?];
+        for _ in 1..total {
+            let next = self.command(
+                CREDENTIAL_MANAGEMENT,
+                Some(Value::Map(vec![(
+                    int(1),
+                    int(ENUMERATE_CREDENTIALS_GET_NEXT),
+                )])),
+            )?;
+            credentials.push(resident_credential(&next)?);
+        }
+
+        Ok(credentials)
+    }
+
+    fn delete_credential(&self, token: &PinUvAuthToken, credential_id: &[u8]) -> Result<()> {
+        self.credential_management(
+            token,
+            DELETE_CREDENTIAL,
+            Value::Map(vec![(int(2), credential_descriptor(credential_id))]),
+        )?;
+        Ok(())
+    }
+}
+
+/// The secret agreed with the authenticator for one clientPIN exchange or
+/// hmac-secret request (PIN/UV auth protocol 1, CTAP 2.1 §6.5.6).
+struct SharedSecret {
+    platform_key: Value,
+    secret: Zeroizing<[u8; 32]>,
+}
+
+impl SharedSecret {
+    fn new(peer_key: &Value) -> Result<Self> {
+        let coordinate = |key| {
+            field(peer_key, key)
+                .and_then(Value::as_bytes)
+                .filter(|coordinate| coordinate.len() == 32)
+                .ok_or_else(|| malformed("keyAgreement"))
+        };
+        let mut peer_point = vec![0x04];
+        peer_point.extend_from_slice(coordinate(-2)?);
+        peer_point.extend_from_slice(coordinate(-3)?);
+
+        let rng = ring::rand::SystemRandom::new();
+        let private_key = agreement::EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng)
+            .map_err(|_| anyhow!("cannot generate a key agreement key"))?;
+        let public_key = private_key
+            .compute_public_key()
+            .map_err(|_| anyhow!("cannot generate a key agreement key"))?;
+
+        let secret = agreement::agree_ephemeral(
+            private_key,
+            &agreement::UnparsedPublicKey::new(&agreement::ECDH_P256, peer_point),
+            |shared_point| Zeroizing::new(<[u8; 32]>::from(Sha256::digest(shared_point))),
+        )
+        .map_err(|_| anyhow!("the authenticator sent an invalid key agreement key"))?;
+
+        // Uncompressed point: 0x04, x, y.
+        let public_key = public_key.as_ref();
+        let platform_key = Value::Map(vec![
+            (int(1), int(2)),
+            (int(3), int(-25)),
+            (int(-1), int(1)),
+            (int(-2), Value::Bytes(public_key[1..33].to_vec())),
+            (int(-3), Value::Bytes(public_key[33..65].to_vec())),
+        ]);
+
+        Ok(Self {
+            platform_key,
+            secret,
+        })
+    }
+
+    /// AES-256-CBC with a zero IV; `data` is a whole number of blocks.
+    fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        let mut buffer = data.to_vec();
+        cbc::Encryptor::<aes::Aes256>::new_from_slices(self.secret.as_ref(), &[0u8; 16])
+            .expect("AES-256 key and IV lengths are fixed")
+            .encrypt_padded_mut::<NoPadding>(&mut buffer, data.len())
+            .expect("callers pass whole blocks");
+        buffer
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let mut buffer = Zeroizing::new(data.to_vec());
+        cbc::Decryptor::<aes::Aes256>::new_from_slices(self.secret.as_ref(), &[0u8; 16])
+            .expect("AES-256 key and IV lengths are fixed")
+            .decrypt_padded_mut::<NoPadding>(&mut buffer)
+            .map_err(|_| malformed("encrypted response"))?;
+        Ok(buffer)
+    }
+
+    fn authenticate(&self, message: &[u8]) -> Vec<u8> {
+        authenticate(self.secret.as_ref(), message)
+    }
+}
+
+/// LEFT(HMAC-SHA-256(key, message), 16).
+fn authenticate(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(message);
+    mac.finalize().into_bytes()[..16].to_vec()
+}
+
+fn padded_pin(pin: &str) -> Result<Zeroizing<Vec<u8>>> {
+    if pin.len() >= PADDED_PIN_LEN {
+        return Err(CtapStatus::PIN_POLICY_VIOLATION.into());
+    }
+    let mut padded = Zeroizing::new(vec![0u8; PADDED_PIN_LEN]);
+    padded[..pin.len()].copy_from_slice(pin.as_bytes());
+    Ok(padded)
+}
+
+/// The extensions map at the end of `auth_data`, if the ED flag is set.
+fn auth_data_extensions(auth_data: &[u8]) -> Result<Option<Value>> {
+    let flags = *auth_data
+        .get(FLAGS_OFFSET)
+        .ok_or_else(|| malformed("authData"))?;
+    if flags & FLAG_EXTENSION_DATA == 0 {
+        return Ok(None);
+    }
+
+    let mut rest = auth_data
+        .get(AUTH_DATA_HEADER_LEN..)
+        .ok_or_else(|| malformed("authData"))?;
+    if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+        let length = rest
+            .get(AAGUID_LEN..AAGUID_LEN + 2)
+            .map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+            .ok_or_else(|| malformed("authData"))?;
+        rest = rest
+            .get(AAGUID_LEN + 2 + length..)
+            .ok_or_else(|| malformed("authData"))?;
+        let _public_key: Value = ciborium::from_reader(&mut rest)?;
+    }
+
+    Ok(Some(ciborium::from_reader(rest)?))
+}
+
+fn resident_credential(response: &Value) -> Result<ResidentCredential> {
+    let user = field(response, 6).ok_or_else(|| malformed("user"))?;
+    let text_of = |key| {
+        text_field(user, key)
+            .and_then(Value::as_text)
+            .unwrap_or_default()
+            .to_string()
+    };
+
+    Ok(ResidentCredential {
+        credential_id: field(response, 7)
+            .and_then(|descriptor| text_field(descriptor, "id"))
+            .and_then(Value::as_bytes)
+            .cloned()
+            .ok_or_else(|| malformed("credentialID"))?,
+        user: PublicKeyCredentialUserEntity {
+            id: text_field(user, "id")
+                .and_then(Value::as_bytes)
+                .cloned()
+                .unwrap_or_default(),
+            name: text_of("name"),
+            display_name: text_of("displayName"),
+        },
+    })
+}
+
+fn credential_descriptor(id: &[u8]) -> Value {
+    Value::Map(vec![
+        (text("id"), Value::Bytes(id.to_vec())),
+        (text("type"), text("public-key")),
+    ])
+}
+
+fn int(value: impl Into<Integer>) -> Value {
+    Value::Integer(value.into())
+}
+
+fn text(value: &str) -> Value {
+    Value::Text(value.to_string())
+}
+
+fn field(map: &Value, key: i64) -> Option<&Value> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k.as_integer().is_some_and(|k| i128::from(k) == key as i128))
+        .map(|(_, value)| value)
+}
+
+fn text_field<'v>(map: &'v Value, key: &str) -> Option<&'v Value> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k.as_text() == Some(key))
+        .map(|(_, value)| value)
+}
+
+fn bytes_field(map: &Value, key: i64) -> Option<&Vec<u8>> {
+    field(map, key)?.as_bytes()
+}
+
+fn uint_field(map: &Value, key: i64) -> Option<u32> {
+    field(map, key)?
+        .as_integer()
+        .and_then(|value| u32::try_from(value).ok())
+}
+
+fn malformed(what: &str) -> anyhow::Error {
+    anyhow!("the authenticator returned a malformed {}", what)
+}
+
+fn init_channel(device: &HidDevice) -> Result<[u8; 4]> {
+    let nonce: [u8; 8] = rand::random();
+    send(device, BROADCAST_CID, CTAPHID_INIT, &nonce)?;
+
+    // Other clients' INIT responses on the broadcast channel carry their own nonce.
+    loop {
+        let response = receive(device, BROADCAST_CID, CTAPHID_INIT)?;
+        if response.len() >= 12 && response[..8] == nonce {
+            return Ok([response[8], response[9], response[10], response[11]]);
+        }
+    }
+}
+
+fn send(device: &HidDevice, cid: [u8; 4], command: u8, data: &[u8]) -> Result<()> {
+    let length = u16::try_from(data.len()).map_err(|_| anyhow!("CTAPHID message too long"))?;
+    let (first, rest) = data.split_at(data.len().min(INIT_PACKET_DATA));
+
+    // hidapi takes the report ID, always 0 for FIDO, before the packet.
+    let mut packet = [0u8; PACKET_SIZE + 1];
+    packet[1..5].copy_from_slice(&cid);
+    packet[5] = command;
+    packet[6..8].copy_from_slice(&length.to_be_bytes());
+    packet[8..8 + first.len()].copy_from_slice(first);
+    device.write(&packet)?;
+
+    for (sequence, chunk) in rest.chunks(CONT_PACKET_DATA).enumerate() {
+        let mut packet = [0u8; PACKET_SIZE + 1];
+        packet[1..5].copy_from_slice(&cid);
+        packet[5] = sequence as u8;
+        packet[6..6 + chunk.len()].copy_from_slice(chunk);
+        device.write(&packet)?;
+    }
+
+    Ok(())
+}
+
+fn read_packet(device: &HidDevice, cid: [u8; 4]) -> Result<[u8; PACKET_SIZE]> {
+    loop {
+        let mut packet = [0u8; PACKET_SIZE];
+        if device.read_timeout(&mut packet, READ_TIMEOUT_MS)? == 0 {
+            return Err(anyhow!("the authenticator stopped responding"));
+        }
+        if packet[..4] == cid {
+            return Ok(packet);
+        }
+    }
+}
+
+fn receive(device: &HidDevice, cid: [u8; 4], command: u8) -> Result<Vec<u8>> {
+    let mut prompted = false;
+    let packet = loop {
+        let packet = read_packet(device, cid)?;
+        match packet[4] {
+            CTAPHID_KEEPALIVE => {
+                if packet[7] == KEEPALIVE_UP_NEEDED && !prompted {
+                    println!("👆 Touch your YubiKey...");
+                    prompted = true;
+                }
+            }
+            CTAPHID_ERROR => return Err(anyhow!("CTAPHID error 0x{:02X}", packet[7])),
+            response if response == command => break packet,
+            response => return Err(anyhow!("unexpected CTAPHID response 0x{:02X}", response)),
+        }
+    };
+
+    let length = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+    let mut data = packet[7..7 + length.min(INIT_PACKET_DATA)].to_vec();
+    let mut sequence = 0u8;
+    while data.len() < length {
+        let packet = read_packet(device, cid)?;
+        if packet[4] != sequence {
+            return Err(anyhow!("CTAPHID continuation packet out of order"));
+        }
+        let take = (length - data.len()).min(CONT_PACKET_DATA);
+        data.extend_from_slice(&packet[5..5 + take]);
+        sequence += 1;
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,137 @@
+use crate::error::{Error, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// What a key derived from the hmac-secret output is for. Each purpose has
+/// its own HKDF label, so no two subsystems ever share a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPurpose {
+    /// Encryption of largeBlob entries.
+    Entry,
+    /// Wrapping of the vault master key entries are derived from.
+    Vault,
+    /// Encryption of the local largeBlob snapshot.
+    Snapshot,
+    /// Encryption of exported backups.
+    Backup,
+    /// The bunker's NIP-46 transport key, so the bunker's public key stays
+    /// the same across runs.
+    BunkerTransport,
+}
+
+impl KeyPurpose {
+    const ALL: [KeyPurpose; 5] = [
+        KeyPurpose::Entry,
+        KeyPurpose::Vault,
+        KeyPurpose::Snapshot,
+        KeyPurpose::Backup,
+        KeyPurpose::BunkerTransport,
+    ];
+
+    /// The HKDF `info`, recorded next to whatever the key encrypts. A new
+    /// derivation for the same purpose gets a new label.
+    pub fn label(self) -> &'static str {
+        match self {
+            KeyPurpose::Entry => "yubikey-nostr-signer/entry/v1",
+            KeyPurpose::Vault => "yubikey-nostr-signer/vault/v1",
+            KeyPurpose::Snapshot => "yubikey-nostr-signer/snapshot/v1",
+            KeyPurpose::Backup => "yubikey-nostr-signer/backup/v1",
+            KeyPurpose::BunkerTransport => "yubikey-nostr-signer/bunker-transport/v1",
+        }
+    }
+
+    /// The purpose `label` derives keys for, if this version knows it.
+    pub fn from_label(label: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|purpose| purpose.label() == label)
+    }
+}
+
+/// HKDF-SHA256 over `secret` with no salt and `purpose`'s label as info.
+pub fn derive_key(secret: &[u8], purpose: KeyPurpose) -> Zeroizing<[u8; 32]> {
+    derive_salted_key(secret, None, purpose)
+}
+
+/// HKDF-SHA256 over `secret` with `salt` and `purpose`'s label as info, so
+/// one secret yields a different key for every salt.
+pub fn derive_salted_key(
+    secret: &[u8],
+    salt: Option<&[u8]>,
+    purpose: KeyPurpose,
+) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(salt, secret)
+        .expand(purpose.label().as_bytes(), key.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+// Ceilings on Argon2id parameters read back from a file or the largeBlob,
+// far above what `PassphraseKdf::new` writes, so crafted ones can't make
+// stretching allocate gigabytes or run for hours before anything is checked.
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_ITERATIONS: u32 = 16;
+const MAX_PARALLELISM: u32 = 16;
+
+/// How a passphrase is stretched with Argon2id (version 0x13), recorded
+/// with whatever it protects.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PassphraseKdf {
+    #[serde(with = "serde_bytes")]
+    pub salt: Vec<u8>,
+    /// Memory in KiB.
+    pub memory: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl PassphraseKdf {
+    /// Fresh parameters: a random 16-byte salt, 64 MiB, 3 passes and 4 lanes
+    /// (the second recommendation of RFC 9106).
+    pub fn new() -> Self {
+        let mut salt = vec![0u8; 16];
+        rand::rng().fill(salt.as_mut_slice());
+        Self {
+            salt,
+            memory: 64 * 1024,
+            iterations: 3,
+            parallelism: 4,
+        }
+    }
+
+    /// Whether the parameters stay within 1 GiB of memory, 16 passes and 16
+    /// lanes. Callers check parameters they read back before asking for the
+    /// passphrase, and treat the record holding them as corrupted.
+    pub fn is_within_limits(&self) -> bool {
+        self.memory <= MAX_MEMORY_KIB
+            && self.iterations <= MAX_ITERATIONS
+            && self.parallelism <= MAX_PARALLELISM
+    }
+
+    /// The 32-byte Argon2id output for `passphrase`.
+    pub fn stretch(&self, passphrase: &str) -> Result<Zeroizing<[u8; 32]>> {
+        if !self.is_within_limits() {
+            return Err(Error::InvalidInput(
+                "Argon2id parameters above the supported limits".to_string(),
+            ));
+        }
+        let params = Params::new(self.memory, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| Error::InvalidInput(format!("invalid Argon2id parameters: {}", e)))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, key.as_mut())
+            .map_err(|e| Error::InvalidInput(format!("cannot stretch passphrase: {}", e)))?;
+        Ok(key)
+    }
+}
+
+impl Default for PassphraseKdf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
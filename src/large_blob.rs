@@ -0,0 +1,178 @@
+use crate::blob_format::BLOB_MAGIC;
+use crate::error::{Error, Result};
+use aes_gcm::{
+    Aes256Gcm, KeyInit, Nonce,
+    aead::{Aead, Payload},
+};
+use ciborium::Value;
+use flate2::{Compression, read::DeflateDecoder, write::DeflateEncoder};
+use rand::Rng;
+use std::io::{Read, Write};
+use zeroize::Zeroizing;
+
+// Keys of a large-blob map (CTAP 2.1 §6.10.3).
+const KEY_CIPHERTEXT: i64 = 0x01;
+const KEY_NONCE: i64 = 0x02;
+const KEY_ORIG_SIZE: i64 = 0x03;
+
+const NONCE_LEN: usize = 12;
+
+/// The serialized large-blob array (CTAP 2.1 §6.10.3): a CBOR array with one
+/// map per credential, each encrypted under that credential's largeBlobKey.
+///
+/// Elements belonging to other credentials, or that aren't large-blob maps
+/// at all, are kept untouched; only this crate's own legacy element is
+/// dropped once migrated.
+#[derive(Debug, Clone, Default)]
+pub struct LargeBlobArray {
+    elements: Vec<Value>,
+}
+
+impl LargeBlobArray {
+    /// Parses `data`, or returns `None` when it isn't a CBOR array, e.g. blobs
+    /// written raw by older versions of this crate.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match ciborium::from_reader::<Value, _>(data) {
+            Ok(Value::Array(elements)) => Some(Self { elements }),
+            _ => None,
+        }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        ciborium::into_writer(&Value::Array(self.elements.clone()), &mut data)
+            .map_err(|e| Error::InvalidInput(format!("cannot encode largeBlob array: {}", e)))?;
+        Ok(data)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    /// Position and decrypted contents of the element `key` opens.
+    fn find(&self, key: &[u8; 32]) -> Option<(usize, Zeroizing<Vec<u8>>)> {
+        self.elements
+            .iter()
+            .enumerate()
+            .find_map(|(i, element)| decrypt_element(key, element).map(|data| (i, data)))
+    }
+
+    pub fn get(&self, key: &[u8; 32]) -> Option<Zeroizing<Vec<u8>>> {
+        self.find(key).map(|(_, data)| data)
+    }
+
+    /// Stores `data` as the element for `key`, in place if one exists.
+    pub fn set(&mut self, key: &[u8; 32], data: &[u8]) -> Result<()> {
+        let element = encrypt_element(key, data)?;
+        match self.find(key) {
+            Some((position, _)) => self.elements[position] = element,
+            None => self.elements.push(element),
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &[u8; 32]) -> bool {
+        match self.find(key) {
+            Some((position, _)) => {
+                self.elements.remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn legacy_position(&self) -> Option<usize> {
+        self.elements.iter().position(|element| {
+            element
+                .as_bytes()
+                .is_some_and(|data| data.starts_with(BLOB_MAGIC))
+        })
+    }
+
+    /// The container earlier versions of this crate kept in the array as a
+    /// plain byte string, which CTAP 2.1 doesn't allow: every element must
+    /// be an encrypted map. It is only read, to be migrated.
+    pub fn legacy_data(&self) -> Option<&[u8]> {
+        self.legacy_position()
+            .and_then(|position| self.elements[position].as_bytes())
+            .map(Vec::as_slice)
+    }
+
+    pub fn remove_legacy_data(&mut self) {
+        if let Some(position) = self.legacy_position() {
+            self.elements.remove(position);
+        }
+    }
+}
+
+// AAD is "blob" || uint64LE(origSize).
+fn associated_data(orig_size: u64) -> Vec<u8> {
+    let mut aad = b"blob".to_vec();
+    aad.extend_from_slice(&orig_size.to_le_bytes());
+    aad
+}
+
+fn encrypt_element(key: &[u8; 32], data: &[u8]) -> Result<Value> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    let compressed = Zeroizing::new(encoder.finish()?);
+
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rng().fill(&mut nonce);
+
+    let orig_size = data.len() as u64;
+    let ciphertext = Aes256Gcm::new(key.into())
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &compressed,
+                aad: &associated_data(orig_size),
+            },
+        )
+        .map_err(|_| Error::InvalidInput("largeBlob entry too large to encrypt".to_string()))?;
+
+    Ok(Value::Map(vec![
+        (KEY_CIPHERTEXT.into(), Value::Bytes(ciphertext)),
+        (KEY_NONCE.into(), Value::Bytes(nonce.to_vec())),
+        (KEY_ORIG_SIZE.into(), orig_size.into()),
+    ]))
+}
+
+fn map_value(map: &[(Value, Value)], key: i64) -> Option<&Value> {
+    map.iter()
+        .find(|(k, _)| k.as_integer() == Some(key.into()))
+        .map(|(_, v)| v)
+}
+
+fn decrypt_element(key: &[u8; 32], element: &Value) -> Option<Zeroizing<Vec<u8>>> {
+    let map = element.as_map()?;
+    let ciphertext = map_value(map, KEY_CIPHERTEXT)?.as_bytes()?;
+    let nonce = map_value(map, KEY_NONCE)?.as_bytes()?;
+    let orig_size: u64 = map_value(map, KEY_ORIG_SIZE)?
+        .as_integer()?
+        .try_into()
+        .ok()?;
+    if nonce.len() != NONCE_LEN {
+        return None;
+    }
+
+    let compressed = Zeroizing::new(
+        Aes256Gcm::new(key.into())
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &associated_data(orig_size),
+                },
+            )
+            .ok()?,
+    );
+
+    let mut data = Zeroizing::new(Vec::with_capacity(orig_size as usize));
+    DeflateDecoder::new(compressed.as_slice())
+        .take(orig_size)
+        .read_to_end(&mut data)
+        .ok()?;
+
+    (data.len() as u64 == orig_size).then_some(data)
+}
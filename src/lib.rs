@@ -1,15 +1,49 @@
 pub mod auth;
+pub mod authenticator;
+pub mod backup;
+pub mod blob_format;
 pub mod blob_operations;
 pub mod credential;
+pub mod credential_management;
 pub mod device;
 pub mod encryption;
+pub mod error;
+pub mod hid;
+pub mod kdf;
+pub mod large_blob;
+pub mod pin_provider;
+pub mod profile;
+pub mod secret;
+pub mod session;
+pub mod snapshot;
+pub mod soft_authenticator;
+pub mod vault;
 pub mod yubikey_bunker;
 pub mod yubikey_helper;
 
-pub use auth::get_pin_from_user;
-pub use blob_operations::{delete_single_entry, read_blob, write_blob};
+pub use auth::{change_pin, check_pin_retries, is_pin_set, set_pin};
+pub use authenticator::{
+    AssertionRequest, AssertionResponse, Authenticator, AuthenticatorInfo, CtapStatus, Permissions,
+    PinUvAuthToken, ResidentCredential,
+};
+pub use backup::{Backup, BackupEntry};
+pub use blob_format::{Algorithm, EntryRecord, EntryType, WrappedVaultKey};
+pub use blob_operations::{
+    BlobCapacity, BlobStore, EntryCheck, EntryInfo, EntryStatus, PutOptions,
+};
 pub use credential::get_credential_id;
-pub use device::{find_fido_device, is_supported};
-pub use encryption::{decrypt_data, encrypt_data};
+pub use credential_management::{ManagedCredential, delete_credential, list_credentials};
+pub use device::{
+    DeviceInfo, DeviceSelector, FidoDeviceInfo, find_fido_device, get_device_info, is_supported,
+    list_fido_devices,
+};
+pub use encryption::{Cipher, decrypt_data, encrypt_data};
+pub use error::Error;
+pub use hid::HidAuthenticator;
+pub use pin_provider::{PinProvider, pin_provider_from_config};
+pub use profile::{Profile, select_profile};
+pub use secret::Secret;
+pub use session::Session;
+pub use soft_authenticator::SoftAuthenticator;
 pub use yubikey_bunker::YubikeyNostrBunker;
 pub use yubikey_helper::YubikeyKeyManager;
@@ -1,29 +1,91 @@
-mod auth;
-mod blob_operations;
-mod credential;
-mod device;
-mod encryption;
-mod yubikey_bunker;
-mod yubikey_helper;
-
 use anyhow::{Context, Result, anyhow};
+use dialoguer::{Confirm, Password};
 use std::io::{self, Write};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+use yubikey_fido2_teste::auth::{change_pin, is_pin_set, set_pin};
+use yubikey_fido2_teste::credential_management::manage_credentials;
+use yubikey_fido2_teste::session::DEFAULT_SESSION_LIFETIME;
+use yubikey_fido2_teste::snapshot::{snapshot_dir, write_private_file};
+use yubikey_fido2_teste::{
+    Authenticator, Backup, BlobStore, Cipher, DeviceSelector, EntryInfo, EntryStatus, EntryType,
+    Error, Profile, PutOptions, Secret, Session, YubikeyNostrBunker, find_fido_device,
+    get_credential_id, get_device_info, is_supported, pin_provider_from_config, select_profile,
+};
+
+/// Value of `--<flag> X` / `--<flag>=X`, falling back to the `env` variable.
+fn option_from_args(flag: &str, env: &str) -> Result<Option<String>> {
+    let long = format!("--{}", flag);
+    let prefix = format!("{}=", long);
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == long {
+            let value = args
+                .next()
+                .with_context(|| format!("{} requires a value", long))?;
+            return Ok(Some(value));
+        }
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            return Ok(Some(value.to_string()));
+        }
+    }
+
+    match std::env::var(env) {
+        Ok(value) if !value.trim().is_empty() => Ok(Some(value.trim().to_string())),
+        _ => Ok(None),
+    }
+}
+
+fn device_selector_from_args() -> Result<Option<DeviceSelector>> {
+    let selector = option_from_args("device", "FIDO_DEVICE")?
+        .map(|value| value.parse())
+        .transpose()?;
+    Ok(selector)
+}
 
-use blob_operations::{delete_single_entry, read_blob, write_blob};
-use credential::get_credential_id;
-use device::{find_fido_device, is_supported};
-use yubikey_bunker::YubikeyNostrBunker;
+fn session_lifetime_from_env() -> Result<Duration> {
+    match std::env::var("PIN_SESSION_SECONDS") {
+        Ok(value) if !value.trim().is_empty() => {
+            let seconds: u64 = value
+                .trim()
+                .parse()
+                .context("PIN_SESSION_SECONDS must be a number of seconds")?;
+            Ok(Duration::from_secs(seconds))
+        }
+        _ => Ok(DEFAULT_SESSION_LIFETIME),
+    }
+}
+
+fn cipher_from_env() -> Result<Cipher> {
+    match std::env::var("ENTRY_CIPHER") {
+        Ok(value) if !value.trim().is_empty() => Ok(value.parse()?),
+        _ => Ok(Cipher::default()),
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     println!("🔐 YubiKey Nostr Manager\n");
 
+    dotenvy::dotenv().ok();
+    let device_selector = device_selector_from_args()?;
+    let session_lifetime = session_lifetime_from_env()?;
+    let cipher = cipher_from_env()?;
+    let pin_provider = pin_provider_from_config()?;
+    let profile = select_profile(option_from_args("profile", "NOSTR_PROFILE")?.as_deref())?;
+    println!("👤 Profile: {}", profile);
+
+    let new_session = || Session::new(session_lifetime).with_pin_provider(pin_provider.clone());
+
     loop {
         println!("\n📋 Main Menu:");
         println!("1. 🔑 Manage Keys");
         println!("2. 🚀 Start NIP-46 Bunker");
-        println!("3. 🚪 Exit");
-        print!("\nOption (1-3): ");
+        println!("3. ℹ️  Device Info");
+        println!("4. 🚪 Exit");
+        print!("\nOption (1-4): ");
         io::stdout().flush()?;
 
         let mut input = String::new();
@@ -32,16 +94,25 @@ async fn main() -> Result<()> {
 
         match choice {
             "1" => {
-                if let Err(e) = manage_keys().await {
+                if let Err(e) =
+                    manage_keys(device_selector.as_ref(), &profile, new_session(), cipher).await
+                {
                     eprintln!("❌ Error: {}", e);
                 }
             }
             "2" => {
-                if let Err(e) = start_bunker().await {
+                if let Err(e) =
+                    start_bunker(device_selector.as_ref(), &profile, new_session()).await
+                {
                     eprintln!("❌ Error starting bunker: {}", e);
                 }
             }
             "3" => {
+                if let Err(e) = show_device_info(device_selector.as_ref()) {
+                    eprintln!("❌ Error: {}", e);
+                }
+            }
+            "4" => {
                 println!("👋 Exiting...");
                 break;
             }
@@ -54,24 +125,76 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn manage_keys() -> Result<()> {
-    let mut device = find_fido_device().context("No FIDO2 device found.")?;
+fn show_device_info(device_selector: Option<&DeviceSelector>) -> Result<()> {
+    let device = find_fido_device(device_selector).context("No FIDO2 device found.")?;
+    let info = get_device_info(&device)?;
+
+    println!("\n📟 Device Info:");
+    println!("{}", info);
+
+    Ok(())
+}
+
+async fn manage_keys(
+    device_selector: Option<&DeviceSelector>,
+    profile: &Profile,
+    mut session: Session,
+    cipher: Cipher,
+) -> Result<()> {
+    let mut device = find_fido_device(device_selector).context("No FIDO2 device found.")?;
     println!("✅ FIDO2 device connected!");
 
     if !is_supported(&device)? {
         return Err(anyhow!("This device does not support largeBlob."));
     }
 
-    let credential_id =
-        get_credential_id(&mut device).context("Failed to configure credential.")?;
+    if !is_pin_set(&device)? {
+        println!("⚠️  This device has no PIN yet. A PIN is required to store keys.");
+
+        let set_now = Confirm::new()
+            .with_prompt("Set a PIN now?")
+            .default(true)
+            .interact()
+            .unwrap_or(false);
+
+        if !set_now {
+            return Err(anyhow!("A PIN is required to manage keys."));
+        }
+
+        set_pin(&device, session.pin_provider())?;
+        println!("✅ PIN set!");
+    }
+
+    session.unlock(&device)?;
+
+    let credential_id = get_credential_id(&mut device, &mut session, profile)
+        .context("Failed to configure credential.")?;
+
+    {
+        let mut store =
+            BlobStore::new(&mut device, &mut session, profile, &credential_id).with_cipher(cipher);
+        if store.migrate()? {
+            println!("📦 largeBlob migrated to the current format");
+        }
+        if let Err(e) = upgrade_encryption(&mut store) {
+            println!("❌ Error: {}", e);
+        }
+    }
 
     loop {
         println!("\n🔑 Key Management:");
         println!("1. 💾 Store key");
         println!("2. 👀 Read key");
-        println!("3. 🗑️  Delete key");
-        println!("4. ⬅️  Back");
-        print!("\nOption (1-4): ");
+        println!("3. ✏️  Edit key");
+        println!("4. 🗑️  Delete key");
+        println!("5. 🩺 Check largeBlob");
+        println!("6. 🔢 Change PIN");
+        println!("7. 🪪 Manage credentials");
+        println!("8. ⏪ Restore previous largeBlob");
+        println!("9. 🔄 Rotate encryption");
+        println!("10. 💼 Backup");
+        println!("11. ⬅️  Back");
+        print!("\nOption (1-11): ");
         io::stdout().flush()?;
 
         let mut input = String::new();
@@ -79,28 +202,39 @@ async fn manage_keys() -> Result<()> {
         let choice = input.trim();
 
         match choice {
-            "1" => {
-                print!("\n📝 Enter private key (hex): ");
-                io::stdout().flush()?;
-                let mut data_input = String::new();
-                io::stdin().read_line(&mut data_input)?;
-                let data_to_write = data_input.trim();
-
-                if let Err(e) = write_blob(&mut device, &credential_id, data_to_write) {
+            "1" | "2" | "3" | "4" | "5" | "8" | "9" | "10" => {
+                let mut store = BlobStore::new(&mut device, &mut session, profile, &credential_id)
+                    .with_cipher(cipher);
+                let result = match choice {
+                    "1" => store_key(&mut store),
+                    "2" => read_key(&mut store),
+                    "3" => edit_key(&mut store),
+                    "4" => delete_key(&mut store),
+                    "5" => check_blob(&mut store, profile),
+                    "8" => restore_blob(&mut store),
+                    "9" => rotate_keys(&mut store),
+                    _ => backup_menu(&mut store, profile, cipher),
+                };
+                if let Err(e) = result {
                     println!("❌ Error: {}", e);
                 }
             }
-            "2" => {
-                if let Err(e) = read_blob(&mut device, &credential_id) {
-                    println!("❌ Error: {}", e);
+            "6" => match change_pin(&device, session.pin_provider()) {
+                Ok(()) => {
+                    session.lock();
+                    println!("✅ PIN changed! You will be asked for the new PIN next time.");
                 }
-            }
-            "3" => {
-                if let Err(e) = delete_single_entry(&mut device) {
-                    println!("❌ Error: {}", e);
+                Err(e) => println!("❌ Error: {}", e),
+            },
+            "7" => match manage_credentials(&mut device, &mut session, profile, &credential_id) {
+                Ok(true) => {
+                    println!("⚠️  The credential in use was deleted. Returning to the main menu.");
+                    break;
                 }
-            }
-            "4" => {
+                Ok(false) => {}
+                Err(e) => println!("❌ Error: {}", e),
+            },
+            "11" => {
                 break;
             }
             _ => {
@@ -112,7 +246,651 @@ async fn manage_keys() -> Result<()> {
     Ok(())
 }
 
-async fn start_bunker() -> Result<()> {
+fn upgrade_encryption<A: Authenticator>(store: &mut BlobStore<'_, A>) -> Result<()> {
+    let entries = store.list()?;
+    let pending = entries.iter().filter(|entry| entry.needs_upgrade).count();
+    if pending == 0 {
+        return Ok(());
+    }
+    let outdated = entries.iter().filter(|entry| entry.outdated).count();
+
+    println!(
+        "\n🔐 {} entries are encrypted the way older versions did, carried over from the legacy data or missing their public key.",
+        pending
+    );
+    let prompt = if outdated > 0 {
+        "Upgrade them now? (one touch per entry outside the vault, plus one)"
+    } else {
+        "Upgrade them now? (one touch to unlock the vault)"
+    };
+    let confirmed = Confirm::new()
+        .with_prompt(prompt)
+        .default(true)
+        .interact()
+        .unwrap_or(false);
+    if !confirmed {
+        return Ok(());
+    }
+
+    let upgraded = store.upgrade_encryption()?;
+    println!("✓ {} entries upgraded.", upgraded);
+    if upgraded < pending {
+        println!(
+            "   {} left as they were: they belong to another profile or fail the check.",
+            pending - upgraded
+        );
+    }
+    Ok(())
+}
+
+fn read_line(prompt: &str) -> Result<String> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_string())
+}
+
+fn display_entries(entries: &[EntryInfo]) {
+    println!("\nExisting entries:");
+    for entry in entries {
+        println!("{}: {}", entry.index + 1, entry);
+    }
+}
+
+/// Index of the entry the user picks, or `None` on 0 or an invalid number.
+fn choose_entry(entries: &[EntryInfo], prompt: &str) -> Result<Option<usize>> {
+    display_entries(entries);
+    let choice: usize = read_line(prompt)?.parse().unwrap_or(0);
+    Ok(choice.checked_sub(1).filter(|index| *index < entries.len()))
+}
+
+fn choose_entry_type() -> Result<Option<EntryType>> {
+    println!("\nEntry type:");
+    for (position, entry_type) in EntryType::ALL.iter().enumerate() {
+        println!("{}. {}", position + 1, entry_type);
+    }
+    let choice: usize = read_line(&format!("Option (1-{}): ", EntryType::ALL.len()))?
+        .parse()
+        .unwrap_or(0);
+    Ok(choice
+        .checked_sub(1)
+        .and_then(|index| EntryType::ALL.get(index).copied()))
+}
+
+fn read_secret(entry_type: EntryType) -> Result<Secret> {
+    let prompt = match entry_type {
+        EntryType::NostrSecretKey => "\n📝 Enter private key (hex or nsec): ",
+        EntryType::Ncryptsec => "\n📝 Enter ncryptsec: ",
+        EntryType::Mnemonic => "\n📝 Enter mnemonic words: ",
+        EntryType::NostrWalletConnect => "\n📝 Enter NWC URI (nostr+walletconnect://...): ",
+        EntryType::Opaque => "\n📝 Enter secret: ",
+    };
+    let input = Zeroizing::new(read_line(prompt)?);
+    Ok(Secret::parse(entry_type, &input)?)
+}
+
+fn read_new_passphrase() -> Result<Zeroizing<String>> {
+    let passphrase = Password::new()
+        .with_prompt("New passphrase")
+        .with_confirmation("Repeat passphrase", "Passphrases don't match.")
+        .interact()?;
+    Ok(Zeroizing::new(passphrase))
+}
+
+fn store_key<A: Authenticator>(store: &mut BlobStore<'_, A>) -> Result<()> {
+    let Some(entry_type) = choose_entry_type()? else {
+        println!("Cancelled.");
+        return Ok(());
+    };
+    let key = read_secret(entry_type)?;
+    let id = read_line("Enter an ID for this entry: ")?;
+    let petname = read_line("Petname (optional): ")?;
+    let passphrase = if Confirm::new()
+        .with_prompt("Also protect it with a passphrase? (asked every time it is used)")
+        .default(false)
+        .interact()
+        .unwrap_or(false)
+    {
+        Some(read_new_passphrase()?)
+    } else {
+        None
+    };
+
+    let mut overwrite = false;
+    loop {
+        let result = store.put_with(
+            &id,
+            &key,
+            &PutOptions {
+                passphrase: passphrase.as_deref().map(String::as_str),
+                petname: Some(&petname),
+                overwrite,
+            },
+        );
+        match result {
+            Ok(()) => break,
+            Err(Error::EntryExists) => {
+                overwrite = Confirm::new()
+                    .with_prompt(format!("An entry '{}' already exists. Overwrite it?", id))
+                    .default(false)
+                    .interact()
+                    .unwrap_or(false);
+                if !overwrite {
+                    println!("Cancelled.");
+                    return Ok(());
+                }
+            }
+            Err(Error::BlobFull { needed, capacity }) => {
+                println!("Insufficient space ({}/{} bytes).", needed, capacity);
+                let entries = store.list()?;
+                if entries.is_empty() {
+                    return Err(Error::BlobFull { needed, capacity }.into());
+                }
+                match choose_entry(
+                    &entries,
+                    "Enter the entry number to remove (or 0 to cancel): ",
+                )? {
+                    Some(index) => {
+                        store.delete_at(index)?;
+                        println!("Entry {} removed.", index + 1);
+                    }
+                    None => {
+                        println!("Cancelled.");
+                        return Ok(());
+                    }
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    println!("✓ Data stored successfully!");
+    Ok(())
+}
+
+fn read_key<A: Authenticator>(store: &mut BlobStore<'_, A>) -> Result<()> {
+    let entries = store.list()?;
+    if entries.is_empty() {
+        println!("No entries.");
+        return Ok(());
+    }
+
+    let Some(index) = choose_entry(
+        &entries,
+        "\nEnter the number of the entry to decrypt (or 0 to cancel): ",
+    )?
+    else {
+        return Ok(());
+    };
+
+    match store.get_at(index) {
+        Ok(secret) => println!("Decrypted {}: {}", secret.entry_type(), secret),
+        Err(Error::EntryCorrupted) => println!("Corrupted data in entry {}", index + 1),
+        Err(Error::PublicKeyMismatch) => println!(
+            "⚠️  Entry {} does not match its recorded public key; it may have been tampered with.",
+            index + 1
+        ),
+        Err(_) => println!("Decryption error for entry {}", index + 1),
+    }
+    Ok(())
+}
+
+fn edit_key<A: Authenticator>(store: &mut BlobStore<'_, A>) -> Result<()> {
+    let entries = store.list()?;
+    if entries.is_empty() {
+        println!("No entries.");
+        return Ok(());
+    }
+
+    let Some(index) = choose_entry(
+        &entries,
+        "Enter the number of the entry to edit (or 0 to cancel): ",
+    )?
+    else {
+        return Ok(());
+    };
+    let entry = &entries[index];
+
+    println!("\n✏️  Editing {}:", entry);
+    println!("1. Rename");
+    println!("2. Replace secret");
+    println!("3. Move");
+    println!("4. Set petname");
+    if entry.default {
+        println!("5. Unset as default");
+    } else {
+        println!("5. Set as default");
+    }
+    println!("6. Change cipher (now {})", entry.algorithm.cipher());
+    if entry.passphrase {
+        println!("7. Change or remove passphrase");
+    } else {
+        println!("7. Set passphrase");
+    }
+    println!("8. Cancel");
+
+    match read_line("\nOption (1-8): ")?.as_str() {
+        "1" => {
+            let new_id = read_line("New ID: ")?;
+            match store.rename(&entry.id, &new_id) {
+                Ok(()) => println!("✓ Entry renamed!"),
+                Err(Error::EntryExists) => println!("An entry '{}' already exists.", new_id),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        "2" => {
+            let key = read_secret(entry.entry_type)?;
+            let confirmed = Confirm::new()
+                .with_prompt(format!("Replace the secret stored in '{}'?", entry.id))
+                .default(false)
+                .interact()
+                .unwrap_or(false);
+            if !confirmed {
+                println!("Cancelled.");
+                return Ok(());
+            }
+            store.replace(&entry.id, &key)?;
+            println!("✓ Secret replaced!");
+        }
+        "3" => {
+            let position: usize = read_line(&format!("New position (1-{}): ", entries.len()))?
+                .parse()
+                .unwrap_or(0);
+            if position == 0 || position > entries.len() {
+                println!("Cancelled.");
+                return Ok(());
+            }
+            store.move_entry(&entry.id, position - 1)?;
+            println!("✓ Entry moved!");
+        }
+        "4" => {
+            let petname = read_line("Petname (empty to clear): ")?;
+            store.set_petname(&entry.id, Some(&petname))?;
+            println!("✓ Petname updated!");
+        }
+        "5" if entry.default => {
+            store.set_default(None)?;
+            println!("✓ No default entry.");
+        }
+        "5" => {
+            store.set_default(Some(&entry.id))?;
+            println!("✓ The bunker will use '{}' by default.", entry.id);
+        }
+        "6" => {
+            println!("\nCipher:");
+            for (position, cipher) in Cipher::ALL.iter().enumerate() {
+                println!("{}. {}", position + 1, cipher);
+            }
+            let choice: usize = read_line(&format!("Option (1-{}): ", Cipher::ALL.len()))?
+                .parse()
+                .unwrap_or(0);
+            let Some(cipher) = choice
+                .checked_sub(1)
+                .and_then(|index| Cipher::ALL.get(index).copied())
+            else {
+                println!("Cancelled.");
+                return Ok(());
+            };
+            store.change_cipher(&entry.id, cipher)?;
+            println!("✓ Entry re-encrypted with {}!", cipher);
+        }
+        "7" => {
+            let remove = entry.passphrase
+                && Confirm::new()
+                    .with_prompt("Remove the passphrase? (no to change it)")
+                    .default(false)
+                    .interact()
+                    .unwrap_or(false);
+            let passphrase = if remove {
+                None
+            } else {
+                Some(read_new_passphrase()?)
+            };
+            store.set_passphrase(&entry.id, passphrase.as_deref().map(String::as_str))?;
+            if remove {
+                println!("✓ Passphrase removed.");
+            } else {
+                println!("✓ Passphrase set. It will be asked for every time the entry is used.");
+            }
+        }
+        _ => println!("Cancelled."),
+    }
+    Ok(())
+}
+
+fn delete_key<A: Authenticator>(store: &mut BlobStore<'_, A>) -> Result<()> {
+    let entries = store.list()?;
+    if entries.is_empty() {
+        println!("No entries.");
+        return Ok(());
+    }
+
+    let Some(index) = choose_entry(
+        &entries,
+        "Enter the number of the entry to delete (or 0 to cancel): ",
+    )?
+    else {
+        println!("Cancelled.");
+        return Ok(());
+    };
+
+    store.delete_at(index)?;
+    if entries.len() == 1 {
+        println!("✓ LargeBlob cleared!");
+    } else {
+        println!("✓ Entry deleted!");
+    }
+    Ok(())
+}
+
+fn check_blob<A: Authenticator>(store: &mut BlobStore<'_, A>, profile: &Profile) -> Result<()> {
+    let entries = store.list()?;
+    let count = entries.len();
+    if count == 0 {
+        println!("No entries.");
+        return Ok(());
+    }
+
+    let outdated = entries.iter().filter(|entry| entry.outdated).count();
+    println!(
+        "\n🔍 Checking {} entries (touch the key once to unlock the vault{})...",
+        count,
+        if outdated > 0 {
+            format!(", and once for each of the {} outside it", outdated)
+        } else {
+            String::new()
+        }
+    );
+    let checks = store.check()?;
+
+    println!("\nResults:");
+    for check in &checks {
+        let icon = match check.status {
+            EntryStatus::Ok => "✅",
+            EntryStatus::Undecryptable => "🔒",
+            EntryStatus::PassphraseProtected => "🔑",
+            _ => "⚠️ ",
+        };
+        println!(
+            "{}: {} {} — {}",
+            check.entry.index + 1,
+            icon,
+            check.entry,
+            check.status
+        );
+        if check.status == EntryStatus::Undecryptable && check.entry.shared {
+            println!("      (legacy entry; it may belong to another profile)");
+        }
+    }
+
+    let problems: Vec<_> = checks
+        .iter()
+        .filter(|check| !check.status.is_ok())
+        .collect();
+    if problems.is_empty() {
+        println!("\n✓ All entries are fine.");
+        return Ok(());
+    }
+
+    let choice: usize = read_line(&format!(
+        "\n{} entries need attention. Enter the number of one to repair (or 0 to finish): ",
+        problems.len()
+    ))?
+    .parse()
+    .unwrap_or(0);
+    let Some(check) = problems
+        .iter()
+        .find(|check| check.entry.index + 1 == choice)
+    else {
+        return Ok(());
+    };
+    let index = check.entry.index;
+
+    println!("\n🛠️  Repair {}:", check.entry);
+    println!("1. Drop the entry");
+    println!("2. Re-encode it (decrypt and store it again)");
+    println!("3. Export it raw");
+    println!("4. Cancel");
+
+    match read_line("\nOption (1-4): ")?.as_str() {
+        "1" => {
+            let confirmed = Confirm::new()
+                .with_prompt(format!("Drop entry {}? This cannot be undone.", index + 1))
+                .default(false)
+                .interact()
+                .unwrap_or(false);
+            if !confirmed {
+                println!("Cancelled.");
+                return Ok(());
+            }
+            store.delete_at(index)?;
+            println!("✓ Entry dropped!");
+        }
+        "2" => {
+            let id = store.reencode_at(index)?;
+            println!("✓ Entry re-encoded as '{}'!", id);
+        }
+        "3" => {
+            let default_path = snapshot_dir().join(format!(
+                "{}-{}.entry",
+                profile.name,
+                file_name_safe(&check.entry.id)
+            ));
+            let path = read_line(&format!("Export to [{}]: ", default_path.display()))?;
+            let path = if path.is_empty() {
+                default_path
+            } else {
+                path.into()
+            };
+            if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&path, store.export_raw_at(index)?)?;
+            println!("✓ Entry exported to {}", path.display());
+        }
+        _ => {
+            println!("Cancelled.");
+            return Ok(());
+        }
+    }
+
+    println!("Run the check again to review the remaining entries.");
+    Ok(())
+}
+
+fn file_name_safe(id: &str) -> String {
+    id.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn restore_blob<A: Authenticator>(store: &mut BlobStore<'_, A>) -> Result<()> {
+    println!(
+        "\nThe snapshot in {} holds the largeBlob as it was before this credential's last write.",
+        store.snapshot_path().display()
+    );
+
+    let confirmed = Confirm::new()
+        .with_prompt("Replace the current largeBlob with it?")
+        .default(false)
+        .interact()
+        .unwrap_or(false);
+
+    if !confirmed {
+        println!("Cancelled.");
+        return Ok(());
+    }
+
+    store.restore_snapshot()?;
+    println!("✓ largeBlob restored! Restoring again undoes this.");
+    Ok(())
+}
+
+fn rotate_keys<A: Authenticator>(store: &mut BlobStore<'_, A>) -> Result<()> {
+    let entries = store.list()?;
+    if entries.iter().all(|entry| entry.shared) {
+        println!("No entries of this credential to rotate.");
+        return Ok(());
+    }
+
+    println!("\n🔄 Every entry will be encrypted again under a new vault key and salt.");
+    let confirmed = Confirm::new()
+        .with_prompt("Rotate now? (one touch, plus one per entry outside the vault)")
+        .default(true)
+        .interact()
+        .unwrap_or(false);
+
+    if !confirmed {
+        println!("Cancelled.");
+        return Ok(());
+    }
+
+    let rotated = store.rotate()?;
+    println!("✓ {} entries rotated!", rotated);
+    Ok(())
+}
+
+fn backup_menu<A: Authenticator>(
+    store: &mut BlobStore<'_, A>,
+    profile: &Profile,
+    cipher: Cipher,
+) -> Result<()> {
+    println!("\n💼 Backup:");
+    println!("1. Export all entries to a file");
+    println!("2. Import a backup");
+    println!("3. Verify a backup");
+    println!("4. Cancel");
+
+    match read_line("\nOption (1-4): ")?.as_str() {
+        "1" => {
+            let default_path = snapshot_dir().join(format!("{}.backup", profile.name));
+            let path = read_line(&format!("Export to [{}]: ", default_path.display()))?;
+            let path = if path.is_empty() {
+                default_path
+            } else {
+                path.into()
+            };
+            if path.exists()
+                && !Confirm::new()
+                    .with_prompt(format!("{} exists. Replace it?", path.display()))
+                    .default(false)
+                    .interact()
+                    .unwrap_or(false)
+            {
+                println!("Cancelled.");
+                return Ok(());
+            }
+
+            println!("\n🔓 Decrypting every entry (touch the key when it blinks)...");
+            let backup = store.export_backup()?;
+            println!("Choose the passphrase the backup is encrypted with.");
+            let passphrase = read_new_passphrase()?;
+            write_private_file(&path, &backup.seal(&passphrase, cipher)?)?;
+            println!(
+                "✓ {} entries backed up to {}. Keep the file and its passphrase apart.",
+                backup.entries.len(),
+                path.display()
+            );
+        }
+        "2" => {
+            let Some(backup) = read_backup()? else {
+                return Ok(());
+            };
+            let existing: Vec<_> = store.list()?.into_iter().map(|entry| entry.id).collect();
+            let taken = backup
+                .entries
+                .iter()
+                .filter(|entry| existing.contains(&entry.id))
+                .count();
+            let overwrite = taken > 0
+                && Confirm::new()
+                    .with_prompt(format!(
+                        "{} entries of the backup have IDs already in use. Overwrite them?",
+                        taken
+                    ))
+                    .default(false)
+                    .interact()
+                    .unwrap_or(false);
+
+            let restored = store.import_backup(&backup, overwrite)?;
+            println!("✓ {} entries restored!", restored);
+            if restored < backup.entries.len() {
+                println!(
+                    "   {} skipped: their IDs are already in use.",
+                    backup.entries.len() - restored
+                );
+            }
+        }
+        "3" => {
+            let Some(backup) = read_backup()? else {
+                return Ok(());
+            };
+            let checks = backup.verify();
+            for (index, (entry, status)) in checks.iter().enumerate() {
+                let icon = if *status == EntryStatus::Ok {
+                    "✅"
+                } else {
+                    "⚠️ "
+                };
+                println!(
+                    "{}: {} {} ({}) — {}",
+                    index + 1,
+                    icon,
+                    entry.id,
+                    entry.entry_type,
+                    status
+                );
+            }
+            if checks.iter().all(|(_, status)| status.is_ok()) {
+                println!("\n✓ All {} entries can be restored.", checks.len());
+            }
+        }
+        _ => println!("Cancelled."),
+    }
+
+    Ok(())
+}
+
+/// Asks for a backup file and its passphrase, and decrypts it. `None` if the
+/// file can't be read or the passphrase is wrong, after saying so.
+fn read_backup() -> Result<Option<Backup>> {
+    let path = read_line("Backup file: ")?;
+    let data = std::fs::read(&path).with_context(|| format!("Cannot read {}", path))?;
+    let passphrase = Zeroizing::new(
+        Password::new()
+            .with_prompt("Backup passphrase")
+            .interact()?,
+    );
+
+    match Backup::open(&data, &passphrase) {
+        Ok(backup) => {
+            println!(
+                "📦 Backup of {} entries, made {}.",
+                backup.entries.len(),
+                nostr::Timestamp::from_secs(backup.created_at).to_human_datetime()
+            );
+            Ok(Some(backup))
+        }
+        Err(Error::DecryptFailed) => {
+            println!("❌ Wrong passphrase, or the file was changed after it was written.");
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn start_bunker(
+    device_selector: Option<&DeviceSelector>,
+    profile: &Profile,
+    session: Session,
+) -> Result<()> {
     println!("\n🚀 Starting NIP-46 Bunker...\n");
 
     dotenvy::dotenv().context(".env file not found")?;
@@ -135,9 +913,22 @@ async fn start_bunker() -> Result<()> {
     }
     println!();
 
-    let secret = Some("yubikey-secure-token".to_string());
+    // The bunker's key is the same every run, so the connect secret is what
+    // keeps an old or leaked URI from pairing new clients after a restart.
+    let secret = Some(hex::encode(rand::random::<[u8; 16]>()));
+    let entry_id = std::env::var("NOSTR_KEY_ID")
+        .ok()
+        .filter(|id| !id.trim().is_empty());
 
-    let bunker = YubikeyNostrBunker::new(relays, secret).context("Failed to initialize bunker")?;
+    let bunker = YubikeyNostrBunker::new(
+        relays,
+        secret,
+        device_selector,
+        profile.clone(),
+        session,
+        entry_id.as_deref(),
+    )
+    .context("Failed to initialize bunker")?;
 
     println!("💡 Share the URI above with Nostr apps");
     println!("🔒 Key loaded on-demand for each operation");
@@ -0,0 +1,307 @@
+use crate::error::{Error, Result};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+pub const DEFAULT_PIN_ENV_VAR: &str = "YUBIKEY_PIN";
+
+const DEFAULT_PINENTRY: &str = "pinentry";
+
+// gpg-error code in the low 16 bits of an Assuan ERR, whatever its source.
+const GPG_ERR_CANCELED: u32 = 99;
+
+/// A source of PINs.
+///
+/// Selected with `PIN_PROVIDER`; see [`pin_provider_from_config`].
+pub trait PinProvider: Send + Sync {
+    fn get_pin(&self, prompt: &str) -> Result<Zeroizing<String>>;
+
+    /// Whether a person answers each request. Non-interactive sources return
+    /// the same PIN every time, so a rejected PIN must not be retried.
+    fn is_interactive(&self) -> bool {
+        true
+    }
+}
+
+fn not_utf8() -> Error {
+    Error::PinSource("PIN is not valid UTF-8".to_string())
+}
+
+/// Reads the PIN from the terminal without echo.
+pub struct TtyPinProvider;
+
+impl PinProvider for TtyPinProvider {
+    fn get_pin(&self, prompt: &str) -> Result<Zeroizing<String>> {
+        print!("{}: ", prompt);
+        io::stdout().flush()?;
+        let pin = rpassword::read_password()?;
+        Ok(Zeroizing::new(pin))
+    }
+}
+
+/// Asks a pinentry program over the Assuan protocol.
+pub struct PinentryProvider {
+    program: String,
+}
+
+impl PinentryProvider {
+    pub fn new(program: impl Into<String>) -> Self {
+        Self {
+            program: program.into(),
+        }
+    }
+}
+
+impl Default for PinentryProvider {
+    fn default() -> Self {
+        Self::new(DEFAULT_PINENTRY)
+    }
+}
+
+// Assuan escapes '%', CR and LF in parameters as %XX.
+fn assuan_escape(text: &str) -> String {
+    text.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn assuan_unescape(data: &str) -> Result<Zeroizing<String>> {
+    let bytes = data.as_bytes();
+    let mut decoded = Zeroizing::new(Vec::with_capacity(bytes.len()));
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let byte = std::str::from_utf8(&bytes[i + 1..i + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| Error::PinSource("invalid Assuan escape".to_string()))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    let pin = String::from_utf8(decoded.to_vec()).map_err(|_| not_utf8())?;
+    Ok(Zeroizing::new(pin))
+}
+
+struct Assuan<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: BufRead, W: Write> Assuan<R, W> {
+    /// Reads until `OK`, returning the data lines. `ERR` becomes an error.
+    fn read_response(&mut self) -> Result<Zeroizing<String>> {
+        let mut data = Zeroizing::new(String::new());
+        loop {
+            let mut line = Zeroizing::new(String::new());
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(Error::PinSource(
+                    "pinentry closed the connection".to_string(),
+                ));
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            if line == "OK" || line.starts_with("OK ") {
+                return Ok(data);
+            }
+            if let Some(error) = line.strip_prefix("ERR ") {
+                let code = error.split(' ').next().and_then(|c| c.parse::<u32>().ok());
+                if code.is_some_and(|c| c & 0xFFFF == GPG_ERR_CANCELED) {
+                    return Err(Error::Cancelled);
+                }
+                return Err(Error::PinSource(format!("pinentry: {}", error)));
+            }
+            if let Some(chunk) = line.strip_prefix("D ") {
+                data.push_str(chunk);
+            }
+            // Status (S) and comment (#) lines are ignored.
+        }
+    }
+
+    fn command(&mut self, command: &str) -> Result<Zeroizing<String>> {
+        writeln!(self.writer, "{}", command)?;
+        self.writer.flush()?;
+        self.read_response()
+    }
+}
+
+impl PinProvider for PinentryProvider {
+    fn get_pin(&self, prompt: &str) -> Result<Zeroizing<String>> {
+        let mut child = Command::new(&self.program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::PinSource(format!("failed to start {}: {}", self.program, e)))?;
+
+        let result = (|| {
+            let (Some(stdout), Some(stdin)) = (child.stdout.take(), child.stdin.take()) else {
+                return Err(Error::PinSource("pinentry has no stdio".to_string()));
+            };
+            let mut assuan = Assuan {
+                reader: BufReader::new(stdout),
+                writer: stdin,
+            };
+
+            assuan.read_response()?;
+            if let Ok(tty) = std::env::var("GPG_TTY") {
+                assuan.command(&format!("OPTION ttyname={}", tty))?;
+            }
+            assuan.command("SETTITLE YubiKey Nostr Signer")?;
+            assuan.command(&format!("SETDESC {}", assuan_escape(prompt)))?;
+            assuan.command("SETPROMPT PIN:")?;
+
+            let pin = assuan_unescape(&assuan.command("GETPIN")?)?;
+            let _ = assuan.command("BYE");
+            Ok(pin)
+        })();
+
+        let _ = child.wait();
+        result
+    }
+}
+
+/// Reads one line per request from a file descriptor or named pipe.
+pub struct FilePinProvider {
+    path: PathBuf,
+}
+
+impl FilePinProvider {
+    pub fn from_fd(fd: u32) -> Self {
+        Self::from_path(format!("/dev/fd/{}", fd))
+    }
+
+    pub fn from_path(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl PinProvider for FilePinProvider {
+    fn get_pin(&self, _prompt: &str) -> Result<Zeroizing<String>> {
+        let mut file = File::open(&self.path).map_err(|e| {
+            Error::PinSource(format!("failed to open {}: {}", self.path.display(), e))
+        })?;
+
+        // Byte by byte, so PINs queued on a pipe for later requests stay unread.
+        let mut line = Zeroizing::new(Vec::new());
+        let mut byte = [0u8; 1];
+        while file.read(&mut byte)? == 1 && byte[0] != b'\n' {
+            line.push(byte[0]);
+        }
+
+        let pin = std::str::from_utf8(&line).map_err(|_| not_utf8())?;
+        let pin = pin.trim_end_matches('\r');
+        if pin.is_empty() {
+            return Err(Error::PinSource(format!(
+                "no PIN available from {}",
+                self.path.display()
+            )));
+        }
+
+        Ok(Zeroizing::new(pin.to_string()))
+    }
+
+    fn is_interactive(&self) -> bool {
+        false
+    }
+}
+
+/// Asks through `systemd-ask-password`, which reaches agents such as the
+/// boot splash or `systemd-tty-ask-password-agent` when running as a service.
+pub struct SystemdAskPasswordProvider;
+
+impl PinProvider for SystemdAskPasswordProvider {
+    fn get_pin(&self, prompt: &str) -> Result<Zeroizing<String>> {
+        let output = Command::new("systemd-ask-password")
+            .arg("--id=yubikey-nostr-signer")
+            .arg(format!("{}:", prompt))
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| Error::PinSource(format!("failed to run systemd-ask-password: {}", e)))?;
+
+        let stdout = Zeroizing::new(output.stdout);
+        if !output.status.success() {
+            return Err(Error::Cancelled);
+        }
+
+        let pin = std::str::from_utf8(&stdout).map_err(|_| not_utf8())?;
+        Ok(Zeroizing::new(
+            pin.trim_end_matches(['\r', '\n']).to_string(),
+        ))
+    }
+}
+
+/// Takes the PIN from an environment variable. Meant for tests only.
+pub struct EnvPinProvider {
+    var: String,
+}
+
+impl EnvPinProvider {
+    pub fn new(var: impl Into<String>) -> Self {
+        Self { var: var.into() }
+    }
+}
+
+impl Default for EnvPinProvider {
+    fn default() -> Self {
+        Self::new(DEFAULT_PIN_ENV_VAR)
+    }
+}
+
+impl PinProvider for EnvPinProvider {
+    fn get_pin(&self, _prompt: &str) -> Result<Zeroizing<String>> {
+        std::env::var(&self.var)
+            .map(Zeroizing::new)
+            .map_err(|_| Error::PinSource(format!("{} is not set", self.var)))
+    }
+
+    fn is_interactive(&self) -> bool {
+        false
+    }
+}
+
+/// Parses a `PIN_PROVIDER` value: `tty`, `pinentry[:<program>]`, `fd:<n>`,
+/// `pipe:<path>`, `systemd` or `env[:<VAR>]`.
+pub fn parse_pin_provider(spec: &str) -> Result<Arc<dyn PinProvider>> {
+    let spec = spec.trim();
+    let (kind, arg) = match spec.split_once(':') {
+        Some((kind, arg)) => (kind, Some(arg)),
+        None => (spec, None),
+    };
+
+    let provider: Arc<dyn PinProvider> = match (kind, arg) {
+        ("tty", None) => Arc::new(TtyPinProvider),
+        ("pinentry", None) => Arc::new(PinentryProvider::default()),
+        ("pinentry", Some(program)) => Arc::new(PinentryProvider::new(program)),
+        ("fd", Some(fd)) => Arc::new(FilePinProvider::from_fd(fd.parse().map_err(|_| {
+            Error::InvalidInput("fd: needs a file descriptor number".to_string())
+        })?)),
+        ("pipe", Some(path)) => Arc::new(FilePinProvider::from_path(path)),
+        ("systemd", None) => Arc::new(SystemdAskPasswordProvider),
+        ("env", None) => Arc::new(EnvPinProvider::default()),
+        ("env", Some(var)) => Arc::new(EnvPinProvider::new(var)),
+        _ => {
+            return Err(Error::InvalidInput(format!(
+                "unknown PIN_PROVIDER '{}'",
+                spec
+            )));
+        }
+    };
+
+    Ok(provider)
+}
+
+/// The provider named by `PIN_PROVIDER`, or the terminal when unset.
+pub fn pin_provider_from_config() -> Result<Arc<dyn PinProvider>> {
+    match std::env::var("PIN_PROVIDER") {
+        Ok(spec) if !spec.trim().is_empty() => parse_pin_provider(&spec),
+        _ => Ok(Arc::new(TtyPinProvider)),
+    }
+}
@@ -0,0 +1,172 @@
+use crate::error::{Error, Result};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::{self, Write};
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+const DEFAULT_RP_ID: &str = "nostr.bunker.yubikey";
+
+/// Relying party and user entity the bunker's credential is created under.
+///
+/// Each profile gets its own resident credential, so several people or
+/// purposes can share one authenticator without sharing keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub name: String,
+    pub rp_id: String,
+    pub user_id: Vec<u8>,
+    pub user_name: String,
+    pub user_display_name: String,
+}
+
+impl Default for Profile {
+    /// The entity used before profiles existed, so existing credentials keep working.
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_PROFILE.to_string(),
+            rp_id: DEFAULT_RP_ID.to_string(),
+            user_id: b"user-id-for-large-blob".to_vec(),
+            user_name: "test.user".to_string(),
+            user_display_name: "Test User".to_string(),
+        }
+    }
+}
+
+impl Profile {
+    /// A profile named `name` under `<name>.nostr.bunker.yubikey`.
+    pub fn new(name: &str) -> Result<Self> {
+        validate_name(name)?;
+
+        if name == DEFAULT_PROFILE {
+            return Ok(Self::default());
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            rp_id: format!("{}.{}", name, DEFAULT_RP_ID),
+            user_id: user_id_for(name),
+            user_name: name.to_string(),
+            user_display_name: name.to_string(),
+        })
+    }
+
+    /// Reads `PROFILE_<NAME>_RP_ID`, `PROFILE_<NAME>_USER_NAME` and
+    /// `PROFILE_<NAME>_DISPLAY_NAME`, falling back to [`Profile::new`].
+    pub fn from_env(name: &str) -> Result<Self> {
+        let mut profile = Self::new(name)?;
+        let prefix = format!("PROFILE_{}", name.to_uppercase().replace('-', "_"));
+
+        if let Some(rp_id) = env_value(&format!("{}_RP_ID", prefix)) {
+            profile.rp_id = rp_id;
+        }
+        if let Some(user_name) = env_value(&format!("{}_USER_NAME", prefix)) {
+            profile.user_id = user_id_for(&user_name);
+            profile.user_display_name = user_name.clone();
+            profile.user_name = user_name;
+        }
+        if let Some(display_name) = env_value(&format!("{}_DISPLAY_NAME", prefix)) {
+            profile.user_display_name = display_name;
+        }
+
+        Ok(profile)
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} | rp {} | user {} ({})",
+            self.name, self.rp_id, self.user_name, self.user_display_name
+        )
+    }
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+
+    if !valid {
+        return Err(Error::InvalidInput(format!(
+            "profile name '{}': use lowercase letters, digits and '-'",
+            name
+        )));
+    }
+
+    Ok(())
+}
+
+// user.id must be at most 64 bytes and carry no personal data (WebAuthn §5.4.3).
+fn user_id_for(user_name: &str) -> Vec<u8> {
+    Sha256::digest(user_name.as_bytes()).to_vec()
+}
+
+fn env_value(key: &str) -> Option<String> {
+    std::env::var(key)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// The default profile followed by every name listed in `NOSTR_PROFILES`.
+pub fn load_profiles() -> Result<Vec<Profile>> {
+    let mut profiles = vec![Profile::default()];
+
+    if let Some(names) = env_value("NOSTR_PROFILES") {
+        for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            if profiles.iter().any(|p| p.name == name) {
+                continue;
+            }
+            let profile = Profile::from_env(name).map_err(|e| match e {
+                Error::InvalidInput(message) => {
+                    Error::InvalidInput(format!("NOSTR_PROFILES: {}", message))
+                }
+                e => e,
+            })?;
+            profiles.push(profile);
+        }
+    }
+
+    Ok(profiles)
+}
+
+pub fn choose_profile(profiles: &[Profile]) -> Result<&Profile> {
+    println!("\n👤 Profiles:");
+    for (i, profile) in profiles.iter().enumerate() {
+        println!("   {}. {}", i + 1, profile);
+    }
+
+    print!("\n👤 Choose profile (1-{}): ", profiles.len());
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    input
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|choice| choice.checked_sub(1))
+        .and_then(|i| profiles.get(i))
+        .ok_or_else(|| Error::InvalidInput("invalid choice".to_string()))
+}
+
+/// Picks the profile named `name`, or asks when several are configured.
+pub fn select_profile(name: Option<&str>) -> Result<Profile> {
+    let profiles = load_profiles()?;
+
+    let profile = match name {
+        Some(name) => profiles.iter().find(|p| p.name == name).ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "unknown profile '{}'; add it to NOSTR_PROFILES",
+                name
+            ))
+        })?,
+        None if profiles.len() == 1 => &profiles[0],
+        None => choose_profile(&profiles)?,
+    };
+
+    Ok(profile.clone())
+}
@@ -0,0 +1,132 @@
+use crate::blob_format::EntryType;
+use crate::error::{Error, Result};
+use nostr::nips::nip06::FromMnemonic;
+use nostr::nips::nip47::NostrWalletConnectURI;
+use nostr::nips::nip49::EncryptedSecretKey;
+use nostr::{FromBech32, Keys, PublicKey, SecretKey, ToBech32};
+use std::fmt;
+use zeroize::Zeroizing;
+
+/// A validated entry secret, held in the form its type is stored in:
+/// 32 raw bytes for a Nostr secret key, UTF-8 text for the textual types
+/// and arbitrary bytes for opaque secrets.
+pub struct Secret {
+    entry_type: EntryType,
+    data: Zeroizing<Vec<u8>>,
+}
+
+fn invalid(entry_type: EntryType) -> Error {
+    Error::InvalidInput(format!("not a valid {}", entry_type))
+}
+
+impl Secret {
+    /// Validates `input` as entered by the user.
+    pub fn parse(entry_type: EntryType, input: &str) -> Result<Self> {
+        let input = input.trim();
+        let data = match entry_type {
+            EntryType::NostrSecretKey => Keys::parse(input)
+                .map_err(|_| invalid(entry_type))?
+                .secret_key()
+                .as_secret_bytes()
+                .to_vec(),
+            EntryType::Ncryptsec => {
+                EncryptedSecretKey::from_bech32(input).map_err(|_| invalid(entry_type))?;
+                input.as_bytes().to_vec()
+            }
+            EntryType::Mnemonic => {
+                let words = input
+                    .split_whitespace()
+                    .map(str::to_lowercase)
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                Keys::from_mnemonic(words.as_str(), None).map_err(|_| invalid(entry_type))?;
+                words.into_bytes()
+            }
+            EntryType::NostrWalletConnect => {
+                NostrWalletConnectURI::parse(input).map_err(|_| invalid(entry_type))?;
+                input.as_bytes().to_vec()
+            }
+            EntryType::Opaque => {
+                if input.is_empty() {
+                    return Err(Error::InvalidInput("secret cannot be empty".to_string()));
+                }
+                input.as_bytes().to_vec()
+            }
+        };
+
+        Ok(Self {
+            entry_type,
+            data: Zeroizing::new(data),
+        })
+    }
+
+    /// Wraps a decrypted entry, checking it holds what `entry_type` says.
+    /// Nostr secret keys stored as hex text by older versions are accepted.
+    pub fn from_plaintext(entry_type: EntryType, plaintext: Zeroizing<Vec<u8>>) -> Result<Self> {
+        match entry_type {
+            EntryType::NostrSecretKey if plaintext.len() == 32 => {
+                SecretKey::from_slice(&plaintext).map_err(|_| invalid(entry_type))?;
+            }
+            EntryType::Opaque => {}
+            _ => {
+                let text = std::str::from_utf8(&plaintext).map_err(|_| invalid(entry_type))?;
+                return Self::parse(entry_type, text);
+            }
+        }
+
+        Ok(Self {
+            entry_type,
+            data: plaintext,
+        })
+    }
+
+    pub fn entry_type(&self) -> EntryType {
+        self.entry_type
+    }
+
+    /// The bytes that get encrypted.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The signing keys a Nostr secret key or mnemonic (account 0) stands for.
+    pub fn keys(&self) -> Option<Keys> {
+        match self.entry_type {
+            EntryType::NostrSecretKey => SecretKey::from_slice(&self.data).ok().map(Keys::new),
+            EntryType::Mnemonic => std::str::from_utf8(&self.data)
+                .ok()
+                .and_then(|words| Keys::from_mnemonic(words, None).ok()),
+            _ => None,
+        }
+    }
+
+    pub fn public_key(&self) -> Option<PublicKey> {
+        self.keys().map(|keys| keys.public_key())
+    }
+}
+
+/// How the secret is shown when read: `nsec` for Nostr secret keys, the
+/// text itself for textual types, and hex for binary opaque secrets.
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.entry_type == EntryType::NostrSecretKey
+            && let Some(keys) = self.keys()
+        {
+            let Ok(nsec) = keys.secret_key().to_bech32();
+            return write!(f, "{}", nsec);
+        }
+
+        match std::str::from_utf8(&self.data) {
+            Ok(text) if !text.chars().any(char::is_control) => write!(f, "{}", text),
+            _ => write!(f, "{}", hex::encode(self.data.as_slice())),
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secret")
+            .field("entry_type", &self.entry_type)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,278 @@
+use crate::auth::{explain_pin_error, prompt_pin};
+use crate::authenticator::{Authenticator, Permissions, PinUvAuthToken};
+use crate::error::{Error, Result};
+use crate::pin_provider::{PinProvider, TtyPinProvider};
+use ctap_hid_fido2::fidokey::get_info::InfoOption;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+pub const DEFAULT_SESSION_LIFETIME: Duration = Duration::from_secs(300);
+
+struct Unlocked {
+    // The PIN itself is dropped as soon as it has been exchanged for this.
+    token: PinUvAuthToken,
+    // largeBlobKeys by credential ID, so reading the largeBlob array doesn't
+    // take an assertion (and a touch) every time.
+    large_blob_keys: Vec<(Vec<u8>, Zeroizing<[u8; 32]>)>,
+    // Unwrapped vault master keys by their wrapped form, so entries are
+    // decrypted on the host after the first assertion.
+    vault_keys: Vec<(Vec<u8>, Zeroizing<[u8; 32]>)>,
+    expires_at: Instant,
+}
+
+/// A PIN unlock shared by every device operation until its lifetime ends.
+///
+/// The PIN is asked for once and exchanged for a pinUvAuthToken, which is
+/// cached in memory together with the largeBlobKeys and vault master keys
+/// unwrapped under it; the PIN is not kept. CTAP 2.1 takes every permission
+/// but largeBlobWrite away from a token once an assertion has checked for a
+/// touch, and a power cycle invalidates it, so the PIN is asked for again
+/// when the authenticator rejects the token (see [`Session::with_token`]).
+/// Everything is zeroized when the session is found expired, is locked, or
+/// is dropped.
+pub struct Session {
+    lifetime: Duration,
+    pin_provider: Arc<dyn PinProvider>,
+    // Set when a non-interactive provider's PIN was rejected; asking it again
+    // would only return the same PIN and burn another retry.
+    provider_rejected: bool,
+    unlocked: Option<Unlocked>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new(DEFAULT_SESSION_LIFETIME)
+    }
+}
+
+impl Session {
+    pub fn new(lifetime: Duration) -> Self {
+        Self {
+            lifetime,
+            pin_provider: Arc::new(TtyPinProvider),
+            provider_rejected: false,
+            unlocked: None,
+        }
+    }
+
+    pub fn with_pin_provider(mut self, pin_provider: Arc<dyn PinProvider>) -> Self {
+        self.pin_provider = pin_provider;
+        self.provider_rejected = false;
+        self
+    }
+
+    pub fn pin_provider(&self) -> &dyn PinProvider {
+        self.pin_provider.as_ref()
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Locks the session if its lifetime has ended. Returns whether it is
+    /// still unlocked.
+    pub fn lock_if_expired(&mut self) -> bool {
+        if self
+            .unlocked
+            .as_ref()
+            .is_some_and(|u| Instant::now() >= u.expires_at)
+        {
+            self.lock();
+        }
+        self.unlocked.is_some()
+    }
+
+    pub fn is_unlocked(&mut self) -> bool {
+        self.lock_if_expired()
+    }
+
+    pub fn unlock(&mut self, device: &impl Authenticator) -> Result<()> {
+        self.lock();
+
+        if self.provider_rejected {
+            return Err(Error::PinSourceRejected);
+        }
+
+        let pin = prompt_pin(device, self.pin_provider.as_ref(), "Enter your PIN")?;
+        let result = self.unlock_with_pin(device, pin);
+
+        if matches!(result, Err(Error::PinInvalid { .. })) && !self.pin_provider.is_interactive() {
+            self.provider_rejected = true;
+        }
+
+        result
+    }
+
+    /// Exchanges `pin` for a pinUvAuthToken and caches the token for the
+    /// session's lifetime.
+    pub fn unlock_with_pin(
+        &mut self,
+        device: &impl Authenticator,
+        pin: Zeroizing<String>,
+    ) -> Result<()> {
+        self.lock();
+
+        let token = request_token(device, &pin)?;
+
+        self.unlocked = Some(Unlocked {
+            token,
+            large_blob_keys: Vec::new(),
+            vault_keys: Vec::new(),
+            expires_at: Instant::now() + self.lifetime,
+        });
+
+        Ok(())
+    }
+
+    /// Asks for the PIN again and replaces the session's token, keeping the
+    /// cached keys and the expiry. Used once `device` rejected the token,
+    /// e.g. a reinserted authenticator or one whose token an assertion has
+    /// used up. Prompts for a full unlock if the session expired.
+    pub fn reauthorize(&mut self, device: &impl Authenticator) -> Result<()> {
+        if !self.lock_if_expired() {
+            return self.unlock(device);
+        }
+        if self.provider_rejected {
+            return Err(Error::PinSourceRejected);
+        }
+
+        let pin = prompt_pin(device, self.pin_provider.as_ref(), "Enter your PIN")?;
+        let token = match request_token(device, &pin) {
+            Ok(token) => token,
+            Err(e) => {
+                if matches!(e, Error::PinInvalid { .. }) && !self.pin_provider.is_interactive() {
+                    self.provider_rejected = true;
+                }
+                self.lock();
+                return Err(e);
+            }
+        };
+
+        if let Some(unlocked) = self.unlocked.as_mut() {
+            unlocked.token = token;
+        }
+        Ok(())
+    }
+
+    pub fn lock(&mut self) {
+        self.unlocked = None;
+    }
+
+    pub fn large_blob_key(&mut self, credential_id: &[u8]) -> Option<Zeroizing<[u8; 32]>> {
+        if !self.lock_if_expired() {
+            return None;
+        }
+
+        self.unlocked
+            .as_ref()?
+            .large_blob_keys
+            .iter()
+            .find(|(id, _)| id == credential_id)
+            .map(|(_, key)| key.clone())
+    }
+
+    /// Keeps `key` until the session is locked or expires.
+    pub fn cache_large_blob_key(&mut self, credential_id: &[u8], key: Zeroizing<[u8; 32]>) {
+        self.lock_if_expired();
+        if let Some(unlocked) = self.unlocked.as_mut() {
+            unlocked
+                .large_blob_keys
+                .retain(|(id, _)| id != credential_id);
+            unlocked.large_blob_keys.push((credential_id.to_vec(), key));
+        }
+    }
+
+    /// The vault master key `wrapped` was unwrapped to in this session.
+    pub fn vault_key(&mut self, wrapped: &[u8]) -> Option<Zeroizing<[u8; 32]>> {
+        if !self.lock_if_expired() {
+            return None;
+        }
+
+        self.unlocked
+            .as_ref()?
+            .vault_keys
+            .iter()
+            .find(|(w, _)| w == wrapped)
+            .map(|(_, key)| key.clone())
+    }
+
+    /// Keeps the unwrapped vault master key until the session is locked or expires.
+    pub fn cache_vault_key(&mut self, wrapped: &[u8], key: Zeroizing<[u8; 32]>) {
+        self.lock_if_expired();
+        if let Some(unlocked) = self.unlocked.as_mut() {
+            unlocked.vault_keys.retain(|(w, _)| w != wrapped);
+            unlocked.vault_keys.push((wrapped.to_vec(), key));
+        }
+    }
+
+    /// Drops a cached vault master key that nothing is encrypted under anymore.
+    pub fn forget_vault_key(&mut self, wrapped: &[u8]) {
+        if let Some(unlocked) = self.unlocked.as_mut() {
+            unlocked.vault_keys.retain(|(w, _)| w != wrapped);
+        }
+    }
+
+    /// Asks the PIN source for an entry passphrase. A non-interactive source
+    /// only knows the PIN, so it is never asked.
+    pub fn passphrase(&self, prompt: &str) -> Result<Zeroizing<String>> {
+        if !self.pin_provider.is_interactive() {
+            return Err(Error::PassphraseRequired);
+        }
+        self.pin_provider.get_pin(prompt)
+    }
+
+    /// Returns the session's pinUvAuthToken, asking for the PIN again if the
+    /// session expired.
+    pub fn token(&mut self, device: &impl Authenticator) -> Result<&PinUvAuthToken> {
+        if !self.lock_if_expired() {
+            self.unlock(device)?;
+        }
+
+        self.unlocked
+            .as_ref()
+            .map(|u| &u.token)
+            .ok_or(Error::PinRequired)
+    }
+
+    /// Runs `command` with the session's token. If the authenticator rejects
+    /// the token, the PIN is asked for once more and `command` retried with
+    /// the new one.
+    pub fn with_token<A, T>(
+        &mut self,
+        device: &A,
+        mut command: impl FnMut(&A, &PinUvAuthToken) -> anyhow::Result<T>,
+    ) -> Result<T>
+    where
+        A: Authenticator,
+    {
+        match command(device, self.token(device)?).map_err(Error::from) {
+            Err(Error::PinTokenRejected) => {
+                self.reauthorize(device)?;
+                Ok(command(device, self.token(device)?)?)
+            }
+            result => result,
+        }
+    }
+}
+
+/// Permissions for a session token: everything the crate does, as far as
+/// `device` supports it.
+fn session_permissions(device: &impl Authenticator) -> Result<Permissions> {
+    let info = device.get_authenticator_info()?;
+    let mut permissions = Permissions::MAKE_CREDENTIAL | Permissions::GET_ASSERTION;
+    if info.option(&InfoOption::CredMgmt).is_some() {
+        permissions = permissions | Permissions::CREDENTIAL_MANAGEMENT;
+    }
+    if info.option(&InfoOption::LargeBlobs) == Some(true) {
+        permissions = permissions | Permissions::LARGE_BLOB_WRITE;
+    }
+    Ok(permissions)
+}
+
+fn request_token(device: &impl Authenticator, pin: &str) -> Result<PinUvAuthToken> {
+    let permissions = session_permissions(device)?;
+    device
+        .get_pin_uv_auth_token(pin, permissions)
+        .map_err(|e| explain_pin_error(device, e))
+}
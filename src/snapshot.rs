@@ -0,0 +1,254 @@
+use crate::authenticator::Authenticator;
+use crate::blob_format::WrappedVaultKey;
+use crate::encryption::{Cipher, decrypt_data, decrypt_with_key, encrypt_with_key};
+use crate::error::{Error, Result};
+use crate::kdf::KeyPurpose;
+use crate::profile::Profile;
+use crate::session::Session;
+use crate::vault;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"YNSS";
+// 1: keyed by the hmac-secret output directly, no header label.
+// 2: the key's HKDF label follows the version, and the header is associated data.
+// 3: keyed by the vault master key, whose wrapped form follows the label.
+const SNAPSHOT_VERSION: u8 = 3;
+
+/// `SNAPSHOT_DIR`, or `$XDG_DATA_HOME/yubikey-nostr-signer`
+/// (`~/.local/share/yubikey-nostr-signer`).
+pub fn snapshot_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("SNAPSHOT_DIR").filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(dir);
+    }
+
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .unwrap_or_else(|| PathBuf::from("."));
+
+    data_home.join("yubikey-nostr-signer")
+}
+
+/// The credential's snapshot of the largeBlob as it was before its last
+/// write. Named after the credential as well as the profile, so two
+/// authenticators set up for the same profile keep a snapshot each.
+pub fn snapshot_path(profile: &Profile, credential_id: &[u8]) -> PathBuf {
+    let digest = Sha256::digest(credential_id);
+    snapshot_dir().join(format!(
+        "{}-{}.snapshot",
+        profile.name,
+        hex::encode(&digest[..8])
+    ))
+}
+
+/// Where versions 1 and 2 kept the one snapshot of a profile.
+fn legacy_snapshot_path(profile: &Profile) -> PathBuf {
+    snapshot_dir().join(format!("{}.snapshot", profile.name))
+}
+
+/// `magic || version || len(label) (u8) || label || len(vault label) (u8)
+/// || vault label || len(wrapped) (u16, big-endian) || wrapped`
+fn header(label: &str, vault: &WrappedVaultKey) -> Result<Vec<u8>> {
+    let too_long = || Error::InvalidInput("snapshot header field too long".to_string());
+    let mut header = SNAPSHOT_MAGIC.to_vec();
+    header.push(SNAPSHOT_VERSION);
+    for field in [label.as_bytes(), vault.kdf.as_bytes()] {
+        header.push(u8::try_from(field.len()).map_err(|_| too_long())?);
+        header.extend_from_slice(field);
+    }
+    let wrapped_len = u16::try_from(vault.wrapped.len()).map_err(|_| too_long())?;
+    header.extend_from_slice(&wrapped_len.to_be_bytes());
+    header.extend_from_slice(&vault.wrapped);
+    Ok(header)
+}
+
+/// Encrypts `blob` under a key derived from the vault master key, which the
+/// session usually holds already, and replaces the credential's snapshot
+/// with it. The wrapped vault key goes in the header, so the snapshot opens
+/// even after the vault on the device was rotated.
+pub fn save_snapshot(
+    profile: &Profile,
+    credential_id: &[u8],
+    vault: &WrappedVaultKey,
+    master_key: &[u8; 32],
+    blob: &[u8],
+) -> Result<PathBuf> {
+    let mut data = header(KeyPurpose::Snapshot.label(), vault)?;
+    let encrypted = encrypt_with_key(
+        master_key,
+        Cipher::Aes256Gcm,
+        KeyPurpose::Snapshot,
+        blob,
+        &data,
+    )?;
+    data.extend(encrypted);
+
+    let path = snapshot_path(profile, credential_id);
+    write_private_file(&path, &data)?;
+
+    Ok(path)
+}
+
+/// Deletes the credential's snapshot, e.g. once the credential is gone and
+/// nothing could decrypt it anymore.
+pub fn remove_snapshot(profile: &Profile, credential_id: &[u8]) -> Result<()> {
+    match fs::remove_file(snapshot_path(profile, credential_id)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Replaces `path` with `data`, readable only by the owner on Unix. The data
+/// is written next to the target and renamed, so a crash never leaves a torn
+/// file.
+pub fn write_private_file(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+/// A largeBlob array read back from a snapshot file.
+pub struct Snapshot {
+    pub blob: Zeroizing<Vec<u8>>,
+    /// The vault the snapshot was encrypted under, whose master key is in
+    /// the session once it is loaded. `None` for snapshots of versions 1
+    /// and 2, keyed by the hmac-secret directly.
+    pub vault: Option<WrappedVaultKey>,
+}
+
+/// The credential's snapshot, or the profile's from before snapshots were
+/// kept per credential.
+pub fn load_snapshot(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+) -> Result<Snapshot> {
+    let data = match fs::read(snapshot_path(profile, credential_id)) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            fs::read(legacy_snapshot_path(profile))
+        }
+        result => result,
+    };
+    let data = match data {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(Error::SnapshotNotFound);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let (version, rest) = data
+        .strip_prefix(SNAPSHOT_MAGIC.as_slice())
+        .and_then(|rest| rest.split_first())
+        .ok_or(Error::EntryCorrupted)?;
+
+    if *version == 1 {
+        return Ok(Snapshot {
+            blob: decrypt_data(device, session, profile, credential_id, None, rest, &[])?,
+            vault: None,
+        });
+    }
+
+    let mut reader = HeaderReader(rest);
+    let label = reader.str()?;
+    if KeyPurpose::from_label(label) != Some(KeyPurpose::Snapshot) {
+        return Err(Error::UnsupportedKeyLabel(label.to_string()));
+    }
+
+    match *version {
+        2 => Ok(Snapshot {
+            blob: decrypt_data(
+                device,
+                session,
+                profile,
+                credential_id,
+                Some(KeyPurpose::Snapshot),
+                reader.0,
+                &data[..data.len() - reader.0.len()],
+            )?,
+            vault: None,
+        }),
+        SNAPSHOT_VERSION => {
+            let vault = WrappedVaultKey {
+                kdf: reader.str()?.to_string(),
+                wrapped: reader.wrapped()?.to_vec(),
+            };
+            let master_key = vault::unlock(device, session, profile, credential_id, &vault)?;
+            let blob = decrypt_with_key(
+                master_key.as_ref(),
+                Cipher::Aes256Gcm,
+                KeyPurpose::Snapshot,
+                reader.0,
+                &data[..data.len() - reader.0.len()],
+            )?;
+            Ok(Snapshot {
+                blob,
+                vault: Some(vault),
+            })
+        }
+        _ => Err(Error::EntryCorrupted),
+    }
+}
+
+/// The vault recorded in the credential's snapshot header, read without
+/// decrypting anything: the vault this host last wrote the device with.
+/// `None` without a snapshot of the current version.
+pub fn recorded_vault(profile: &Profile, credential_id: &[u8]) -> Option<WrappedVaultKey> {
+    let data = fs::read(snapshot_path(profile, credential_id)).ok()?;
+    let rest = data.strip_prefix(SNAPSHOT_MAGIC.as_slice())?;
+    let (&version, rest) = rest.split_first()?;
+    if version != SNAPSHOT_VERSION {
+        return None;
+    }
+
+    let mut reader = HeaderReader(rest);
+    reader.str().ok()?;
+    Some(WrappedVaultKey {
+        kdf: reader.str().ok()?.to_string(),
+        wrapped: reader.wrapped().ok()?.to_vec(),
+    })
+}
+
+/// Reads header fields off the front of the remaining bytes.
+struct HeaderReader<'d>(&'d [u8]);
+
+impl<'d> HeaderReader<'d> {
+    fn take(&mut self, len: usize) -> Result<&'d [u8]> {
+        if self.0.len() < len {
+            return Err(Error::EntryCorrupted);
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn str(&mut self) -> Result<&'d str> {
+        let len = self.take(1)?[0] as usize;
+        std::str::from_utf8(self.take(len)?).map_err(|_| Error::EntryCorrupted)
+    }
+
+    fn wrapped(&mut self) -> Result<&'d [u8]> {
+        let len = self.take(2)?;
+        self.take(u16::from_be_bytes([len[0], len[1]]) as usize)
+    }
+}
@@ -0,0 +1,403 @@
+use anyhow::{Result, anyhow};
+use ctap_hid_fido2::{
+    fidokey::{get_info::InfoOption, large_blobs::large_blobs_params::LargeBlobData},
+    public_key_credential_user_entity::PublicKeyCredentialUserEntity,
+};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::authenticator::{
+    AssertionRequest, AssertionResponse, Authenticator, AuthenticatorInfo, CtapStatus,
+    EMPTY_LARGE_BLOB_ARRAY, LARGE_BLOB_HASH_LEN, MIN_SERIALIZED_LARGE_BLOB_ARRAY, Permissions,
+    PinUvAuthToken, ResidentCredential,
+};
+
+const MAX_DISCOVERABLE_CREDENTIALS: usize = 25;
+const MAX_PIN_RETRIES: i32 = 8;
+// CTAP 2.1 §6.5.5.5: after three consecutive mismatches a power cycle is required.
+const MAX_CONSECUTIVE_PIN_MISMATCHES: u32 = 3;
+const MIN_PIN_LENGTH: usize = 4;
+const TOKEN_LEN: usize = 32;
+
+fn ctap_error(status: CtapStatus) -> anyhow::Error {
+    status.into()
+}
+
+struct SoftCredential {
+    id: Vec<u8>,
+    rp_id: String,
+    user: PublicKeyCredentialUserEntity,
+    // CredRandomWithUV: every assertion here is authorized by a token.
+    cred_random: [u8; 32],
+    large_blob_key: Option<[u8; 32]>,
+}
+
+impl Drop for SoftCredential {
+    fn drop(&mut self) {
+        self.cred_random.zeroize();
+        if let Some(key) = self.large_blob_key.as_mut() {
+            key.zeroize();
+        }
+    }
+}
+
+struct SoftState {
+    credentials: Vec<SoftCredential>,
+    large_blob: Vec<u8>,
+    pin: Option<Zeroizing<String>>,
+    pin_retries: i32,
+    consecutive_mismatches: u32,
+    // The one pinUvAuthToken handed out since the last power cycle, with the
+    // permissions it has left.
+    token: Option<(Zeroizing<Vec<u8>>, Permissions)>,
+    // Assertions answered, each of which takes a touch on a real key.
+    assertions: usize,
+}
+
+impl SoftState {
+    fn resident_count(&self) -> usize {
+        self.credentials.len()
+    }
+
+    fn verify_pin(&mut self, pin: &str) -> Result<()> {
+        let matches = match &self.pin {
+            Some(expected) => expected.as_str() == pin,
+            None => return Err(ctap_error(CtapStatus::PIN_NOT_SET)),
+        };
+
+        if self.pin_retries == 0 {
+            return Err(ctap_error(CtapStatus::PIN_BLOCKED));
+        }
+        if self.consecutive_mismatches >= MAX_CONSECUTIVE_PIN_MISMATCHES {
+            return Err(ctap_error(CtapStatus::PIN_AUTH_BLOCKED));
+        }
+
+        if !matches {
+            self.pin_retries -= 1;
+            self.consecutive_mismatches += 1;
+            if self.pin_retries == 0 {
+                return Err(ctap_error(CtapStatus::PIN_BLOCKED));
+            }
+            if self.consecutive_mismatches >= MAX_CONSECUTIVE_PIN_MISMATCHES {
+                return Err(ctap_error(CtapStatus::PIN_AUTH_BLOCKED));
+            }
+            return Err(ctap_error(CtapStatus::PIN_INVALID));
+        }
+
+        self.pin_retries = MAX_PIN_RETRIES;
+        self.consecutive_mismatches = 0;
+        Ok(())
+    }
+
+    /// Checks that `token` is the current one and still carries `permission`.
+    fn check_token(&self, token: &PinUvAuthToken, permission: Permissions) -> Result<()> {
+        match &self.token {
+            Some((key, permissions))
+                if key.as_slice() == token.key() && permissions.contains(permission) =>
+            {
+                Ok(())
+            }
+            _ => Err(ctap_error(CtapStatus::PIN_AUTH_INVALID)),
+        }
+    }
+
+    /// What an assertion or registration with user presence leaves of the
+    /// token (CTAP 2.1 §6.1.2 and §6.2.2).
+    fn clear_permissions_except_large_blob_write(&mut self) {
+        if let Some((_, permissions)) = self.token.as_mut() {
+            *permissions = if permissions.contains(Permissions::LARGE_BLOB_WRITE) {
+                Permissions::LARGE_BLOB_WRITE
+            } else {
+                Permissions::default()
+            };
+        }
+    }
+}
+
+fn check_pin_policy(pin: &str) -> Result<()> {
+    if pin.chars().count() < MIN_PIN_LENGTH || pin.len() > 63 {
+        return Err(ctap_error(CtapStatus::PIN_POLICY_VIOLATION));
+    }
+    Ok(())
+}
+
+/// In-memory authenticator implementing resident credentials, hmac-secret
+/// and largeBlob storage, for running the crate without a USB device.
+pub struct SoftAuthenticator {
+    max_large_blob: usize,
+    state: Mutex<SoftState>,
+}
+
+impl Default for SoftAuthenticator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SoftAuthenticator {
+    pub fn new() -> Self {
+        Self {
+            max_large_blob: MIN_SERIALIZED_LARGE_BLOB_ARRAY,
+            state: Mutex::new(SoftState {
+                credentials: Vec::new(),
+                large_blob: EMPTY_LARGE_BLOB_ARRAY.to_vec(),
+                pin: None,
+                pin_retries: MAX_PIN_RETRIES,
+                consecutive_mismatches: 0,
+                token: None,
+                assertions: 0,
+            }),
+        }
+    }
+
+    pub fn with_pin(self, pin: &str) -> Self {
+        if let Ok(mut state) = self.state.lock() {
+            state.pin = Some(Zeroizing::new(pin.to_string()));
+        }
+        self
+    }
+
+    /// Clears the consecutive-mismatch lockout and invalidates the
+    /// pinUvAuthToken, like unplugging a real key.
+    pub fn power_cycle(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.consecutive_mismatches = 0;
+            state.token = None;
+        }
+    }
+
+    /// How many assertions were answered so far; a real key takes a touch
+    /// for each.
+    pub fn assertions(&self) -> usize {
+        self.state.lock().map_or(0, |state| state.assertions)
+    }
+
+    pub fn with_max_large_blob(mut self, max_large_blob: usize) -> Self {
+        self.max_large_blob = max_large_blob;
+        self
+    }
+
+    fn options(&self, state: &SoftState) -> Vec<(String, bool)> {
+        [
+            (InfoOption::Rk, true),
+            (InfoOption::Up, true),
+            (InfoOption::ClientPin, state.pin.is_some()),
+            (InfoOption::LargeBlobs, true),
+            (InfoOption::PinUvAuthToken, true),
+            (InfoOption::AlwaysUv, false),
+            (InfoOption::CredMgmt, true),
+        ]
+        .into_iter()
+        .map(|(option, value)| (option.as_ref().to_string(), value))
+        .collect()
+    }
+
+    fn lock_state(&self) -> Result<std::sync::MutexGuard<'_, SoftState>> {
+        self.state
+            .lock()
+            .map_err(|_| anyhow!("Software authenticator state poisoned"))
+    }
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+impl Authenticator for SoftAuthenticator {
+    fn get_pin_uv_auth_token(&self, pin: &str, permissions: Permissions) -> Result<PinUvAuthToken> {
+        let mut state = self.lock_state()?;
+        state.verify_pin(pin)?;
+
+        let mut key = vec![0u8; TOKEN_LEN];
+        rand::rng().fill(key.as_mut_slice());
+        state.token = Some((Zeroizing::new(key.clone()), permissions));
+
+        Ok(PinUvAuthToken::new(key, permissions))
+    }
+
+    fn get_assertion(
+        &self,
+        request: &AssertionRequest<'_>,
+        token: &PinUvAuthToken,
+    ) -> Result<AssertionResponse> {
+        let mut state = self.lock_state()?;
+        state.check_token(token, Permissions::GET_ASSERTION)?;
+        state.assertions += 1;
+        if request.hmac_secret_salts.len() > 2 {
+            return Err(ctap_error(CtapStatus::INVALID_PARAMETER));
+        }
+
+        let credential = state
+            .credentials
+            .iter()
+            .filter(|c| c.rp_id == request.rp_id)
+            .find(|c| request.credential_ids.is_empty() || request.credential_ids.contains(&c.id))
+            .ok_or_else(|| ctap_error(CtapStatus::NO_CREDENTIALS))?;
+
+        let hmac_secret = request
+            .hmac_secret_salts
+            .iter()
+            .map(|salt| Zeroizing::new(hmac_sha256(&credential.cred_random, salt)))
+            .collect();
+        // Credentials created without largeBlobKey simply omit it.
+        let large_blob_key = credential
+            .large_blob_key
+            .filter(|_| request.large_blob_key)
+            .map(Zeroizing::new);
+
+        let response = AssertionResponse {
+            credential_id: credential.id.clone(),
+            hmac_secret,
+            large_blob_key,
+        };
+        state.clear_permissions_except_large_blob_write();
+
+        Ok(response)
+    }
+
+    fn make_credential(
+        &self,
+        rp_id: &str,
+        user: &PublicKeyCredentialUserEntity,
+        token: &PinUvAuthToken,
+    ) -> Result<Vec<u8>> {
+        let mut state = self.lock_state()?;
+        state.check_token(token, Permissions::MAKE_CREDENTIAL)?;
+
+        let mut credential = SoftCredential {
+            id: vec![0u8; 32],
+            rp_id: rp_id.to_string(),
+            user: user.clone(),
+            cred_random: [0u8; 32],
+            large_blob_key: Some(rand::random()),
+        };
+        rand::rng().fill(credential.id.as_mut_slice());
+        rand::rng().fill(&mut credential.cred_random);
+
+        state
+            .credentials
+            .retain(|c| !(c.rp_id == credential.rp_id && c.user.id == credential.user.id));
+        if state.resident_count() >= MAX_DISCOVERABLE_CREDENTIALS {
+            return Err(ctap_error(CtapStatus::KEY_STORE_FULL));
+        }
+
+        let credential_id = credential.id.clone();
+        state.credentials.push(credential);
+        state.clear_permissions_except_large_blob_write();
+
+        Ok(credential_id)
+    }
+
+    fn get_large_blob(&self) -> Result<LargeBlobData> {
+        let state = self.lock_state()?;
+
+        Ok(LargeBlobData {
+            large_blob_array: state.large_blob.clone(),
+            hash: Sha256::digest(&state.large_blob)[..LARGE_BLOB_HASH_LEN].to_vec(),
+        })
+    }
+
+    fn write_large_blob(&self, token: &PinUvAuthToken, data: Vec<u8>) -> Result<()> {
+        let mut state = self.lock_state()?;
+        state.check_token(token, Permissions::LARGE_BLOB_WRITE)?;
+
+        if data.len() + LARGE_BLOB_HASH_LEN > self.max_large_blob {
+            return Err(ctap_error(CtapStatus::LARGE_BLOB_STORAGE_FULL));
+        }
+
+        state.large_blob = data;
+
+        Ok(())
+    }
+
+    fn enable_info_option(&self, info_option: &InfoOption) -> Result<Option<bool>> {
+        let state = self.lock_state()?;
+
+        Ok(self
+            .options(&state)
+            .into_iter()
+            .find(|(name, _)| name == info_option.as_ref())
+            .map(|(_, value)| value))
+    }
+
+    fn get_authenticator_info(&self) -> Result<AuthenticatorInfo> {
+        let state = self.lock_state()?;
+
+        Ok(AuthenticatorInfo {
+            versions: vec!["FIDO_2_0".to_string(), "FIDO_2_1".to_string()],
+            extensions: vec!["hmac-secret".to_string(), "largeBlobKey".to_string()],
+            aaguid: vec![0u8; 16],
+            options: self.options(&state),
+            max_msg_size: 1200,
+            max_serialized_large_blob_array: self.max_large_blob as u32,
+            remaining_discoverable_credentials: (MAX_DISCOVERABLE_CREDENTIALS
+                - state.resident_count()) as u32,
+            min_pin_length: MIN_PIN_LENGTH as u32,
+            firmware_version: 0,
+        })
+    }
+
+    fn get_pin_retries(&self) -> Result<i32> {
+        Ok(self.lock_state()?.pin_retries)
+    }
+
+    fn set_new_pin(&self, pin: &str) -> Result<()> {
+        let mut state = self.lock_state()?;
+        if state.pin.is_some() {
+            return Err(ctap_error(CtapStatus::PIN_AUTH_INVALID));
+        }
+        check_pin_policy(pin)?;
+
+        state.pin = Some(Zeroizing::new(pin.to_string()));
+        state.pin_retries = MAX_PIN_RETRIES;
+        Ok(())
+    }
+
+    fn change_pin(&self, current_pin: &str, new_pin: &str) -> Result<()> {
+        let mut state = self.lock_state()?;
+        state.verify_pin(current_pin)?;
+        check_pin_policy(new_pin)?;
+
+        state.pin = Some(Zeroizing::new(new_pin.to_string()));
+        state.token = None;
+        Ok(())
+    }
+
+    fn enumerate_credentials(
+        &self,
+        token: &PinUvAuthToken,
+        rpid: &str,
+    ) -> Result<Vec<ResidentCredential>> {
+        let state = self.lock_state()?;
+        state.check_token(token, Permissions::CREDENTIAL_MANAGEMENT)?;
+
+        Ok(state
+            .credentials
+            .iter()
+            .filter(|c| c.rp_id == rpid)
+            .map(|c| ResidentCredential {
+                credential_id: c.id.clone(),
+                user: c.user.clone(),
+            })
+            .collect())
+    }
+
+    fn delete_credential(&self, token: &PinUvAuthToken, credential_id: &[u8]) -> Result<()> {
+        let mut state = self.lock_state()?;
+        state.check_token(token, Permissions::CREDENTIAL_MANAGEMENT)?;
+
+        let position = state
+            .credentials
+            .iter()
+            .position(|c| c.id == credential_id)
+            .ok_or_else(|| ctap_error(CtapStatus::NO_CREDENTIALS))?;
+        state.credentials.remove(position);
+
+        Ok(())
+    }
+}
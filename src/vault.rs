@@ -0,0 +1,195 @@
+use crate::authenticator::Authenticator;
+use crate::blob_format::WrappedVaultKey;
+use crate::credential::{
+    get_hmac_secret_pair, get_large_blob_key, get_large_blob_key_and_hmac_secret,
+};
+use crate::encryption::{
+    decrypt_data, decrypt_with_hmac_secret, encrypt_data, encrypt_with_hmac_secret,
+    hmac_secret_salt,
+};
+use crate::error::{Error, Result};
+use crate::kdf::KeyPurpose;
+use crate::profile::Profile;
+use crate::session::Session;
+use rand::Rng;
+use zeroize::Zeroizing;
+
+/// Associated data the master key is wrapped with, binding it to the credential.
+fn associated_data(credential_id: &[u8]) -> Vec<u8> {
+    let mut aad = b"yubikey-nostr-signer vault".to_vec();
+    aad.extend_from_slice(&(credential_id.len() as u32).to_be_bytes());
+    aad.extend_from_slice(credential_id);
+    aad
+}
+
+/// Generates a vault master key and wraps it under the credential's
+/// hmac-secret (one assertion). The key stays cached in the session.
+pub fn create(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+) -> Result<(WrappedVaultKey, Zeroizing<[u8; 32]>)> {
+    let mut master_key = Zeroizing::new([0u8; 32]);
+    rand::rng().fill(master_key.as_mut());
+
+    let wrapped = encrypt_data(
+        device,
+        session,
+        profile,
+        credential_id,
+        Some(KeyPurpose::Vault),
+        master_key.as_ref(),
+        &associated_data(credential_id),
+    )?;
+    session.cache_vault_key(&wrapped, master_key.clone());
+
+    Ok((
+        WrappedVaultKey {
+            kdf: KeyPurpose::Vault.label().to_string(),
+            wrapped,
+        },
+        master_key,
+    ))
+}
+
+/// The master key `vault` wraps: from the session if it was unwrapped
+/// before, usually by [`large_blob_key`] in the assertion that fetched the
+/// largeBlobKey, otherwise with one hmac-secret assertion.
+pub fn unlock(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+    vault: &WrappedVaultKey,
+) -> Result<Zeroizing<[u8; 32]>> {
+    if let Some(key) = session.vault_key(&vault.wrapped) {
+        return Ok(key);
+    }
+
+    check_label(vault)?;
+
+    let plaintext = decrypt_data(
+        device,
+        session,
+        profile,
+        credential_id,
+        Some(KeyPurpose::Vault),
+        &vault.wrapped,
+        &associated_data(credential_id),
+    )?;
+    let master_key = master_key_from(&plaintext)?;
+    session.cache_vault_key(&vault.wrapped, master_key.clone());
+
+    Ok(master_key)
+}
+
+/// The credential's largeBlobKey, with the master key `hint` wraps
+/// unwrapped in the same assertion, so opening the store and its vault
+/// takes one touch. `hint` is the vault the device is expected to hold,
+/// since the device's own copy only reads once the largeBlobKey is known;
+/// if the device turns out to hold another one, `unlock` takes its own
+/// assertion for it. Both keys stay cached in the session.
+pub fn large_blob_key(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+    hint: Option<&WrappedVaultKey>,
+) -> Result<Option<Zeroizing<[u8; 32]>>> {
+    if let Some(key) = session.large_blob_key(credential_id) {
+        return Ok(Some(key));
+    }
+
+    let salt = hint
+        .filter(|hint| check_label(hint).is_ok() && session.vault_key(&hint.wrapped).is_none())
+        .and_then(|hint| Some((hint, hmac_secret_salt(&hint.wrapped).ok()?)));
+    let Some((hint, salt)) = salt else {
+        return get_large_blob_key(device, session, profile, credential_id);
+    };
+
+    let assertion =
+        get_large_blob_key_and_hmac_secret(device, session, profile, credential_id, &salt)?;
+
+    // A hint that doesn't unwrap is only a wasted salt.
+    if let Ok(plaintext) = decrypt_with_hmac_secret(
+        &assertion.hmac_secret[0],
+        Some(KeyPurpose::Vault),
+        &hint.wrapped,
+        &associated_data(credential_id),
+    ) && let Ok(master_key) = master_key_from(&plaintext)
+    {
+        session.cache_vault_key(&hint.wrapped, master_key);
+    }
+
+    Ok(assertion.large_blob_key)
+}
+
+/// Replaces `vault` with a new master key wrapped under a new salt, so
+/// nothing derived from the old salt opens anything encrypted afterwards.
+/// The old key is unwrapped and the new one wrapped with the outputs for
+/// both salts from one assertion (one touch). Both keys stay cached in the
+/// session, and the new vault and key are returned.
+pub fn rotate(
+    device: &mut impl Authenticator,
+    session: &mut Session,
+    profile: &Profile,
+    credential_id: &[u8],
+    vault: &WrappedVaultKey,
+) -> Result<(WrappedVaultKey, Zeroizing<[u8; 32]>)> {
+    check_label(vault)?;
+
+    let old_salt = hmac_secret_salt(&vault.wrapped)?;
+    let mut new_salt = [0u8; 32];
+    rand::rng().fill(&mut new_salt);
+
+    let (old_output, new_output) = get_hmac_secret_pair(
+        device,
+        session,
+        profile,
+        credential_id,
+        &old_salt,
+        &new_salt,
+    )?;
+    let (old_output, new_output) = (Zeroizing::new(old_output), Zeroizing::new(new_output));
+    let aad = associated_data(credential_id);
+
+    let plaintext =
+        decrypt_with_hmac_secret(&old_output, Some(KeyPurpose::Vault), &vault.wrapped, &aad)?;
+    session.cache_vault_key(&vault.wrapped, master_key_from(&plaintext)?);
+
+    let mut master_key = Zeroizing::new([0u8; 32]);
+    rand::rng().fill(master_key.as_mut());
+    let wrapped = encrypt_with_hmac_secret(
+        &new_output,
+        &new_salt,
+        Some(KeyPurpose::Vault),
+        master_key.as_ref(),
+        &aad,
+    )?;
+    session.cache_vault_key(&wrapped, master_key.clone());
+
+    Ok((
+        WrappedVaultKey {
+            kdf: KeyPurpose::Vault.label().to_string(),
+            wrapped,
+        },
+        master_key,
+    ))
+}
+
+fn check_label(vault: &WrappedVaultKey) -> Result<()> {
+    if KeyPurpose::from_label(&vault.kdf) != Some(KeyPurpose::Vault) {
+        return Err(Error::UnsupportedKeyLabel(vault.kdf.clone()));
+    }
+    Ok(())
+}
+
+fn master_key_from(plaintext: &[u8]) -> Result<Zeroizing<[u8; 32]>> {
+    if plaintext.len() != 32 {
+        return Err(Error::EntryCorrupted);
+    }
+    let mut master_key = Zeroizing::new([0u8; 32]);
+    master_key.copy_from_slice(plaintext);
+    Ok(master_key)
+}
@@ -1,11 +1,19 @@
 use anyhow::Result;
 use dialoguer::Confirm;
+use nostr::nips::nip46::ResponseResult;
 use nostr::prelude::*;
 use nostr_relay_pool::prelude::*;
 use std::sync::Arc;
+use std::time::Duration;
 
+use crate::device::DeviceSelector;
+use crate::error::Error;
+use crate::profile::Profile;
+use crate::session::Session;
 use crate::yubikey_helper::YubikeyKeyManager;
 
+const SESSION_EXPIRY_CHECK: Duration = Duration::from_secs(1);
+
 pub struct YubikeyNostrBunker {
     signer_key: Keys,
     yubikey_manager: Arc<YubikeyKeyManager>,
@@ -15,15 +23,27 @@ pub struct YubikeyNostrBunker {
 }
 
 impl YubikeyNostrBunker {
-    pub fn new<I, S>(relays: I, secret: Option<String>) -> Result<Self>
+    pub fn new<I, S>(
+        relays: I,
+        secret: Option<String>,
+        device_selector: Option<&DeviceSelector>,
+        profile: Profile,
+        session: Session,
+        entry_id: Option<&str>,
+    ) -> Result<Self>
     where
         I: IntoIterator<Item = S>,
         S: AsRef<str>,
     {
-        let yubikey_manager = Arc::new(YubikeyKeyManager::new()?);
-        let signer_key = Keys::generate();
-
-        println!("🔐 Temporary NIP-46 key generated:");
+        let yubikey_manager = Arc::new(YubikeyKeyManager::new(
+            device_selector,
+            profile,
+            session,
+            entry_id,
+        )?);
+        let signer_key = yubikey_manager.transport_keys()?;
+
+        println!("🔐 NIP-46 key derived from the YubiKey credential:");
         println!("   Pubkey: {}\n", signer_key.public_key().to_bech32()?);
 
         let relay_urls: Vec<String> = relays.into_iter().map(|r| r.as_ref().to_string()).collect();
@@ -73,12 +93,24 @@ impl YubikeyNostrBunker {
 
         let mut notifications = self.pool.notifications();
 
-        while let Ok(notification) = notifications.recv().await {
-            if let RelayPoolNotification::Event { event, .. } = notification
-                && event.kind == Kind::NostrConnect
-                && let Err(e) = self.handle_request(&event, &user_pubkey).await
-            {
-                eprintln!("❌ Error processing request: {}", e);
+        // The session is otherwise only checked when a request arrives, so an
+        // idle bunker would keep the PIN and keys in memory indefinitely.
+        let mut expiry_check = tokio::time::interval(SESSION_EXPIRY_CHECK);
+
+        loop {
+            tokio::select! {
+                _ = expiry_check.tick() => self.yubikey_manager.lock_if_expired()?,
+                notification = notifications.recv() => {
+                    let Ok(notification) = notification else {
+                        break;
+                    };
+                    if let RelayPoolNotification::Event { event, .. } = notification
+                        && event.kind == Kind::NostrConnect
+                        && let Err(e) = self.handle_request(&event, &user_pubkey).await
+                    {
+                        eprintln!("❌ Error processing request: {}", e);
+                    }
+                }
             }
         }
 
@@ -104,6 +136,27 @@ impl YubikeyNostrBunker {
             }
         };
 
+        // Don't ask the user to approve something the missing device can't do.
+        if requires_key(&request)
+            && let Err(e) = self.yubikey_manager.ensure_connected()
+        {
+            eprintln!("❌ {}\n", e);
+            let response = error_response("Error", e);
+            self.send_response(&event.pubkey, &id, response).await?;
+            return Ok(());
+        }
+
+        // A connect without this run's secret comes from an old or leaked URI.
+        if let NostrConnectRequest::Connect { secret, .. } = &request
+            && self.secret.is_some()
+            && *secret != self.secret
+        {
+            println!("❌ Connection refused: wrong secret\n");
+            let response = NostrConnectResponse::with_error("invalid secret");
+            self.send_response(&event.pubkey, &id, response).await?;
+            return Ok(());
+        }
+
         if !self.should_approve(&event.pubkey, &request) {
             println!("❌ Request denied by user\n");
 
@@ -138,7 +191,7 @@ impl YubikeyNostrBunker {
                     }
                     Err(e) => {
                         eprintln!("❌ Error signing: {}\n", e);
-                        NostrConnectResponse::with_error(format!("Error signing: {}", e))
+                        error_response("Error signing", e)
                     }
                 }
             }
@@ -155,7 +208,7 @@ impl YubikeyNostrBunker {
                             ciphertext,
                         })
                     }
-                    Err(e) => NostrConnectResponse::with_error(format!("Error: {}", e)),
+                    Err(e) => error_response("Error", e),
                 }
             }
             NostrConnectRequest::Nip04Decrypt {
@@ -174,7 +227,7 @@ impl YubikeyNostrBunker {
                             plaintext,
                         })
                     }
-                    Err(e) => NostrConnectResponse::with_error(format!("Error: {}", e)),
+                    Err(e) => error_response("Error", e),
                 }
             }
             NostrConnectRequest::Nip44Encrypt { public_key, text } => {
@@ -195,7 +248,7 @@ impl YubikeyNostrBunker {
                             ciphertext,
                         })
                     }
-                    Err(e) => NostrConnectResponse::with_error(format!("Error: {}", e)),
+                    Err(e) => error_response("Error", e),
                 }
             }
             NostrConnectRequest::Nip44Decrypt {
@@ -214,7 +267,7 @@ impl YubikeyNostrBunker {
                             plaintext,
                         })
                     }
-                    Err(e) => NostrConnectResponse::with_error(format!("Error: {}", e)),
+                    Err(e) => error_response("Error", e),
                 }
             }
             NostrConnectRequest::Ping => {
@@ -379,3 +432,39 @@ impl YubikeyNostrBunker {
         }
     }
 }
+
+fn requires_key(request: &NostrConnectRequest) -> bool {
+    matches!(
+        request,
+        NostrConnectRequest::SignEvent(_)
+            | NostrConnectRequest::Nip04Encrypt { .. }
+            | NostrConnectRequest::Nip04Decrypt { .. }
+            | NostrConnectRequest::Nip44Encrypt { .. }
+            | NostrConnectRequest::Nip44Decrypt { .. }
+    )
+}
+
+/// Maps library errors to the message NIP-46 clients receive.
+fn error_response(prefix: &str, error: Error) -> NostrConnectResponse {
+    let message = match error {
+        Error::SignerUnavailable | Error::DeviceNotFound => "signer unavailable".to_string(),
+        Error::PinInvalid { .. }
+        | Error::PinSourceRejected
+        | Error::PinSource(_)
+        | Error::PinBlocked
+        | Error::PinAuthBlocked
+        | Error::PinNotSet
+        | Error::PinRequired => "signer locked".to_string(),
+        Error::UserPresenceTimeout => "user presence timeout".to_string(),
+        Error::OperationDenied | Error::Cancelled => "denied on signer".to_string(),
+        Error::EntryNotFound
+        | Error::EntryCorrupted
+        | Error::VaultKeyMissing
+        | Error::PassphraseRequired
+        | Error::DecryptFailed
+        | Error::PublicKeyMismatch => "key unavailable".to_string(),
+        error => format!("{}: {}", prefix, error),
+    };
+
+    NostrConnectResponse::with_error(message)
+}
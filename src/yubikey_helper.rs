@@ -1,59 +1,219 @@
-use anyhow::{Context, Result, anyhow};
-use ctap_hid_fido2::fidokey::FidoKeyHid;
+use anyhow::anyhow;
 use nostr::prelude::*;
-use std::sync::Mutex;
+use sha2::{Digest, Sha256};
+use std::io::{self, Write};
+use std::sync::{Mutex, MutexGuard};
+use zeroize::Zeroizing;
 
-use crate::blob_operations;
-use crate::credential::get_credential_id;
-use crate::device::{find_fido_device, is_supported};
+use crate::authenticator::Authenticator;
+use crate::blob_format::{EntryRecord, EntryType};
+use crate::blob_operations::{self, BlobStore, EntryInfo};
+use crate::credential::{get_credential_id, get_hmac_secret, get_large_blob_key};
+use crate::device::{
+    DeviceSelector, find_fido_device, is_supported, list_fido_devices, open_fido_device,
+};
+use crate::error::{Error, Result};
+use crate::hid::HidAuthenticator;
+use crate::kdf::{KeyPurpose, derive_key};
+use crate::profile::Profile;
+use crate::secret::Secret;
+use crate::session::Session;
 
-pub struct YubikeyKeyManager {
-    device: Mutex<FidoKeyHid>,
+type Reopen<A> = Box<dyn Fn() -> Vec<A> + Send + Sync>;
+
+pub struct YubikeyKeyManager<A: Authenticator = HidAuthenticator> {
+    // `None` while the device is gone; filled again by `reconnect`.
+    device: Mutex<Option<A>>,
+    session: Mutex<Session>,
+    reopen: Reopen<A>,
+    profile: Profile,
     credential_id: Vec<u8>,
-    selected_entry_index: usize,
+    // Entries are looked up by ID, which survives deletes and reordering.
+    selected_entry_id: String,
+    // Kept beyond the PIN session so a reinserted device's entry can be
+    // compared without a PIN; it only opens the outer largeBlob layer.
+    large_blob_key: Option<Zeroizing<[u8; 32]>>,
     cached_public_key: PublicKey,
 }
 
 impl YubikeyKeyManager {
-    pub fn new() -> Result<Self> {
+    pub fn new(
+        selector: Option<&DeviceSelector>,
+        profile: Profile,
+        session: Session,
+        entry_id: Option<&str>,
+    ) -> Result<Self> {
         println!("🔑 Initializing YubiKey...");
 
-        let mut device =
-            find_fido_device().context("YubiKey not found. Connect the device and try again.")?;
+        let device = find_fido_device(selector)?;
+
+        let manager = Self::with_authenticator(device, profile, session, entry_id)?;
+
+        Ok(manager.with_reopen(|| {
+            list_fido_devices()
+                .iter()
+                .filter_map(|device| open_fido_device(device).ok())
+                .collect()
+        }))
+    }
+}
 
+impl<A: Authenticator + 'static> YubikeyKeyManager<A> {
+    /// Signs with the entry `entry_id`, or else the default entry, or else
+    /// the one chosen at the prompt.
+    pub fn with_authenticator(
+        mut device: A,
+        profile: Profile,
+        mut session: Session,
+        entry_id: Option<&str>,
+    ) -> Result<Self> {
         if !is_supported(&device)? {
-            return Err(anyhow!("This device does not support largeBlob"));
+            return Err(Error::LargeBlobUnsupported);
         }
 
-        let credential_id =
-            get_credential_id(&mut device).context("Failed to configure credential")?;
-
-        println!("✅ YubiKey configured successfully\n");
+        if !session.is_unlocked() {
+            session.unlock(&device)?;
+        }
 
-        let (selected_entry_index, key_data) =
-            blob_operations::select_and_read_entry(&mut device, &credential_id)
-                .context("Failed to select entry")?;
+        let credential_id = get_credential_id(&mut device, &mut session, &profile)?;
 
-        println!("\n🔍 Validating selected key...");
-        let key_hex = String::from_utf8(key_data).context("Invalid key data")?;
+        println!("✅ YubiKey configured successfully\n");
 
-        let keys = Keys::parse(&key_hex).context("Failed to parse private key")?;
+        let (selected_entry_id, recorded_public_key) = {
+            let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+            if store.migrate()? {
+                println!("📦 largeBlob migrated to the current format");
+            }
+            let entries = store.list()?;
+            let entry = select_entry(&entries, entry_id)?;
+            (entry.id.clone(), entry.public_key)
+        };
 
-        let cached_public_key = keys.public_key();
+        // The recorded public key is checked on every load, so only entries
+        // stored before it was recorded need decrypting now.
+        let cached_public_key = match recorded_public_key {
+            Some(public_key) => public_key,
+            None => {
+                println!("\n🔍 Validating selected key...");
+                let key_data = BlobStore::new(&mut device, &mut session, &profile, &credential_id)
+                    .get(&selected_entry_id)?;
+                let keys = parse_keys(key_data)?;
+                println!("✅ Valid key!");
+                keys.public_key()
+            }
+        };
 
-        drop(keys);
+        let large_blob_key =
+            get_large_blob_key(&mut device, &mut session, &profile, &credential_id)?;
 
-        println!("✅ Valid key!");
-        println!("   Pubkey: {}\n", cached_public_key.to_bech32()?);
+        let Ok(npub) = cached_public_key.to_bech32();
+        println!("   Pubkey: {}\n", npub);
 
         Ok(Self {
-            device: Mutex::new(device),
+            device: Mutex::new(Some(device)),
+            session: Mutex::new(session),
+            reopen: Box::new(Vec::new),
+            profile,
             credential_id,
-            selected_entry_index,
+            selected_entry_id,
+            large_blob_key,
             cached_public_key,
         })
     }
 
+    /// Sets how candidate devices are reopened after the current one is lost.
+    pub fn with_reopen<F>(mut self, reopen: F) -> Self
+    where
+        F: Fn() -> Vec<A> + Send + Sync + 'static,
+    {
+        self.reopen = Box::new(reopen);
+        self
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.device.lock().is_ok_and(|device| device.is_some())
+    }
+
+    /// Makes sure a device is available, reconnecting if the previous one was lost.
+    pub fn ensure_connected(&self) -> Result<()> {
+        let mut device = self.lock_device()?;
+        if device.is_none() {
+            *device = Some(self.reconnect()?);
+        }
+        Ok(())
+    }
+
+    /// Locks the PIN session, zeroizing the cached token and keys, if its
+    /// lifetime has ended. Meant to be called periodically by long-running
+    /// callers like the bunker.
+    pub fn lock_if_expired(&self) -> Result<()> {
+        self.lock_session()?.lock_if_expired();
+        Ok(())
+    }
+
+    fn lock_device(&self) -> Result<MutexGuard<'_, Option<A>>> {
+        self.device
+            .lock()
+            .map_err(|_| Error::Other(anyhow!("device lock poisoned")))
+    }
+
+    fn lock_session(&self) -> Result<MutexGuard<'_, Session>> {
+        self.session
+            .lock()
+            .map_err(|_| Error::Other(anyhow!("PIN session lock poisoned")))
+    }
+
+    /// Looks for a reinserted device holding the same entry and checks that it
+    /// still decrypts to the cached public key.
+    fn reconnect(&self) -> Result<A> {
+        let mut session = self.lock_session()?;
+
+        for mut candidate in (self.reopen)() {
+            // Look for the entry ID and its recorded public key first: that
+            // needs no PIN, so foreign authenticators never see a PIN attempt.
+            // The ciphertext isn't compared, as rotations and upgrades
+            // rewrite it.
+            match stored_entry(
+                &mut candidate,
+                self.large_blob_key.as_deref(),
+                &self.selected_entry_id,
+            ) {
+                Ok(Some(entry))
+                    if entry.public_key.as_deref().is_none_or(|recorded| {
+                        recorded == self.cached_public_key.to_bytes().as_slice()
+                    }) => {}
+                _ => continue,
+            }
+
+            println!("🔌 YubiKey reinserted, verifying key...");
+
+            // The reinserted device rejects the old token; the session asks
+            // for the PIN again only if a command here needs one.
+            let key_data = BlobStore::new(
+                &mut candidate,
+                &mut session,
+                &self.profile,
+                &self.credential_id,
+            )
+            .get(&self.selected_entry_id)?;
+
+            let keys = parse_keys(key_data)?;
+            if keys.public_key() != self.cached_public_key {
+                println!("⚠️  This device decrypts to a different public key; not using it.");
+                continue;
+            }
+
+            println!("✅ YubiKey reconnected\n");
+            return Ok(candidate);
+        }
+
+        Err(Error::SignerUnavailable)
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
     pub fn get_public_key(&self) -> Result<PublicKey> {
         Ok(self.cached_public_key)
     }
@@ -61,21 +221,36 @@ impl YubikeyKeyManager {
     pub fn load_private_key(&self) -> Result<Keys> {
         println!("🔐 Loading key from YubiKey for signing...");
 
-        let mut device = self
-            .device
-            .lock()
-            .map_err(|_| anyhow!("Failed to access device"))?;
+        let mut slot = self.lock_device()?;
+        if slot.is_none() {
+            *slot = Some(self.reconnect()?);
+        }
+        let Some(device) = slot.as_mut() else {
+            return Err(Error::SignerUnavailable);
+        };
 
-        let key_data = blob_operations::read_blob_entry_by_index(
-            &mut device,
-            &self.credential_id,
-            self.selected_entry_index,
-        )
-        .context("Failed to read entry from YubiKey")?;
+        let result = {
+            let mut session = self.lock_session()?;
+            BlobStore::new(device, &mut session, &self.profile, &self.credential_id)
+                .get(&self.selected_entry_id)
+        };
 
-        let key_hex = String::from_utf8(key_data).context("Invalid key data")?;
+        let key_data = match result {
+            Ok(key_data) => key_data,
+            // A failed command on a responsive device is a real error; if the
+            // device no longer answers at all, it was unplugged or reset.
+            Err(e) if device.get_authenticator_info().is_ok() => return Err(e),
+            Err(_) => {
+                *slot = None;
+                println!("🔌 YubiKey disconnected. Reinsert it to resume signing.\n");
+                return Err(Error::SignerUnavailable);
+            }
+        };
 
-        let keys = Keys::parse(&key_hex).context("Failed to parse private key")?;
+        let keys = parse_keys(key_data)?;
+        if keys.public_key() != self.cached_public_key {
+            return Err(Error::PublicKeyMismatch);
+        }
 
         println!("✅ Key loaded (will be discarded after use)\n");
 
@@ -84,12 +259,113 @@ impl YubikeyKeyManager {
 
     pub fn with_key<F, R>(&self, operation: F) -> Result<R>
     where
-        F: FnOnce(&Keys) -> Result<R>,
+        F: FnOnce(&Keys) -> anyhow::Result<R>,
     {
         let keys = self.load_private_key()?;
-        let result = operation(&keys);
+        let result = operation(&keys).map_err(Error::from);
         drop(keys);
         println!("🧹 Key removed from memory\n");
         result
     }
+
+    /// The bunker's NIP-46 transport keys: the hmac-secret output for a fixed
+    /// salt, run through HKDF under `KeyPurpose::BunkerTransport`. They depend
+    /// only on the credential, so the bunker's public key stays the same across
+    /// runs; its URI changes only by the connect secret.
+    pub fn transport_keys(&self) -> Result<Keys> {
+        let mut slot = self.lock_device()?;
+        if slot.is_none() {
+            *slot = Some(self.reconnect()?);
+        }
+        let Some(device) = slot.as_mut() else {
+            return Err(Error::SignerUnavailable);
+        };
+
+        let salt: [u8; 32] = Sha256::digest(KeyPurpose::BunkerTransport.label().as_bytes()).into();
+        let output = Zeroizing::new(get_hmac_secret(
+            device,
+            &mut *self.lock_session()?,
+            &self.profile,
+            &self.credential_id,
+            &salt,
+        )?);
+        let key = derive_key(output.as_ref(), KeyPurpose::BunkerTransport);
+        let secret_key = SecretKey::from_slice(key.as_ref())
+            .map_err(|e| anyhow!("derived transport key is not a valid secret key: {e}"))?;
+        Ok(Keys::new(secret_key))
+    }
+}
+
+fn stored_entry(
+    device: &mut impl Authenticator,
+    large_blob_key: Option<&[u8; 32]>,
+    id: &str,
+) -> Result<Option<EntryRecord>> {
+    Ok(blob_operations::read_entries(device, large_blob_key)?
+        .entries
+        .into_iter()
+        .find(|entry| entry.id == id))
+}
+
+fn can_sign(entry_type: EntryType) -> bool {
+    matches!(entry_type, EntryType::NostrSecretKey | EntryType::Mnemonic)
+}
+
+fn select_entry<'e>(entries: &'e [EntryInfo], entry_id: Option<&str>) -> Result<&'e EntryInfo> {
+    if let Some(id) = entry_id {
+        let entry = entries
+            .iter()
+            .find(|entry| entry.id == id)
+            .ok_or(Error::EntryNotFound)?;
+        if !can_sign(entry.entry_type) {
+            return Err(Error::InvalidInput(format!(
+                "'{}' holds a {}, which cannot sign",
+                id, entry.entry_type
+            )));
+        }
+        return Ok(entry);
+    }
+
+    let signing: Vec<_> = entries
+        .iter()
+        .filter(|entry| can_sign(entry.entry_type))
+        .collect();
+
+    if let Some(entry) = signing.iter().find(|entry| entry.default) {
+        println!("🔑 Using default entry: {}", entry);
+        return Ok(entry);
+    }
+
+    Ok(signing[choose_entry(&signing)?])
+}
+
+fn choose_entry(entries: &[&EntryInfo]) -> Result<usize> {
+    if entries.is_empty() {
+        return Err(Error::EntryNotFound);
+    }
+
+    println!("\n📋 Entries:");
+    for (position, entry) in entries.iter().enumerate() {
+        println!("   {}. {}", position + 1, entry);
+    }
+
+    print!("\n🔑 Choose entry (1-{}): ", entries.len());
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let choice: usize = input
+        .trim()
+        .parse()
+        .map_err(|_| Error::InvalidInput(input.trim().to_string()))?;
+
+    if choice == 0 || choice > entries.len() {
+        return Err(Error::InvalidInput(format!("no entry {}", choice)));
+    }
+
+    Ok(choice - 1)
+}
+
+fn parse_keys(secret: Secret) -> Result<Keys> {
+    secret.keys().ok_or(Error::EntryCorrupted)
 }
@@ -0,0 +1,1318 @@
+use ctap_hid_fido2::fidokey::{
+    get_info::InfoOption, large_blobs::large_blobs_params::LargeBlobData,
+};
+use ctap_hid_fido2::public_key_credential_user_entity::PublicKeyCredentialUserEntity;
+use nostr::prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
+use yubikey_fido2_teste::{
+    Algorithm, AssertionRequest, AssertionResponse, Authenticator, AuthenticatorInfo, Backup,
+    BlobStore, Cipher, CtapStatus, EntryStatus, EntryType, Error, Permissions, PinProvider,
+    PinUvAuthToken, Profile, PutOptions, ResidentCredential, Secret, Session, SoftAuthenticator,
+    WrappedVaultKey, YubikeyKeyManager,
+    blob_format::{self, EntryRecord, ParsedBlob},
+    credential::{get_hmac_secret, get_large_blob_key, get_large_blob_key_and_hmac_secret},
+    encrypt_data, get_credential_id,
+    large_blob::LargeBlobArray,
+    snapshot::remove_snapshot,
+};
+use zeroize::Zeroizing;
+
+const PIN: &str = "123456";
+const NSEC_HEX: &str = "6b911fd37cdf5c81d4c0adb1ab7fa822ed253ab0ad9aa18d77257c88b29b718e";
+
+// Snapshots go to a temporary directory instead of the user's data directory.
+fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let dir = std::env::temp_dir().join(format!("yns-soft-tests-{}", std::process::id()));
+        // SAFETY: set once, before any test reads the environment.
+        unsafe { std::env::set_var("SNAPSHOT_DIR", dir) };
+    });
+}
+
+// A configured PIN source: answers every PIN prompt with the PIN, and counts
+// them. With a passphrase it stands for someone at the terminal, who also
+// answers entry passphrase prompts.
+#[derive(Default)]
+struct TestPin {
+    prompts: AtomicUsize,
+    passphrase: Option<&'static str>,
+}
+
+impl TestPin {
+    fn with_passphrase(passphrase: &'static str) -> Self {
+        Self {
+            passphrase: Some(passphrase),
+            ..Self::default()
+        }
+    }
+
+    fn prompts(&self) -> usize {
+        self.prompts.load(Ordering::SeqCst)
+    }
+}
+
+impl PinProvider for TestPin {
+    fn get_pin(&self, prompt: &str) -> Result<Zeroizing<String>, Error> {
+        if let Some(passphrase) = self.passphrase
+            && prompt.starts_with("Passphrase")
+        {
+            return Ok(Zeroizing::new(passphrase.to_string()));
+        }
+        self.prompts.fetch_add(1, Ordering::SeqCst);
+        Ok(Zeroizing::new(PIN.to_string()))
+    }
+
+    fn is_interactive(&self) -> bool {
+        self.passphrase.is_some()
+    }
+}
+
+fn unlocked(device: &SoftAuthenticator) -> Session {
+    unlocked_with(device, TestPin::default())
+}
+
+fn unlocked_with(device: &SoftAuthenticator, pin_provider: TestPin) -> Session {
+    let mut session = Session::default().with_pin_provider(Arc::new(pin_provider));
+    session
+        .unlock_with_pin(device, Zeroizing::new(PIN.to_string()))
+        .unwrap();
+    session
+}
+
+// This credential's container as it is on the device.
+fn stored_container(device: &SoftAuthenticator, large_blob_key: &[u8; 32]) -> ParsedBlob {
+    let array = LargeBlobArray::parse(&device.get_large_blob().unwrap().large_blob_array).unwrap();
+    blob_format::decode(&array.get(large_blob_key).unwrap()).unwrap()
+}
+
+// Replaces this credential's container on the device, as another tool could.
+fn write_container(
+    device: &SoftAuthenticator,
+    session: &mut Session,
+    large_blob_key: &[u8; 32],
+    entries: &[EntryRecord],
+    vault: Option<&WrappedVaultKey>,
+) {
+    let mut array =
+        LargeBlobArray::parse(&device.get_large_blob().unwrap().large_blob_array).unwrap();
+    array
+        .set(
+            large_blob_key,
+            &blob_format::encode(entries, vault).unwrap(),
+        )
+        .unwrap();
+    let data = array.serialize().unwrap();
+    session
+        .with_token(device, |device, token| {
+            device.write_large_blob(token, data.clone())
+        })
+        .unwrap();
+}
+
+fn secret_key() -> Secret {
+    Secret::parse(EntryType::NostrSecretKey, NSEC_HEX).unwrap()
+}
+
+#[test]
+fn stores_reads_and_deletes_entries() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+
+    store.put("main", &secret_key(), false).unwrap();
+    let note = Secret::parse(EntryType::Opaque, "correct horse battery staple").unwrap();
+    store.put("note", &note, false).unwrap();
+    assert!(matches!(
+        store.put("main", &note, false),
+        Err(Error::EntryExists)
+    ));
+
+    let ids: Vec<_> = store.list().unwrap().into_iter().map(|e| e.id).collect();
+    assert_eq!(ids, ["main", "note"]);
+    assert_eq!(
+        store.get("main").unwrap().as_bytes(),
+        secret_key().as_bytes()
+    );
+    assert_eq!(store.get("note").unwrap().as_bytes(), note.as_bytes());
+
+    store.delete("main").unwrap();
+    assert!(matches!(store.get("main"), Err(Error::EntryNotFound)));
+    assert_eq!(store.list().unwrap().len(), 1);
+}
+
+#[test]
+fn signs_with_the_selected_entry() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    BlobStore::new(&mut device, &mut session, &profile, &credential_id)
+        .put("main", &secret_key(), false)
+        .unwrap();
+
+    let manager =
+        YubikeyKeyManager::with_authenticator(device, profile, session, Some("main")).unwrap();
+    let expected = secret_key().public_key().unwrap();
+    assert_eq!(manager.get_public_key().unwrap(), expected);
+
+    let event = manager
+        .with_key(|keys| Ok(EventBuilder::text_note("hello").sign_with_keys(keys)?))
+        .unwrap();
+    assert_eq!(event.pubkey, expected);
+    assert!(event.verify().is_ok());
+}
+
+#[test]
+fn rejects_writes_past_the_large_blob_capacity() {
+    init();
+    let mut device = SoftAuthenticator::new()
+        .with_pin(PIN)
+        .with_max_large_blob(2048);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+
+    // Random bytes, so compression can't make them fit.
+    let noise: Vec<u8> = (0..1500).map(|_| rand::random()).collect();
+    let large = Secret::parse(EntryType::Opaque, &hex::encode(noise)).unwrap();
+    assert!(matches!(
+        store.put("large", &large, false),
+        Err(Error::BlobFull { capacity: 2032, .. })
+    ));
+    assert!(store.list().unwrap().is_empty());
+}
+
+// Reports twice the largeBlob room the wrapped authenticator really has.
+struct Overstated(SoftAuthenticator);
+
+impl Authenticator for Overstated {
+    fn get_pin_uv_auth_token(
+        &self,
+        pin: &str,
+        permissions: Permissions,
+    ) -> anyhow::Result<PinUvAuthToken> {
+        self.0.get_pin_uv_auth_token(pin, permissions)
+    }
+
+    fn get_assertion(
+        &self,
+        request: &AssertionRequest<'_>,
+        token: &PinUvAuthToken,
+    ) -> anyhow::Result<AssertionResponse> {
+        self.0.get_assertion(request, token)
+    }
+
+    fn make_credential(
+        &self,
+        rp_id: &str,
+        user: &PublicKeyCredentialUserEntity,
+        token: &PinUvAuthToken,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.0.make_credential(rp_id, user, token)
+    }
+
+    fn get_large_blob(&self) -> anyhow::Result<LargeBlobData> {
+        self.0.get_large_blob()
+    }
+
+    fn write_large_blob(&self, token: &PinUvAuthToken, data: Vec<u8>) -> anyhow::Result<()> {
+        self.0.write_large_blob(token, data)
+    }
+
+    fn enable_info_option(&self, info_option: &InfoOption) -> anyhow::Result<Option<bool>> {
+        self.0.enable_info_option(info_option)
+    }
+
+    fn get_authenticator_info(&self) -> anyhow::Result<AuthenticatorInfo> {
+        let mut info = self.0.get_authenticator_info()?;
+        info.max_serialized_large_blob_array *= 2;
+        Ok(info)
+    }
+
+    fn get_pin_retries(&self) -> anyhow::Result<i32> {
+        self.0.get_pin_retries()
+    }
+
+    fn set_new_pin(&self, pin: &str) -> anyhow::Result<()> {
+        self.0.set_new_pin(pin)
+    }
+
+    fn change_pin(&self, current_pin: &str, new_pin: &str) -> anyhow::Result<()> {
+        self.0.change_pin(current_pin, new_pin)
+    }
+
+    fn enumerate_credentials(
+        &self,
+        token: &PinUvAuthToken,
+        rpid: &str,
+    ) -> anyhow::Result<Vec<ResidentCredential>> {
+        self.0.enumerate_credentials(token, rpid)
+    }
+
+    fn delete_credential(
+        &self,
+        token: &PinUvAuthToken,
+        credential_id: &[u8],
+    ) -> anyhow::Result<()> {
+        self.0.delete_credential(token, credential_id)
+    }
+}
+
+#[test]
+fn reports_the_sizes_when_the_authenticator_runs_out_of_room() {
+    init();
+    let mut device = Overstated(
+        SoftAuthenticator::new()
+            .with_pin(PIN)
+            .with_max_large_blob(2048),
+    );
+    let mut session = Session::default().with_pin_provider(Arc::new(TestPin::default()));
+    session
+        .unlock_with_pin(&device, Zeroizing::new(PIN.to_string()))
+        .unwrap();
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+
+    let noise: Vec<u8> = (0..1500).map(|_| rand::random()).collect();
+    let large = Secret::parse(EntryType::Opaque, &hex::encode(noise)).unwrap();
+    match store.put("large", &large, false) {
+        Err(Error::BlobFull { needed, capacity }) => {
+            assert_eq!(capacity, 4096 - 16);
+            assert!(needed > 2048 - 16 && needed <= capacity);
+        }
+        other => panic!("expected BlobFull, got {other:?}"),
+    }
+}
+
+#[test]
+fn maps_authenticator_statuses_to_errors() {
+    let error = |status: CtapStatus| {
+        Error::from(anyhow::Error::new(status).context("authenticatorGetAssertion failed"))
+    };
+
+    assert!(matches!(
+        error(CtapStatus::PIN_INVALID),
+        Error::PinInvalid { .. }
+    ));
+    assert!(matches!(
+        error(CtapStatus::PIN_AUTH_INVALID),
+        Error::PinTokenRejected
+    ));
+    assert!(matches!(
+        error(CtapStatus::PIN_AUTH_BLOCKED),
+        Error::PinAuthBlocked
+    ));
+    assert!(matches!(error(CtapStatus::PIN_BLOCKED), Error::PinBlocked));
+    assert!(matches!(error(CtapStatus::PIN_NOT_SET), Error::PinNotSet));
+    assert!(matches!(
+        error(CtapStatus::PUAT_REQUIRED),
+        Error::PinRequired
+    ));
+    assert!(matches!(
+        error(CtapStatus::PIN_POLICY_VIOLATION),
+        Error::PinPolicyViolation
+    ));
+    assert!(matches!(
+        error(CtapStatus::USER_ACTION_TIMEOUT),
+        Error::UserPresenceTimeout
+    ));
+    assert!(matches!(
+        error(CtapStatus::OPERATION_DENIED),
+        Error::OperationDenied
+    ));
+    assert!(matches!(
+        error(CtapStatus::KEEPALIVE_CANCEL),
+        Error::OperationDenied
+    ));
+    assert!(matches!(
+        error(CtapStatus::NO_CREDENTIALS),
+        Error::NoCredentials
+    ));
+    assert!(matches!(
+        error(CtapStatus::KEY_STORE_FULL),
+        Error::KeyStoreFull
+    ));
+    // Only the store knows the sizes to report with a full largeBlob.
+    assert!(matches!(
+        error(CtapStatus::LARGE_BLOB_STORAGE_FULL),
+        Error::Ctap(message) if message == "0x18 CTAP2_ERR_LARGE_BLOB_STORAGE_FULL"
+    ));
+    assert!(matches!(
+        error(CtapStatus(0x7F)),
+        Error::Ctap(message) if message == "0x7F unknown status"
+    ));
+    assert!(matches!(
+        Error::from(anyhow::anyhow!("CTAP2_ERR_PIN_INVALID")),
+        Error::Other(_)
+    ));
+}
+
+#[test]
+fn locks_out_after_consecutive_wrong_pins_until_power_cycle() {
+    let device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = Session::default();
+
+    for _ in 0..2 {
+        let result = session.unlock_with_pin(&device, Zeroizing::new("000000".to_string()));
+        assert!(matches!(result, Err(Error::PinInvalid { .. })));
+    }
+    let result = session.unlock_with_pin(&device, Zeroizing::new("000000".to_string()));
+    assert!(matches!(result, Err(Error::PinAuthBlocked)));
+    let result = session.unlock_with_pin(&device, Zeroizing::new(PIN.to_string()));
+    assert!(matches!(result, Err(Error::PinAuthBlocked)));
+
+    device.power_cycle();
+    session
+        .unlock_with_pin(&device, Zeroizing::new(PIN.to_string()))
+        .unwrap();
+    assert_eq!(device.get_pin_retries().unwrap(), 8);
+}
+
+#[test]
+fn answers_hmac_secret_for_two_salts_in_one_assertion() {
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let credential_ids = [credential_id];
+
+    // Each assertion takes the token's getAssertion permission away.
+    let outputs = |salts: &[[u8; 32]]| {
+        let token = device
+            .get_pin_uv_auth_token(PIN, Permissions::GET_ASSERTION)
+            .unwrap();
+        let request = AssertionRequest {
+            rp_id: &profile.rp_id,
+            credential_ids: &credential_ids,
+            hmac_secret_salts: salts,
+            large_blob_key: true,
+        };
+        let assertion = device.get_assertion(&request, &token).unwrap();
+        assert!(assertion.large_blob_key.is_some());
+        assertion
+            .hmac_secret
+            .into_iter()
+            .map(|output| *output)
+            .collect::<Vec<_>>()
+    };
+
+    let both = outputs(&[[1u8; 32], [2u8; 32]]);
+    assert_eq!(both.len(), 2);
+    assert_eq!(both[..1], outputs(&[[1u8; 32]]));
+    assert_eq!(both[1..], outputs(&[[2u8; 32]]));
+    assert_ne!(both[0], both[1]);
+}
+
+#[test]
+fn fetches_the_large_blob_key_and_hmac_secret_in_one_assertion() {
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let salt = [7u8; 32];
+
+    let before = device.assertions();
+    let assertion = get_large_blob_key_and_hmac_secret(
+        &mut device,
+        &mut session,
+        &profile,
+        &credential_id,
+        &salt,
+    )
+    .unwrap();
+    assert_eq!(device.assertions(), before + 1);
+    assert!(assertion.large_blob_key.is_some());
+
+    // The largeBlobKey is cached, and the output is the one for the salt alone.
+    assert_eq!(
+        get_large_blob_key(&mut device, &mut session, &profile, &credential_id).unwrap(),
+        assertion.large_blob_key
+    );
+    assert_eq!(device.assertions(), before + 1);
+    assert_eq!(
+        get_hmac_secret(&mut device, &mut session, &profile, &credential_id, &salt).unwrap(),
+        *assertion.hmac_secret[0]
+    );
+}
+
+#[test]
+fn unlocks_the_vault_in_the_large_blob_key_assertion() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    BlobStore::new(&mut device, &mut session, &profile, &credential_id)
+        .put("main", &secret_key(), false)
+        .unwrap();
+
+    // A new session holds no keys; the snapshot header names the vault.
+    let mut session = unlocked(&device);
+    let before = device.assertions();
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    assert_eq!(
+        store.get("main").unwrap().as_bytes(),
+        secret_key().as_bytes()
+    );
+    assert_eq!(device.assertions(), before + 1);
+
+    // Without a snapshot, the vault is only known once the array is read.
+    remove_snapshot(&profile, &credential_id).unwrap();
+    let mut session = unlocked(&device);
+    let before = device.assertions();
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    assert_eq!(
+        store.get("main").unwrap().as_bytes(),
+        secret_key().as_bytes()
+    );
+    assert_eq!(device.assertions(), before + 2);
+}
+
+#[test]
+fn asks_for_the_pin_again_once_the_token_is_used_up() {
+    let provider = Arc::new(TestPin::default());
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = Session::default().with_pin_provider(provider.clone());
+    session.unlock(&device).unwrap();
+    let profile = Profile::default();
+
+    // Looked up by credential management, then registered, on the same token.
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    assert_eq!(provider.prompts(), 1);
+
+    // Registering took the token's getAssertion permission, not largeBlobWrite.
+    session
+        .with_token(&device, |device, token| {
+            device.write_large_blob(token, vec![0x80])
+        })
+        .unwrap();
+    assert_eq!(provider.prompts(), 1);
+    get_hmac_secret(
+        &mut device,
+        &mut session,
+        &profile,
+        &credential_id,
+        &[1u8; 32],
+    )
+    .unwrap();
+    assert_eq!(provider.prompts(), 2);
+
+    // Unplugging invalidates the token.
+    device.power_cycle();
+    session
+        .with_token(&device, |device, token| {
+            device.write_large_blob(token, vec![0x80])
+        })
+        .unwrap();
+    assert_eq!(provider.prompts(), 3);
+}
+
+#[test]
+fn locks_the_session_once_it_expires() {
+    let device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = Session::new(std::time::Duration::ZERO);
+    session
+        .unlock_with_pin(&device, Zeroizing::new(PIN.to_string()))
+        .unwrap();
+    session.cache_vault_key(b"wrapped", Zeroizing::new([7u8; 32]));
+
+    assert!(session.vault_key(b"wrapped").is_none());
+    assert!(!session.lock_if_expired());
+}
+
+#[test]
+fn rejects_a_token_without_the_permission() {
+    let device = SoftAuthenticator::new().with_pin(PIN);
+    let token = device
+        .get_pin_uv_auth_token(PIN, Permissions::GET_ASSERTION)
+        .unwrap();
+    let error = device.write_large_blob(&token, Vec::new()).unwrap_err();
+    assert!(matches!(Error::from(error), Error::PinTokenRejected));
+}
+
+#[test]
+fn migrates_the_legacy_byte_string_element_into_the_credential_element() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    BlobStore::new(&mut device, &mut session, &profile, &credential_id)
+        .put("main", &secret_key(), false)
+        .unwrap();
+
+    // Lay the container out the way earlier versions did: a bare byte string
+    // in the array instead of an encrypted map.
+    let key = get_large_blob_key(&mut device, &mut session, &profile, &credential_id)
+        .unwrap()
+        .unwrap();
+    let array = device.get_large_blob().unwrap().large_blob_array;
+    let container = LargeBlobArray::parse(&array).unwrap().get(&key).unwrap();
+    let mut legacy = Vec::new();
+    ciborium::into_writer(
+        &ciborium::Value::Array(vec![ciborium::Value::Bytes(container.to_vec())]),
+        &mut legacy,
+    )
+    .unwrap();
+    let token = device
+        .get_pin_uv_auth_token(PIN, Permissions::LARGE_BLOB_WRITE)
+        .unwrap();
+    device.write_large_blob(&token, legacy).unwrap();
+
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    assert!(store.list().unwrap()[0].shared);
+    assert!(store.migrate().unwrap());
+    assert_eq!(
+        store.get("main").unwrap().as_bytes(),
+        secret_key().as_bytes()
+    );
+
+    let array = device.get_large_blob().unwrap().large_blob_array;
+    let elements: ciborium::Value = ciborium::from_reader(array.as_slice()).unwrap();
+    let elements = elements.into_array().unwrap();
+    assert_eq!(elements.len(), 1);
+    assert!(elements[0].is_map());
+
+    // Reading leaves the device alone; taking the entry over is an explicit write.
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    store.get("main").unwrap();
+    assert_eq!(device.get_large_blob().unwrap().large_blob_array, array);
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    assert_eq!(store.upgrade_encryption().unwrap(), 1);
+    assert!(!store.list().unwrap()[0].needs_upgrade);
+}
+
+#[test]
+fn stores_the_petname_with_the_entry() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+
+    let options = PutOptions {
+        petname: Some(" alice "),
+        ..PutOptions::default()
+    };
+    store.put_with("main", &secret_key(), &options).unwrap();
+    assert_eq!(store.list().unwrap()[0].petname.as_deref(), Some("alice"));
+
+    // Overwriting without a petname keeps the old one.
+    store.put("main", &secret_key(), true).unwrap();
+    assert_eq!(store.list().unwrap()[0].petname.as_deref(), Some("alice"));
+}
+
+#[test]
+fn restores_the_snapshot_of_its_own_credential() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+
+    store.put("main", &secret_key(), false).unwrap();
+    let note = Secret::parse(EntryType::Opaque, "correct horse battery staple").unwrap();
+    store.put("note", &note, false).unwrap();
+    assert!(store.snapshot_path().exists());
+
+    store.restore_snapshot().unwrap();
+    let ids: Vec<_> = store.list().unwrap().into_iter().map(|e| e.id).collect();
+    assert_eq!(ids, ["main"]);
+    store.restore_snapshot().unwrap();
+    assert_eq!(store.list().unwrap().len(), 2);
+}
+
+#[test]
+fn restores_only_its_own_element_under_the_recorded_vault() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let other = Profile::new("other").unwrap();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let other_id = get_credential_id(&mut device, &mut session, &other).unwrap();
+    let note = Secret::parse(EntryType::Opaque, "correct horse battery staple").unwrap();
+
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    store.put("main", &secret_key(), false).unwrap();
+    store.put("note", &note, false).unwrap();
+    drop(store);
+    // Written after this credential's snapshot was taken.
+    BlobStore::new(&mut device, &mut session, &other, &other_id)
+        .put("theirs", &note, false)
+        .unwrap();
+
+    let large_blob_key = get_large_blob_key(&mut device, &mut session, &profile, &credential_id)
+        .unwrap()
+        .unwrap();
+    let vault = stored_container(&device, &large_blob_key).vault;
+
+    // A new session: the largeBlobKey assertion also opens the snapshot.
+    let mut session = unlocked(&device);
+    let before = device.assertions();
+    BlobStore::new(&mut device, &mut session, &profile, &credential_id)
+        .restore_snapshot()
+        .unwrap();
+    assert_eq!(device.assertions(), before + 1);
+    assert_eq!(stored_container(&device, &large_blob_key).vault, vault);
+
+    let ids: Vec<_> = BlobStore::new(&mut device, &mut session, &profile, &credential_id)
+        .list()
+        .unwrap()
+        .into_iter()
+        .map(|e| e.id)
+        .collect();
+    assert_eq!(ids, ["main"]);
+    let ids: Vec<_> = BlobStore::new(&mut device, &mut session, &other, &other_id)
+        .list()
+        .unwrap()
+        .into_iter()
+        .map(|e| e.id)
+        .collect();
+    assert_eq!(ids, ["theirs"]);
+}
+
+#[test]
+fn derives_the_same_transport_key_every_run() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    BlobStore::new(&mut device, &mut session, &profile, &credential_id)
+        .put("main", &secret_key(), false)
+        .unwrap();
+
+    let manager =
+        YubikeyKeyManager::with_authenticator(device, profile, session, Some("main")).unwrap();
+    let transport = manager.transport_keys().unwrap();
+    assert_eq!(
+        manager.transport_keys().unwrap().public_key(),
+        transport.public_key()
+    );
+    assert_ne!(transport.public_key(), secret_key().public_key().unwrap());
+}
+
+#[test]
+fn rotates_the_vault_in_one_assertion() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let note = Secret::parse(EntryType::Opaque, "correct horse battery staple").unwrap();
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    store.put("main", &secret_key(), false).unwrap();
+    store.put("note", &note, false).unwrap();
+    drop(store);
+
+    let large_blob_key = get_large_blob_key(&mut device, &mut session, &profile, &credential_id)
+        .unwrap()
+        .unwrap();
+    let old_vault = stored_container(&device, &large_blob_key).vault.unwrap();
+
+    let before = device.assertions();
+    let rotated = BlobStore::new(&mut device, &mut session, &profile, &credential_id)
+        .rotate()
+        .unwrap();
+    assert_eq!(rotated, 2);
+    assert_eq!(device.assertions(), before + 1);
+
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    assert_eq!(
+        store.get("main").unwrap().as_bytes(),
+        secret_key().as_bytes()
+    );
+    assert_eq!(store.get("note").unwrap().as_bytes(), note.as_bytes());
+    drop(store);
+
+    // The entries as rotated, put back under the old vault.
+    let rotated = stored_container(&device, &large_blob_key);
+    assert_ne!(rotated.vault.as_ref(), Some(&old_vault));
+    write_container(
+        &device,
+        &mut session,
+        &large_blob_key,
+        &rotated.entries,
+        Some(&old_vault),
+    );
+
+    let mut session = unlocked(&device);
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    assert!(matches!(store.get("main"), Err(Error::DecryptFailed)));
+    assert!(matches!(store.get("note"), Err(Error::DecryptFailed)));
+}
+
+#[test]
+fn binds_entries_to_their_id_type_and_format_version() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let note = Secret::parse(EntryType::Opaque, "correct horse battery staple").unwrap();
+    BlobStore::new(&mut device, &mut session, &profile, &credential_id)
+        .put("note", &note, false)
+        .unwrap();
+    let large_blob_key = get_large_blob_key(&mut device, &mut session, &profile, &credential_id)
+        .unwrap()
+        .unwrap();
+    let stored = stored_container(&device, &large_blob_key);
+
+    type Tamper = fn(&mut EntryRecord);
+    let tampered: [(&str, Tamper); 3] = [
+        ("moved", |entry| entry.id = "moved".to_string()),
+        ("note", |entry| entry.entry_type = EntryType::Mnemonic),
+        ("note", |entry| entry.format_version = Some(1)),
+    ];
+    for (id, tamper) in tampered {
+        let mut entries = stored.entries.clone();
+        tamper(&mut entries[0]);
+        write_container(
+            &device,
+            &mut session,
+            &large_blob_key,
+            &entries,
+            stored.vault.as_ref(),
+        );
+
+        let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+        assert!(matches!(store.get(id), Err(Error::DecryptFailed)));
+    }
+}
+
+#[test]
+fn rejects_argon2_parameters_above_the_limits() {
+    init();
+    let sealed = Backup::new(Vec::new())
+        .seal("backup passphrase", Cipher::default())
+        .unwrap();
+    let (prefix, rest) = sealed.split_at(5);
+    let header_len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+    let (header, ciphertext) = rest[4..].split_at(header_len);
+    let mut header: ciborium::Value = ciborium::from_reader(header).unwrap();
+    let passphrase = header
+        .as_map_mut()
+        .unwrap()
+        .iter_mut()
+        .find(|(key, _)| key.as_text() == Some("passphrase"))
+        .unwrap();
+    for (key, value) in passphrase.1.as_map_mut().unwrap() {
+        if key.as_text() == Some("memory") {
+            *value = ciborium::Value::from(4u32 * 1024 * 1024);
+        }
+    }
+    let mut encoded = Vec::new();
+    ciborium::into_writer(&header, &mut encoded).unwrap();
+    let mut crafted = prefix.to_vec();
+    crafted.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+    crafted.extend(encoded);
+    crafted.extend_from_slice(ciphertext);
+    assert!(matches!(
+        Backup::open(&crafted, "backup passphrase"),
+        Err(Error::BackupCorrupted)
+    ));
+
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    BlobStore::new(&mut device, &mut session, &profile, &credential_id)
+        .put_with_passphrase("main", &secret_key(), "entry passphrase", false)
+        .unwrap();
+    let large_blob_key = get_large_blob_key(&mut device, &mut session, &profile, &credential_id)
+        .unwrap()
+        .unwrap();
+    let mut stored = stored_container(&device, &large_blob_key);
+    stored.entries[0].passphrase.as_mut().unwrap().iterations = 1 << 20;
+    write_container(
+        &device,
+        &mut session,
+        &large_blob_key,
+        &stored.entries,
+        stored.vault.as_ref(),
+    );
+
+    // Rejected before the passphrase is even asked for.
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    assert!(matches!(store.get("main"), Err(Error::EntryCorrupted)));
+}
+
+const BACKUP_PASSPHRASE: &str = "backup passphrase";
+
+fn sample_backup() -> Backup {
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked_with(&device, TestPin::with_passphrase("entry passphrase"));
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let note = Secret::parse(EntryType::Opaque, "correct horse battery staple").unwrap();
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    store
+        .put_with(
+            "main",
+            &secret_key(),
+            &PutOptions {
+                petname: Some("Alice"),
+                ..PutOptions::default()
+            },
+        )
+        .unwrap();
+    store
+        .put_with_passphrase("note", &note, "entry passphrase", false)
+        .unwrap();
+    store.set_default(Some("main")).unwrap();
+    store.export_backup().unwrap()
+}
+
+#[test]
+fn seals_and_opens_a_backup() {
+    init();
+    let backup = sample_backup();
+    let sealed = backup.seal(BACKUP_PASSPHRASE, Cipher::default()).unwrap();
+    let opened = Backup::open(&sealed, BACKUP_PASSPHRASE).unwrap();
+
+    assert_eq!(opened.created_at, backup.created_at);
+    assert_eq!(opened.entries.len(), 2);
+    for (opened, entry) in opened.entries.iter().zip(&backup.entries) {
+        assert_eq!(opened.id, entry.id);
+        assert_eq!(opened.entry_type, entry.entry_type);
+        assert_eq!(opened.petname, entry.petname);
+        assert_eq!(opened.default, entry.default);
+        assert_eq!(opened.secret, entry.secret);
+        assert_eq!(
+            opened.passphrase.as_ref().map(|p| &p.key),
+            entry.passphrase.as_ref().map(|p| &p.key)
+        );
+    }
+    assert!(
+        opened
+            .verify()
+            .iter()
+            .all(|(_, status)| *status == EntryStatus::Ok)
+    );
+}
+
+#[test]
+fn refuses_a_backup_with_the_wrong_passphrase_or_a_changed_header() {
+    init();
+    let sealed = sample_backup()
+        .seal(BACKUP_PASSPHRASE, Cipher::default())
+        .unwrap();
+    assert!(matches!(
+        Backup::open(&sealed, "wrong passphrase"),
+        Err(Error::DecryptFailed)
+    ));
+
+    // The header ends with `created_at`; its last byte still parses when flipped.
+    let header_len = u32::from_be_bytes(sealed[5..9].try_into().unwrap()) as usize;
+    let mut changed = sealed.clone();
+    changed[9 + header_len - 1] ^= 0x01;
+    assert!(matches!(
+        Backup::open(&changed, BACKUP_PASSPHRASE),
+        Err(Error::DecryptFailed)
+    ));
+}
+
+#[test]
+fn imports_a_backup_onto_another_authenticator() {
+    init();
+    let sealed = sample_backup()
+        .seal(BACKUP_PASSPHRASE, Cipher::default())
+        .unwrap();
+    let backup = Backup::open(&sealed, BACKUP_PASSPHRASE).unwrap();
+
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let imported = BlobStore::new(&mut device, &mut session, &profile, &credential_id)
+        .import_backup(&backup, false)
+        .unwrap();
+    assert_eq!(imported, 2);
+
+    let mut session = unlocked_with(&device, TestPin::with_passphrase("entry passphrase"));
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    let entries = store.list().unwrap();
+    let ids: Vec<_> = entries.iter().map(|e| e.id.as_str()).collect();
+    assert_eq!(ids, ["main", "note"]);
+    assert_eq!(entries[0].petname.as_deref(), Some("Alice"));
+    assert!(entries[0].default && !entries[1].default);
+    assert!(!entries[0].passphrase && entries[1].passphrase);
+    assert_eq!(
+        store.get("main").unwrap().as_bytes(),
+        secret_key().as_bytes()
+    );
+    assert_eq!(
+        store.get("note").unwrap().as_bytes(),
+        b"correct horse battery staple"
+    );
+}
+
+#[test]
+fn reads_and_migrates_the_three_legacy_text_encodings() {
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+
+    // Encrypted as older versions did: keyed by the hmac-secret output
+    // directly, without associated data.
+    let plaintext = secret_key().as_bytes().to_vec();
+    let mut encrypted = || {
+        encrypt_data(
+            &mut device,
+            &mut session,
+            &profile,
+            &credential_id,
+            None,
+            &plaintext,
+            &[],
+        )
+        .unwrap()
+    };
+    let (named, base64, hex) = (encrypted(), encrypted(), encrypted());
+    let legacy = format!(
+        "main:{}|{}|{}|",
+        STANDARD.encode(&named),
+        STANDARD.encode(&base64),
+        hex::encode(&hex)
+    );
+    session
+        .with_token(&device, |device, token| {
+            device.write_large_blob(token, legacy.clone().into_bytes())
+        })
+        .unwrap();
+
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    let entries = store.list().unwrap();
+    let ids: Vec<_> = entries.iter().map(|e| e.id.as_str()).collect();
+    assert_eq!(ids, ["main", "entry-2", "entry-3"]);
+    assert!(entries.iter().all(|e| e.outdated));
+    for id in ids {
+        assert_eq!(store.get(id).unwrap().as_bytes(), plaintext);
+    }
+
+    assert!(store.migrate().unwrap());
+    let array = device.get_large_blob().unwrap().large_blob_array;
+    assert!(LargeBlobArray::parse(&array).is_some());
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    assert_eq!(store.list().unwrap().len(), 3);
+    assert_eq!(store.get("entry-3").unwrap().as_bytes(), plaintext);
+
+    // The markers older versions wrote for an empty blob.
+    for empty in [STANDARD.encode("EMPTY"), hex::encode("EMPTY")] {
+        let parsed = blob_format::decode(empty.as_bytes()).unwrap();
+        assert!(parsed.legacy && parsed.entries.is_empty());
+    }
+}
+
+#[test]
+fn renames_reorders_and_sets_the_default_entry() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    let note = Secret::parse(EntryType::Opaque, "correct horse battery staple").unwrap();
+    store.put("main", &secret_key(), false).unwrap();
+    store.put("note", &note, false).unwrap();
+    store.put("spare", &note, false).unwrap();
+    let ids = |store: &mut BlobStore<'_, SoftAuthenticator>| -> Vec<String> {
+        store.list().unwrap().into_iter().map(|e| e.id).collect()
+    };
+
+    store.rename("main", "signer").unwrap();
+    assert_eq!(ids(&mut store), ["signer", "note", "spare"]);
+    assert_eq!(
+        store.get("signer").unwrap().as_bytes(),
+        secret_key().as_bytes()
+    );
+    assert!(matches!(store.get("main"), Err(Error::EntryNotFound)));
+    assert!(matches!(
+        store.rename("signer", "note"),
+        Err(Error::EntryExists)
+    ));
+    assert!(matches!(
+        store.rename("signer", "  "),
+        Err(Error::InvalidInput(_))
+    ));
+
+    store.move_entry("spare", 0).unwrap();
+    assert_eq!(ids(&mut store), ["spare", "signer", "note"]);
+    store.move_entry("spare", 99).unwrap();
+    assert_eq!(ids(&mut store), ["signer", "note", "spare"]);
+
+    store.set_default(Some("note")).unwrap();
+    store.set_default(Some("signer")).unwrap();
+    let defaults: Vec<_> = store
+        .list()
+        .unwrap()
+        .into_iter()
+        .filter(|e| e.default)
+        .map(|e| e.id)
+        .collect();
+    assert_eq!(defaults, ["signer"]);
+
+    // The default follows the entry through a rename and a move.
+    store.rename("signer", "main").unwrap();
+    store.move_entry("main", 2).unwrap();
+    let entries = store.list().unwrap();
+    assert_eq!(entries[2].id, "main");
+    assert!(entries[2].default);
+    store.set_default(None).unwrap();
+    assert!(store.list().unwrap().iter().all(|e| !e.default));
+}
+
+#[test]
+fn checks_and_repairs_damaged_entries() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let note = Secret::parse(EntryType::Opaque, "correct horse battery staple").unwrap();
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    store.put("main", &secret_key(), false).unwrap();
+    store.put("note", &note, false).unwrap();
+    store.put("spare", &note, false).unwrap();
+    drop(store);
+    let large_blob_key = get_large_blob_key(&mut device, &mut session, &profile, &credential_id)
+        .unwrap()
+        .unwrap();
+    let stored = stored_container(&device, &large_blob_key);
+
+    let mut entries = stored.entries.clone();
+    entries[0].public_key = Some(Keys::generate().public_key().to_bytes().to_vec());
+    entries[1].ciphertext.truncate(3);
+    *entries[2].ciphertext.last_mut().unwrap() ^= 1;
+    entries.push(stored.entries[0].clone());
+    write_container(
+        &device,
+        &mut session,
+        &large_blob_key,
+        &entries,
+        stored.vault.as_ref(),
+    );
+
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    let statuses: Vec<_> = store
+        .check()
+        .unwrap()
+        .into_iter()
+        .map(|check| check.status)
+        .collect();
+    assert!(matches!(statuses[0], EntryStatus::Malformed(_)));
+    assert!(matches!(statuses[1], EntryStatus::Malformed(_)));
+    assert_eq!(statuses[2], EntryStatus::Undecryptable);
+    assert_eq!(statuses[3], EntryStatus::DuplicateId);
+
+    // Exported raw, the undecryptable entry is a container of its own.
+    let raw = blob_format::decode(&store.export_raw_at(2).unwrap()).unwrap();
+    assert_eq!(raw.entries.len(), 1);
+    assert_eq!(raw.entries[0].id, "spare");
+    assert_eq!(raw.entries[0].ciphertext, entries[2].ciphertext);
+
+    let renamed = store.reencode_at(3).unwrap();
+    assert_eq!(renamed, "main-2");
+    store.reencode_at(0).unwrap();
+    store.delete_at(2).unwrap();
+    store.delete_at(1).unwrap();
+    assert!(
+        store
+            .check()
+            .unwrap()
+            .iter()
+            .all(|check| check.status.is_ok())
+    );
+    let ids: Vec<_> = store.list().unwrap().into_iter().map(|e| e.id).collect();
+    assert_eq!(ids, ["main", "main-2"]);
+    assert_eq!(
+        store.get("main").unwrap().as_bytes(),
+        secret_key().as_bytes()
+    );
+    assert_eq!(
+        store.get("main-2").unwrap().as_bytes(),
+        secret_key().as_bytes()
+    );
+}
+
+#[test]
+fn validates_and_displays_each_entry_type() {
+    init();
+    const NCRYPTSEC: &str = "ncryptsec1qgg9947rlpvqu76pj5ecreduf9jxhselq2nae2kghhvd5g7dgjtcxfqtd67p9m0w57lspw8gsq6yphnm8623nsl8xn9j4jdzz84zm3frztj3z7s35vpzmqf6ksu8r89qk5z2zxfmu5gv8th8wclt0h4p";
+    const MNEMONIC: &str =
+        "leader monkey parrot ring guide accident before fence cannon height naive bean";
+    const NWC: &str = "nostr+walletconnect://b889ff5b1513b641e2a139f661a661364979c5beee91842f8f0ef42ab558e9d4?relay=wss%3A%2F%2Frelay.damus.io&secret=71a8c14c1407c113601079c4302dab36460f0ccd0ad506f1f2dc73b5100e4f3c";
+
+    // Hex and nsec are the same 32 raw bytes, shown as nsec.
+    let nsec = Keys::parse(NSEC_HEX)
+        .unwrap()
+        .secret_key()
+        .to_bech32()
+        .unwrap();
+    let from_nsec = Secret::parse(EntryType::NostrSecretKey, &nsec).unwrap();
+    assert_eq!(from_nsec.as_bytes(), hex::decode(NSEC_HEX).unwrap());
+    assert_eq!(from_nsec.to_string(), nsec);
+
+    let ncryptsec = Secret::parse(EntryType::Ncryptsec, NCRYPTSEC).unwrap();
+    assert_eq!(ncryptsec.to_string(), NCRYPTSEC);
+    assert!(ncryptsec.public_key().is_none());
+
+    // Mnemonics are stored in lowercase, single-spaced, and sign as account 0.
+    let shouted = MNEMONIC.to_uppercase().replace(' ', "  ");
+    let mnemonic = Secret::parse(EntryType::Mnemonic, &shouted).unwrap();
+    assert_eq!(mnemonic.to_string(), MNEMONIC);
+    assert_eq!(
+        mnemonic.public_key().unwrap(),
+        Keys::parse("7f7ff03d123792d6ac594bfa67bf6d0c0ab55b6b1fdb6249303fe861f1ccba9a")
+            .unwrap()
+            .public_key()
+    );
+
+    let nwc = Secret::parse(EntryType::NostrWalletConnect, NWC).unwrap();
+    assert_eq!(nwc.to_string(), NWC);
+
+    for entry_type in [
+        EntryType::NostrSecretKey,
+        EntryType::Ncryptsec,
+        EntryType::Mnemonic,
+        EntryType::NostrWalletConnect,
+    ] {
+        assert!(matches!(
+            Secret::parse(entry_type, "not a secret"),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+    assert!(matches!(
+        Secret::parse(EntryType::Opaque, "  "),
+        Err(Error::InvalidInput(_))
+    ));
+    // The types check each other's values too.
+    assert!(Secret::parse(EntryType::Ncryptsec, &nsec).is_err());
+    assert!(Secret::parse(EntryType::NostrSecretKey, MNEMONIC).is_err());
+
+    // Binary payloads survive the store and are shown as hex.
+    let binary =
+        Secret::from_plaintext(EntryType::Opaque, Zeroizing::new(vec![0, 0xff, 0x80])).unwrap();
+    assert_eq!(binary.to_string(), "00ff80");
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    store.put("binary", &binary, false).unwrap();
+    store.put("mnemonic", &mnemonic, false).unwrap();
+    assert_eq!(store.get("binary").unwrap().as_bytes(), [0, 0xff, 0x80]);
+    let listed = store.list().unwrap();
+    assert_eq!(listed[1].entry_type, EntryType::Mnemonic);
+    assert_eq!(
+        listed[1].public_key.as_ref().map(|key| key.to_string()),
+        mnemonic.public_key().map(|key| key.to_string())
+    );
+}
+
+#[test]
+fn moves_entries_between_ciphers() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked(&device);
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    BlobStore::new(&mut device, &mut session, &profile, &credential_id)
+        .put("main", &secret_key(), false)
+        .unwrap();
+    let large_blob_key = get_large_blob_key(&mut device, &mut session, &profile, &credential_id)
+        .unwrap()
+        .unwrap();
+
+    for cipher in [
+        Cipher::XChaCha20Poly1305,
+        Cipher::Aes256GcmSiv,
+        Cipher::Aes256Gcm,
+    ] {
+        let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+        store.change_cipher("main", cipher).unwrap();
+        assert_eq!(store.list().unwrap()[0].algorithm, Algorithm::vault(cipher));
+        assert_eq!(
+            store.get("main").unwrap().as_bytes(),
+            secret_key().as_bytes()
+        );
+
+        let entry = &stored_container(&device, &large_blob_key).entries[0];
+        assert_eq!(entry.algorithm.cipher(), cipher);
+        assert_eq!(entry.ciphertext.len(), cipher.overhead() + 32);
+    }
+
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    assert!(matches!(
+        store.change_cipher("missing", Cipher::Aes256GcmSiv),
+        Err(Error::EntryNotFound)
+    ));
+}
+
+#[test]
+fn asks_for_the_passphrase_of_protected_entries_only() {
+    init();
+    let mut device = SoftAuthenticator::new().with_pin(PIN);
+    let mut session = unlocked_with(&device, TestPin::with_passphrase("entry passphrase"));
+    let profile = Profile::default();
+    let credential_id = get_credential_id(&mut device, &mut session, &profile).unwrap();
+    let note = Secret::parse(EntryType::Opaque, "correct horse battery staple").unwrap();
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    store
+        .put_with_passphrase("main", &secret_key(), "entry passphrase", false)
+        .unwrap();
+    store.put("note", &note, false).unwrap();
+    assert_eq!(
+        store.get("main").unwrap().as_bytes(),
+        secret_key().as_bytes()
+    );
+    drop(store);
+
+    // Without anyone to ask, the protected entry can't be read, but is still
+    // listed with its public key, and the others are read as before.
+    let mut session = unlocked(&device);
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    let listed = store.list().unwrap();
+    assert!(listed[0].passphrase && !listed[1].passphrase);
+    assert_eq!(
+        listed[0].public_key.as_ref().map(|key| key.to_string()),
+        secret_key().public_key().map(|key| key.to_string())
+    );
+    assert!(matches!(store.get("main"), Err(Error::PassphraseRequired)));
+    assert_eq!(store.get("note").unwrap().as_bytes(), note.as_bytes());
+    drop(store);
+
+    let mut session = unlocked_with(&device, TestPin::with_passphrase("wrong passphrase"));
+    let mut store = BlobStore::new(&mut device, &mut session, &profile, &credential_id);
+    assert!(matches!(store.get("main"), Err(Error::DecryptFailed)));
+    drop(store);
+
+    let session = unlocked_with(&device, TestPin::with_passphrase("entry passphrase"));
+    let manager =
+        YubikeyKeyManager::with_authenticator(device, profile, session, Some("main")).unwrap();
+    assert_eq!(
+        manager.get_public_key().unwrap(),
+        secret_key().public_key().unwrap()
+    );
+    manager
+        .with_key(|keys| {
+            assert_eq!(keys.public_key(), secret_key().public_key().unwrap());
+            Ok(())
+        })
+        .unwrap();
+}